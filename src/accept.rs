@@ -0,0 +1,121 @@
+use std::{io::ErrorKind, time::Duration};
+
+use tracing::{debug, warn};
+
+/// How long to stop accepting for when out of file descriptors or memory, giving connections
+/// time to close and free some up
+const BACKOFF: Duration = Duration::from_millis(250);
+
+/// `EMFILE` and `ENFILE`, the per-process and system wide open file limits, which have the same
+/// numbers on Linux, macOS and the BSDs, and have no `ErrorKind` of their own
+#[cfg(unix)]
+const OUT_OF_FILES: [i32; 2] = [24, 23];
+#[cfg(not(unix))]
+const OUT_OF_FILES: [i32; 0] = [];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Handling {
+    /// Only the connection being accepted failed, so carry on
+    Retry,
+    /// Resources ran out, so carry on after a pause
+    Backoff,
+    /// The listener itself is broken
+    Fatal,
+}
+
+fn classify(error: &std::io::Error) -> Handling {
+    if error.raw_os_error().is_some_and(|e| OUT_OF_FILES.contains(&e)) {
+        return Handling::Backoff;
+    }
+
+    match error.kind() {
+        ErrorKind::OutOfMemory => Handling::Backoff,
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut => Handling::Retry,
+        _ => Handling::Fatal,
+    }
+}
+
+/// Passes on an accepted connection, or recovers from a failed accept by logging it (and after
+/// a pause when resources have run out), so only errors that leave the listener unusable stop
+/// a forward.
+pub async fn recover<T>(accepted: std::io::Result<T>) -> std::io::Result<Option<T>> {
+    let error = match accepted {
+        Ok(conn) => return Ok(Some(conn)),
+        Err(e) => e,
+    };
+
+    match classify(&error) {
+        Handling::Retry => {
+            debug!(error = &error as &dyn std::error::Error, "failed to accept connection");
+            Ok(None)
+        }
+        Handling::Backoff => {
+            warn!(
+                error = &error as &dyn std::error::Error,
+                backoff_ms = BACKOFF.as_millis() as u64,
+                "failed to accept connection, pausing before accepting more"
+            );
+            tokio::time::sleep(BACKOFF).await;
+            Ok(None)
+        }
+        Handling::Fatal => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{StreamExt, TryStreamExt};
+
+    use super::*;
+
+    #[cfg(unix)]
+    fn emfile() -> std::io::Error {
+        std::io::Error::from_raw_os_error(24)
+    }
+
+    #[test]
+    fn classification() {
+        #[cfg(unix)]
+        assert_eq!(classify(&emfile()), Handling::Backoff);
+        assert_eq!(classify(&ErrorKind::ConnectionAborted.into()), Handling::Retry);
+        assert_eq!(classify(&ErrorKind::OutOfMemory.into()), Handling::Backoff);
+        assert_eq!(classify(&ErrorKind::InvalidInput.into()), Handling::Fatal);
+        assert_eq!(classify(&ErrorKind::PermissionDenied.into()), Handling::Fatal);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn backs_off_and_carries_on() {
+        let accepted = futures::stream::iter([
+            Err(emfile()),
+            Ok(1),
+            Err(ErrorKind::ConnectionAborted.into()),
+            Ok(2),
+        ]);
+
+        let started = std::time::Instant::now();
+        let conns: Vec<i32> = accepted
+            .filter_map(|a| async { recover(a).await.transpose() })
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(conns, [1, 2]);
+        assert!(started.elapsed() >= BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_stop_accepting() {
+        let accepted = futures::stream::iter([Ok(1), Err(ErrorKind::InvalidInput.into()), Ok(2)]);
+
+        let result: std::io::Result<Vec<i32>> =
+            accepted.filter_map(|a| async { recover(a).await.transpose() }).try_collect().await;
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,150 @@
+use std::net::IpAddr;
+
+use crate::{cli::ControlArgs, errors::MyError};
+
+/// An IPv4 or IPv6 network given to `--allow-cidr` or `--deny-cidr`, eg. `10.0.0.0/8` or
+/// `fd00::/8`. A bare address is a network of just that address.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Self, MyError> {
+        let invalid = || MyError::InvalidCidr(cidr.to_owned());
+
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = max_prefix(&addr);
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(net.into(), ip.into(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Compares the top `prefix` of `bits` bits of each address
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let shift = u32::from(bits - prefix);
+
+    net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+/// Which client addresses may use a forward. A client matching any `deny` network is refused,
+/// and when there are `allow` networks the client must match one of them.
+#[derive(Clone, Default, Debug)]
+pub struct AccessRules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessRules {
+    pub fn from_args(args: &ControlArgs) -> Self {
+        Self {
+            allow: args.allow_cidr.clone(),
+            deny: args.deny_cidr.clone(),
+        }
+    }
+
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        Cidr::parse(s).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(&ip("10.200.3.4")));
+        assert!(!cidr("10.0.0.0/8").contains(&ip("11.0.0.1")));
+        assert!(cidr("192.168.1.7").contains(&ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(&ip("192.168.1.8")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ipv6() {
+        assert!(cidr("fd00::/8").contains(&ip("fd12:3456::1")));
+        assert!(!cidr("fd00::/8").contains(&ip("fe80::1")));
+        assert!(cidr("::1").contains(&ip("::1")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6() {
+        assert!(cidr("10.0.0.0/8").contains(&ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in ["", "10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/", "10.0.0.0/-1", "host/8"] {
+            assert!(matches!(Cidr::parse(s), Err(MyError::InvalidCidr(_))), "{s}");
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let open = AccessRules::default();
+        assert!(open.permits(&ip("203.0.113.9")));
+
+        let rules = AccessRules {
+            allow: vec![cidr("10.0.0.0/8"), cidr("fd00::/8")],
+            deny: vec![cidr("10.0.0.13")],
+        };
+        assert!(rules.permits(&ip("10.1.1.1")));
+        assert!(rules.permits(&ip("fd00::5")));
+        assert!(!rules.permits(&ip("10.0.0.13")));
+        assert!(!rules.permits(&ip("192.168.0.1")));
+
+        let deny_only = AccessRules {
+            allow: vec![],
+            deny: vec![cidr("192.168.0.0/16")],
+        };
+        assert!(!deny_only.permits(&ip("192.168.4.4")));
+        assert!(deny_only.permits(&ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use crate::cli::Forward;
+
+/// The first loopback alias handed out, leaving 127.0.0.x and 127.0.1.x (which some systems
+/// use for the hostname) alone
+const FIRST_ALIAS: Ipv4Addr = Ipv4Addr::new(127, 0, 2, 1);
+
+/// Hands out loopback addresses of their own to services, so they can each listen on their
+/// own ports without clashing. Linux routes all of 127.0.0.0/8 to the loopback interface, other
+/// systems need the addresses added to it first.
+#[derive(Debug)]
+pub struct Aliases {
+    next: u32,
+}
+
+impl Default for Aliases {
+    fn default() -> Self {
+        Self {
+            next: FIRST_ALIAS.into(),
+        }
+    }
+}
+
+impl Aliases {
+    /// Hands out the aliases after any the forwards are bound to, so they aren't handed out twice.
+    pub fn after(forwards: &[Forward]) -> Self {
+        let first = u32::from(FIRST_ALIAS);
+        let next = forwards
+            .iter()
+            .filter_map(|f| match f.local_address {
+                Some(IpAddr::V4(address)) if address.is_loopback() => Some(u32::from(address) + 1),
+                _ => None,
+            })
+            .fold(first, u32::max);
+
+        Self { next }
+    }
+
+    /// The next unused alias, or `None` once 127.0.0.0/8 is exhausted. Addresses ending in
+    /// `.0` or `.255` are skipped, as some clients refuse to connect to them.
+    pub fn next(&mut self) -> Option<Ipv4Addr> {
+        loop {
+            let alias = Ipv4Addr::from(self.next);
+            if !alias.is_loopback() {
+                return None;
+            }
+            self.next += 1;
+
+            if !matches!(alias.octets()[3], 0 | 255) {
+                return Some(alias);
+            }
+        }
+    }
+}
+
+/// For --loopback-aliases, binds each service's forwards that don't give a local address to
+/// an alias of the service's own, handed out in the order the services first appear.
+pub fn assign(forwards: &mut [Forward]) {
+    let mut aliases = Aliases::default();
+    let mut assigned = HashMap::new();
+
+    for forward in forwards.iter_mut().filter(|f| f.local_address.is_none() && f.local_path.is_none()) {
+        let service = (
+            forward.namespace.clone(),
+            forward.kind,
+            forward.service_name.clone(),
+            forward.options.context.clone(),
+            forward.options.kubeconfig.clone(),
+        );
+        let alias = match assigned.get(&service) {
+            Some(alias) => *alias,
+            None => {
+                let Some(alias) = aliases.next() else {
+                    return;
+                };
+                assigned.insert(service, alias);
+                alias
+            }
+        };
+
+        forward.local_address = Some(IpAddr::V4(alias));
+    }
+}
+
+/// A table of the local address each forward binds, for logging at startup.
+pub fn summary(forwards: &[Forward]) -> String {
+    let mut rows: Vec<(String, Vec<String>)> = vec![];
+    for forward in forwards {
+        let address = match (&forward.local_path, forward.local_address) {
+            (Some(path), _) => format!("unix:{}", path.display()),
+            (None, Some(address)) => address.to_string(),
+            (None, None) => "localhost".to_owned(),
+        };
+        let target = format!(
+            "{namespace}{kind}{service}{port}",
+            namespace = forward.namespace.as_ref().map(|ns| format!("{ns}/")).unwrap_or_default(),
+            kind = forward.kind.prefix(),
+            service = forward.service_name,
+            port = forward.service_port.as_ref().map(|p| format!(":{p}")).unwrap_or_default(),
+        );
+
+        match rows.iter_mut().find(|(a, _)| *a == address) {
+            Some((_, targets)) => targets.push(target),
+            None => rows.push((address, vec![target])),
+        }
+    }
+
+    let width = rows.iter().map(|(a, _)| a.len()).max().unwrap_or_default();
+    rows.iter()
+        .map(|(address, targets)| format!("{address:width$}  {}", targets.join(", ")))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_service_gets_an_alias() {
+        let mut forwards: Vec<_> = [
+            "web:8080",
+            "api:8080",
+            "web:8443",
+            "192.0.2.31:80:proxy:80",
+            "web:8080?context=staging",
+        ]
+        .into_iter()
+        .map(|f| Forward::parse(f).unwrap())
+        .collect();
+
+        assign(&mut forwards);
+
+        let addresses: Vec<_> = forwards.iter().map(|f| f.local_address.unwrap().to_string()).collect();
+        assert_eq!(addresses, ["127.0.2.1", "127.0.2.2", "127.0.2.1", "192.0.2.31", "127.0.2.3"]);
+        assert_eq!(Aliases::after(&forwards).next(), Some(Ipv4Addr::new(127, 0, 2, 4)));
+        assert_eq!(
+            summary(&forwards[..4]),
+            "127.0.2.1   web:8080, web:8443\n127.0.2.2   api:8080\n192.0.2.31  proxy:80"
+        );
+    }
+
+    #[test]
+    fn aliases_skip_network_and_broadcast_like_addresses() {
+        let mut aliases = Aliases::default();
+
+        let first: Vec<_> = std::iter::from_fn(|| aliases.next()).take(256).collect();
+
+        assert_eq!(first[0], Ipv4Addr::new(127, 0, 2, 1));
+        assert_eq!(first[253], Ipv4Addr::new(127, 0, 2, 254));
+        assert_eq!(first[254], Ipv4Addr::new(127, 0, 3, 1));
+    }
+
+    #[test]
+    fn aliases_run_out() {
+        let mut aliases = Aliases {
+            next: Ipv4Addr::new(127, 255, 255, 254).into(),
+        };
+
+        assert_eq!(aliases.next(), Some(Ipv4Addr::new(127, 255, 255, 254)));
+        assert_eq!(aliases.next(), None);
+    }
+}
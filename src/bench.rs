@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use kube::Client;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{field, info, info_span, Instrument};
+
+use crate::{
+    cli::{CliArgs, ClientKey},
+    connectivity::Connectivity,
+    errors::MyError,
+    target::Target,
+};
+
+/// How long to wait for the pod to echo a payload back before giving up
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait, after uploading to a discard server, for the forward to close the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What --bench expects to be listening on the pod side of the forward.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BenchMode {
+    /// An echo server, sending back everything it receives, to time round trips
+    Echo,
+    /// A discard server, reading and dropping everything it receives, to measure upload speed
+    Discard,
+}
+
+/// Drives traffic through a single forward for --bench-duration and prints the results as one
+/// line of JSON. The connection goes through a listener on a free loopback port, so it takes
+/// the same path as any other.
+pub async fn run(clients: &HashMap<ClientKey, (Client, Arc<Connectivity>)>, args: &CliArgs) -> anyhow::Result<()> {
+    let [forward] = args.forwards.as_slice() else {
+        return Err(MyError::BenchForwardCount(args.forwards.len()).into());
+    };
+    let (client, connectivity) = clients
+        .get(&args.client_key(forward))
+        .ok_or_else(|| anyhow::anyhow!("no client for the forward"))?;
+
+    let control = args.control.with_options(&forward.options);
+    let target = Arc::new(Target::new(
+        client.clone(),
+        connectivity.clone(),
+        forward.clone(),
+        control.clone(),
+    ));
+    let name = target.name.clone();
+    let span = info_span!("bench", target = name);
+    target.resolve().instrument(span.clone()).await?;
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let local_addr = listener.local_addr()?;
+
+    let accept = async move {
+        let (conn, peer_addr) = listener.accept().await?;
+        let connection_span = info_span!(
+            "connection",
+            peer_addr = peer_addr.to_string(),
+            ttfb_ms = field::Empty,
+            duration_ms = field::Empty
+        );
+        tokio::spawn(crate::handle_connection(conn, peer_addr.into(), target, control, None).instrument(connection_span));
+        anyhow::Ok(())
+    };
+    tokio::spawn(accept.instrument(span.clone()));
+
+    let payload = vec![0x6b; args.bench_payload as usize];
+    let duration = Duration::from_secs(args.bench_duration);
+
+    info!(mode = ?args.bench_mode, payload_bytes = payload.len(), duration_s = duration.as_secs(), "benchmarking");
+    let results = drive(local_addr, args.bench_mode, &payload, duration).instrument(span).await?;
+
+    println!(
+        "{}",
+        json!({
+            "target": name,
+            "mode": args.bench_mode.to_possible_value().map(|v| v.get_name().to_owned()),
+            "payload_bytes": payload.len(),
+            "duration_s": results.elapsed.as_secs_f64(),
+            "bytes": results.bytes,
+            "bytes_per_s": results.bytes as f64 / results.elapsed.as_secs_f64(),
+            "round_trips": results.round_trips.len(),
+            "latency_ms": latency(&results.round_trips),
+        })
+    );
+
+    Ok(())
+}
+
+struct Results {
+    elapsed: Duration,
+    /// Bytes sent, plus those echoed back
+    bytes: u64,
+    round_trips: Vec<Duration>,
+}
+
+async fn drive(addr: SocketAddr, mode: BenchMode, payload: &[u8], duration: Duration) -> anyhow::Result<Results> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+
+    let mut echoed = vec![0; payload.len()];
+    let mut results = Results {
+        elapsed: Duration::ZERO,
+        bytes: 0,
+        round_trips: vec![],
+    };
+
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        let sent = Instant::now();
+        stream.write_all(payload).await?;
+        results.bytes += payload.len() as u64;
+
+        if mode == BenchMode::Echo {
+            tokio::time::timeout(ECHO_TIMEOUT, stream.read_exact(&mut echoed))
+                .await
+                .context("no echo from the pod, is it running an echo server?")??;
+            results.bytes += payload.len() as u64;
+            results.round_trips.push(sent.elapsed());
+        }
+    }
+    stream.shutdown().await?;
+
+    // What was written may still be buffered on the way to the pod, so the upload only counts
+    // as done once the forward has passed the end of it on and closed the connection
+    if mode == BenchMode::Discard {
+        tokio::time::timeout(CLOSE_TIMEOUT, tokio::io::copy(&mut stream, &mut tokio::io::sink()))
+            .await
+            .context("the connection wasn't closed after the upload, is the pod running a discard server?")??;
+    }
+    results.elapsed = started.elapsed();
+
+    Ok(results)
+}
+
+/// Summarises the round trip times, or `null` when there were none.
+fn latency(round_trips: &[Duration]) -> serde_json::Value {
+    if round_trips.is_empty() {
+        return serde_json::Value::Null;
+    }
+
+    let mut sorted = round_trips.to_vec();
+    sorted.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+    json!({
+        "min": ms(sorted[0]),
+        "p50": ms(percentile(&sorted, 50)),
+        "p90": ms(percentile(&sorted, 90)),
+        "p99": ms(percentile(&sorted, 99)),
+        "max": ms(sorted[sorted.len() - 1]),
+    })
+}
+
+/// The nearest-rank percentile of sorted, non-empty, times.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let times: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+
+        assert_eq!(percentile(&times, 50), Duration::from_millis(50));
+        assert_eq!(percentile(&times, 99), Duration::from_millis(99));
+        assert_eq!(percentile(&times, 100), Duration::from_millis(100));
+        assert_eq!(percentile(&times[..1], 50), Duration::from_millis(1));
+        assert_eq!(percentile(&times[..3], 90), Duration::from_millis(3));
+    }
+
+    #[test]
+    fn latency_summary() {
+        assert_eq!(latency(&[]), serde_json::Value::Null);
+
+        let summary = latency(&[Duration::from_millis(3), Duration::from_millis(1), Duration::from_millis(2)]);
+        assert_eq!(summary["min"], 1.0);
+        assert_eq!(summary["p50"], 2.0);
+        assert_eq!(summary["max"], 3.0);
+    }
+
+    #[tokio::test]
+    async fn drives_an_echo_server() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = conn.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+
+        let results = drive(addr, BenchMode::Echo, &[1; 64], Duration::from_millis(50)).await.unwrap();
+
+        assert!(!results.round_trips.is_empty());
+        assert_eq!(results.bytes, results.round_trips.len() as u64 * 128);
+    }
+
+    #[tokio::test]
+    async fn discard_waits_for_the_connection_to_close() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            tokio::io::copy(&mut conn, &mut tokio::io::sink()).await.unwrap();
+            // Still taking its time over what was sent after the client finished
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+
+        let results = drive(addr, BenchMode::Discard, &[1; 64], Duration::from_millis(50)).await.unwrap();
+
+        assert!(results.round_trips.is_empty());
+        assert!(results.elapsed >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::{errors::MyError, events};
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const LOOPBACKS: [IpAddr; 2] = [IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+
+static AVAILABLE_LOOPBACKS: OnceLock<Vec<IpAddr>> = OnceLock::new();
+
+/// The loopback addresses forwards without a local address bind, `127.0.0.1` and `::1`, less
+/// any whose address family this host doesn't have (eg. containers without IPv6). Detected on
+/// first use and logged once.
+pub fn loopback_addresses() -> &'static [IpAddr] {
+    AVAILABLE_LOOPBACKS.get_or_init(|| {
+        let available = available_loopbacks(|ip| std::net::TcpListener::bind((ip, 0)).map(|_| ()));
+
+        match available.len() {
+            n if n == LOOPBACKS.len() => debug!("binding IPv4 and IPv6 loopback"),
+            // Binding both will report why neither works
+            0 => return LOOPBACKS.to_vec(),
+            _ => info!(
+                addresses = available.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", "),
+                "only binding the loopback addresses available on this host"
+            ),
+        }
+
+        available
+    })
+}
+
+fn available_loopbacks(bind: impl Fn(IpAddr) -> std::io::Result<()>) -> Vec<IpAddr> {
+    LOOPBACKS
+        .into_iter()
+        .filter(|ip| match bind(*ip) {
+            Ok(()) => true,
+            // Only a missing address family rules the address out, anything else is reported
+            // when the forward binds it
+            Err(e) => !matches!(e.kind(), std::io::ErrorKind::AddrNotAvailable | std::io::ErrorKind::Unsupported)
+                // EAFNOSUPPORT, which has no ErrorKind of its own
+                && e.raw_os_error() != Some(97),
+        })
+        .collect()
+}
+
+/// Binds `addr` for `target`, explaining a refusal to bind a privileged port and, when a
+/// `fallback_port` was given, binding that instead.
+pub async fn bind(addr: SocketAddr, fallback_port: Option<u16>, target: &str) -> anyhow::Result<TcpListener> {
+    let error = match TcpListener::bind(addr).await {
+        Ok(listener) => return Ok(listener),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && addr.port() < 1024 => e,
+        Err(e) => return Err(e.into()),
+    };
+
+    let Some(port) = fallback_port else {
+        return Err(MyError::PrivilegedPortDenied(addr, error).into());
+    };
+
+    let listener = TcpListener::bind(SocketAddr::new(addr.ip(), port)).await?;
+    warn!(
+        requested_addr = addr.to_string(),
+        local_addr = listener.local_addr()?.to_string(),
+        "not permitted to bind privileged port, bound --fallback-port instead"
+    );
+    events::port_fallback(target, addr, listener.local_addr()?);
+
+    Ok(listener)
+}
+
+/// Confirms that clients can actually reach a freshly bound listener's address, warning when they
+/// can't (eg. a local firewall dropping the traffic).
+///
+/// The probe connects to a listener of its own on the same address, as connecting to `listener`
+/// would leave the probe to be accepted along with, and so mistaken for, any client connecting
+/// meanwhile. A firewall rule for the listener's port alone isn't noticed.
+///
+/// Only loopback and unspecified binds are checked, as connecting to our own address on any other
+/// interface doesn't necessarily take the same path as a remote client would.
+pub async fn verify_listener(listener: &TcpListener) -> std::io::Result<()> {
+    let local_addr = listener.local_addr()?;
+
+    let probe_ip = match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip if ip.is_loopback() => ip,
+        _ => {
+            debug!(local_addr = local_addr.to_string(), "skipping bind verification of non-loopback address");
+            return Ok(());
+        }
+    };
+
+    let probe_listener = TcpListener::bind((probe_ip, 0)).await?;
+    let probe_addr = probe_listener.local_addr()?;
+    let probe = async { tokio::try_join!(TcpStream::connect(probe_addr), probe_listener.accept()) };
+
+    match tokio::time::timeout(VERIFY_TIMEOUT, probe).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            warn!(
+                error = &e as &dyn std::error::Error,
+                local_addr = local_addr.to_string(),
+                "bound but unable to connect to listener, check for a local firewall"
+            );
+            return Ok(());
+        }
+        Err(_) => {
+            warn!(
+                local_addr = local_addr.to_string(),
+                "bound but connecting to listener timed out, check for a local firewall"
+            );
+            return Ok(());
+        }
+    }
+
+    debug!(local_addr = local_addr.to_string(), "verified listener is reachable");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_errors_other_than_privilege_are_returned() {
+        let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let err = bind(taken.local_addr().unwrap(), Some(0), "test").await.unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<std::io::Error>().map(|e| e.kind()),
+            Some(std::io::ErrorKind::AddrInUse)
+        );
+    }
+
+    #[test]
+    fn missing_address_families_are_skipped() {
+        let no_ipv6 = available_loopbacks(|ip| match ip {
+            IpAddr::V4(_) => Ok(()),
+            IpAddr::V6(_) => Err(std::io::ErrorKind::AddrNotAvailable.into()),
+        });
+        assert_eq!(no_ipv6, [IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+
+        // Other failures are left for binding to report
+        let denied = available_loopbacks(|_| Err(std::io::ErrorKind::PermissionDenied.into()));
+        assert_eq!(denied, LOOPBACKS);
+    }
+
+    #[tokio::test]
+    async fn verify_loopback_listener() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        verify_listener(&listener).await.unwrap();
+
+        // the probe connection must not be left waiting to be accepted
+        let pending = tokio::time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(pending.is_err());
+    }
+
+    #[tokio::test]
+    async fn verifying_leaves_clients_to_be_accepted() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        verify_listener(&listener).await.unwrap();
+
+        let (_, peer) = tokio::time::timeout(Duration::from_secs(1), listener.accept()).await.unwrap().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+}
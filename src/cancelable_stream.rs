@@ -1,30 +1,85 @@
+use std::io::ErrorKind;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+use clap::ValueEnum;
 use futures::stream::{AbortHandle, AbortRegistration};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+/// Errors that only mean the other end has gone away, which --conceal-error treats as the end of
+/// the stream rather than a failure.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConcealedError {
+    ConnectionReset,
+    BrokenPipe,
+    ConnectionAborted,
+    NotConnected,
+    UnexpectedEof,
+    TimedOut,
+}
+
+impl ConcealedError {
+    /// The default set, of the errors seen while a pod is torn down
+    pub const DEFAULT: [Self; 3] = [Self::ConnectionReset, Self::BrokenPipe, Self::ConnectionAborted];
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ConcealedError::ConnectionReset => ErrorKind::ConnectionReset,
+            ConcealedError::BrokenPipe => ErrorKind::BrokenPipe,
+            ConcealedError::ConnectionAborted => ErrorKind::ConnectionAborted,
+            ConcealedError::NotConnected => ErrorKind::NotConnected,
+            ConcealedError::UnexpectedEof => ErrorKind::UnexpectedEof,
+            ConcealedError::TimedOut => ErrorKind::TimedOut,
+        }
+    }
+}
+
 pub struct CancelableReadWrite<'a, T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     stream: &'a mut T,
     abort: AbortHandle,
+    conceal: &'a [ErrorKind],
+    graceful: bool,
 
     finished: bool,
+    written: u64,
 }
 
 impl<'a, T> CancelableReadWrite<'a, T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    pub fn new(stream: &'a mut T, abort_registration: &AbortRegistration) -> Self {
+    /// Errors of the `conceal` kinds end the stream as though it had closed cleanly.
+    pub fn new(stream: &'a mut T, abort_registration: &AbortRegistration, conceal: &'a [ErrorKind]) -> Self {
         Self {
             stream,
             abort: abort_registration.handle(),
+            conceal,
+            graceful: false,
             finished: false,
+            written: 0,
         }
     }
+
+    /// Once aborted, ends the stream for reading but carries on writing, so anything already
+    /// read from the other side is delivered before this side is shut down.
+    pub fn graceful(mut self, graceful: bool) -> Self {
+        self.graceful = graceful;
+        self
+    }
+
+    /// Whether a concealed error has ended the stream. Writes after that return `Ok(0)`, failing
+    /// the copy with `WriteZero`, which is then a clean close rather than an error.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The bytes written to the stream, which is what a copy ended by a concealed error wrote.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
 }
 
 impl<'a, T> AsyncRead for CancelableReadWrite<'a, T>
@@ -44,15 +99,16 @@ where
 
         let pinned = Pin::new(&mut mut_self.stream);
 
-        if is_aborted {
+        if is_aborted && mut_self.graceful {
+            Poll::Ready(Ok(()))
+        } else if is_aborted {
             pinned.poll_shutdown(cx)
         } else {
             match pinned.poll_read(cx, buf) {
                 Poll::Ready(r) => match r {
                     Ok(()) => Poll::Ready(Ok(())),
                     Err(e) => {
-                        // If the erroris 104 (Connection Reset by Peer) then we want to conceal the error
-                        match e.raw_os_error() == Some(104) {
+                        match mut_self.conceal.contains(&e.kind()) {
                             true => {
                                 mut_self.finished = true;
                                 Poll::Ready(Ok(()))
@@ -80,12 +136,24 @@ where
             return Poll::Ready(Ok(0));
         }
 
-        if self.abort.is_aborted() {
-            Pin::new(&mut self.get_mut().stream)
+        let mut_self = self.get_mut();
+
+        if mut_self.abort.is_aborted() && !mut_self.graceful {
+            Pin::new(&mut mut_self.stream)
                 .poll_shutdown(cx)
                 .map(|m| m.map(|_| 0))
         } else {
-            Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+            match Pin::new(&mut mut_self.stream).poll_write(cx, buf) {
+                Poll::Ready(Err(e)) if mut_self.conceal.contains(&e.kind()) => {
+                    mut_self.finished = true;
+                    Poll::Ready(Ok(0))
+                }
+                Poll::Ready(Ok(n)) => {
+                    mut_self.written += n as u64;
+                    Poll::Ready(Ok(n))
+                }
+                r => r,
+            }
         }
     }
 
@@ -110,3 +178,153 @@ where
 }
 
 impl<T> Unpin for CancelableReadWrite<'_, T> where T: AsyncRead + AsyncWrite + Unpin {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::AbortHandle;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const DEFAULT_KINDS: [ErrorKind; 3] = [ErrorKind::ConnectionReset, ErrorKind::BrokenPipe, ErrorKind::ConnectionAborted];
+
+    /// A stream failing every read and write with `kind`
+    struct Failing(ErrorKind);
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+    }
+
+    impl AsyncWrite for Failing {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, _buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn default_set_matches_kinds() {
+        let kinds: Vec<_> = ConcealedError::DEFAULT.iter().map(ConcealedError::kind).collect();
+        assert_eq!(kinds, DEFAULT_KINDS);
+    }
+
+    #[tokio::test]
+    async fn concealed_kinds_end_the_stream() {
+        let (_abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        for kind in DEFAULT_KINDS {
+            let mut failing = Failing(kind);
+            let mut stream = CancelableReadWrite::new(&mut failing, &abort_registration, &DEFAULT_KINDS);
+
+            assert_eq!(stream.write(b"lost").await.unwrap(), 0, "{kind:?}");
+            assert!(stream.is_finished(), "{kind:?}");
+            assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0, "{kind:?}");
+            assert_eq!(stream.write(b"lost").await.unwrap(), 0, "{kind:?}");
+
+            let mut failing = Failing(kind);
+            let mut stream = CancelableReadWrite::new(&mut failing, &abort_registration, &DEFAULT_KINDS);
+            assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0, "{kind:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn other_kinds_propagate() {
+        let (_abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        for kind in [ErrorKind::PermissionDenied, ErrorKind::TimedOut, ErrorKind::Other] {
+            let mut failing = Failing(kind);
+            let mut stream = CancelableReadWrite::new(&mut failing, &abort_registration, &DEFAULT_KINDS);
+
+            assert_eq!(stream.write(b"sent").await.unwrap_err().kind(), kind);
+            assert_eq!(stream.read(&mut [0; 8]).await.unwrap_err().kind(), kind);
+        }
+
+        let mut failing = Failing(ErrorKind::ConnectionReset);
+        let mut stream = CancelableReadWrite::new(&mut failing, &abort_registration, &[]);
+        assert_eq!(stream.read(&mut [0; 8]).await.unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+
+    /// A client that sends its request and closes its write side, still expecting the whole
+    /// response, must get every byte the pod sends after that.
+    #[tokio::test]
+    async fn half_closed_client_receives_all_upstream_bytes() {
+        let (mut client, mut client_side) = duplex(1024);
+        let (mut pod, mut upstream_side) = duplex(1024);
+        let (_abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        let relay = tokio::spawn(async move {
+            let mut client = CancelableReadWrite::new(&mut client_side, &abort_registration, &DEFAULT_KINDS);
+            let mut upstream = CancelableReadWrite::new(&mut upstream_side, &abort_registration, &DEFAULT_KINDS);
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+        });
+
+        let response: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let expected = response.clone();
+        let pod = tokio::spawn(async move {
+            let mut request = vec![];
+            pod.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+
+            // Only respond once the client's side has been closed
+            pod.write_all(&response).await.unwrap();
+            pod.shutdown().await.unwrap();
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+
+        pod.await.unwrap();
+        assert_eq!(relay.await.unwrap().unwrap(), (7, expected.len() as u64));
+        assert_eq!(received, expected);
+    }
+
+    /// Bytes the relay has read from the pod but not yet written to a slow client must still
+    /// reach it when the connection is closed gracefully for the pod going unready.
+    #[tokio::test]
+    async fn graceful_close_delivers_buffered_bytes() {
+        let (mut client, mut client_side) = duplex(16);
+        let (mut pod, mut upstream_side) = duplex(1024);
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        let relay = tokio::spawn(async move {
+            let mut client = CancelableReadWrite::new(&mut client_side, &abort_registration, &DEFAULT_KINDS).graceful(true);
+            let mut upstream =
+                CancelableReadWrite::new(&mut upstream_side, &abort_registration, &DEFAULT_KINDS).graceful(true);
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+        });
+
+        let response: Vec<u8> = (0..512).map(|i| i as u8).collect();
+        pod.write_all(&response).await.unwrap();
+
+        // Let the relay take the response, most of which is left waiting on the client
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        abort_handle.abort();
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, response);
+
+        // The pod's side is closed cleanly too
+        let mut request = vec![];
+        pod.read_to_end(&mut request).await.unwrap();
+        assert!(request.is_empty());
+
+        assert_eq!(relay.await.unwrap().unwrap(), (0, response.len() as u64));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    path::PathBuf,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+    sync::mpsc,
+};
+use tracing::{debug, warn, Instrument};
+
+/// Writes the bytes passing through each connection of a forward to files in a directory.
+pub struct Capture {
+    dir: PathBuf,
+    prefix: String,
+    max_bytes: u64,
+    sequence: AtomicU64,
+}
+
+impl Capture {
+    pub fn new(dir: PathBuf, target: &str, max_bytes: u64) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let prefix = target
+            .chars()
+            .map(|c| match c {
+                '/' | ':' | '\\' => '_',
+                c => c,
+            })
+            .collect();
+
+        Ok(Self {
+            dir,
+            prefix,
+            max_bytes,
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    /// Opens the capture files for the next connection.
+    ///
+    /// Files are named `<target>-<sequence>-<unix timestamp>.{up,down}`, where `up` holds the bytes
+    /// sent by the client and `down` the bytes sent back to it. They are created and written in
+    /// the background, with any failure logged.
+    pub fn start(&self) -> CaptureFiles {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let name = format!("{}-{:06}-{}", self.prefix, sequence, timestamp);
+
+        CaptureFiles {
+            up: CaptureFile::create(self.dir.join(format!("{name}.up")), self.max_bytes),
+            down: CaptureFile::create(self.dir.join(format!("{name}.down")), self.max_bytes),
+        }
+    }
+}
+
+pub struct CaptureFiles {
+    up: CaptureFile,
+    down: CaptureFile,
+}
+
+struct CaptureFile {
+    path: PathBuf,
+    /// Sends the bytes to the task writing the file, so the connection never waits on the disk.
+    /// Dropped once the file is complete, which closes it.
+    chunks: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    remaining: u64,
+}
+
+impl CaptureFile {
+    fn create(path: PathBuf, max_bytes: u64) -> Self {
+        let (chunks, received) = mpsc::unbounded_channel();
+        tokio::spawn(write_file(path.clone(), received).in_current_span());
+
+        Self {
+            path,
+            chunks: Some(chunks),
+            remaining: max_bytes,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        let Some(chunks) = self.chunks.as_ref() else {
+            return;
+        };
+        if buf.is_empty() {
+            return;
+        }
+
+        let len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        // Only fails once writing the file has failed, which has been logged
+        if chunks.send(buf[..len].to_vec()).is_err() {
+            self.chunks = None;
+            return;
+        }
+        self.remaining -= len as u64;
+
+        if self.remaining == 0 {
+            debug!(path = %self.path.display(), "capture size limit reached");
+            self.chunks = None;
+        }
+    }
+}
+
+/// Creates the file at `path` and writes the chunks to it until they stop.
+async fn write_file(path: PathBuf, mut chunks: mpsc::UnboundedReceiver<Vec<u8>>) {
+    let written = async {
+        let mut writer = BufWriter::new(File::create(&path).await?);
+        while let Some(chunk) = chunks.recv().await {
+            writer.write_all(&chunk).await?;
+        }
+        writer.flush().await
+    };
+
+    if let Err(e) = written.await {
+        warn!(error = &e as &dyn std::error::Error, path = %path.display(), "capture failed");
+    }
+}
+
+/// Wraps the client side of a connection, copying everything read from it to the `up` capture
+/// and everything written to it to the `down` capture.
+pub struct CaptureReadWrite<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: T,
+    files: Option<CaptureFiles>,
+}
+
+impl<T> CaptureReadWrite<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: T, files: Option<CaptureFiles>) -> Self {
+        Self { stream, files }
+    }
+}
+
+impl<T> AsyncRead for CaptureReadWrite<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut_self = self.get_mut();
+        let before = buf.filled().len();
+
+        let result = Pin::new(&mut mut_self.stream).poll_read(cx, buf);
+
+        if let (Poll::Ready(Ok(())), Some(files)) = (&result, mut_self.files.as_mut()) {
+            files.up.write(&buf.filled()[before..]);
+        }
+
+        result
+    }
+}
+
+impl<T> AsyncWrite for CaptureReadWrite<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let mut_self = self.get_mut();
+
+        let result = Pin::new(&mut mut_self.stream).poll_write(cx, buf);
+
+        if let (Poll::Ready(Ok(n)), Some(files)) = (&result, mut_self.files.as_mut()) {
+            files.down.write(&buf[..*n]);
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn captures_each_direction() {
+        let dir = std::env::temp_dir().join(format!("kubempf-capture-{}", std::process::id()));
+        let capture = Capture::new(dir.clone(), "ns/svc:80", 4).unwrap();
+
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut wrapped = CaptureReadWrite::new(client, Some(capture.start()));
+
+        peer.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        wrapped.read_exact(&mut buf).await.unwrap();
+        wrapped.write_all(b"ok").await.unwrap();
+        drop(wrapped);
+
+        // The files are written in the background, and complete once they have both bytes
+        let captured = || {
+            let mut files: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap())
+                .map(|e| (e.file_name().into_string().unwrap(), std::fs::read(e.path()).unwrap()))
+                .collect();
+            files.sort();
+            files
+        };
+        let mut files = captured();
+        for _ in 0..100 {
+            if files.iter().map(|(_, contents)| contents.len()).sum::<usize>() == 6 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            files = captured();
+        }
+
+        assert_eq!(files.len(), 2);
+        assert!(files[0].0.starts_with("ns_svc_80-000000-") && files[0].0.ends_with(".down"));
+        assert_eq!(files[0].1, b"ok");
+        assert_eq!(files[1].1, b"requ");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::{ffi::OsString, path::Path};
+
+use clap::{parser::ValueSource, ArgAction, ArgMatches, Command};
+
+use crate::errors::MyError;
+
+/// Key for the forwards in a --config file, as they have no flag of their own
+const FORWARDS_KEY: &str = "forwards";
+
+/// Reads a --config file into arguments to parse ahead of those on the command line.
+///
+/// Each key is the long name of an option, with the forwards under `forwards`, eg.
+///
+/// ```toml
+/// context = "staging"
+/// ignore-readiness = true
+/// allow-cidr = ["10.0.0.0/8"]
+/// forwards = ["postgres:5432", "8080:web/frontend:80"]
+/// ```
+///
+/// Options already given on the command line or through the environment are left out, so they
+/// take precedence over the file. Forwards are added to those given elsewhere.
+pub fn args_from_file(path: &Path, command: &Command, matches: &ArgMatches) -> Result<Vec<OsString>, MyError> {
+    let invalid = |reason: String| MyError::InvalidConfig(path.display().to_string(), reason);
+
+    let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let table: toml::Table = contents.parse().map_err(|e: toml::de::Error| invalid(e.message().to_owned()))?;
+
+    to_args(&table, command, |id| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    })
+    .map_err(invalid)
+}
+
+/// Converts the keys of a --config file to arguments, leaving out those `is_set` elsewhere.
+fn to_args(table: &toml::Table, command: &Command, is_set: impl Fn(&str) -> bool) -> Result<Vec<OsString>, String> {
+    let mut forwards = vec![];
+    let mut options = vec![];
+
+    for (key, value) in table {
+        if key == FORWARDS_KEY {
+            forwards.extend(values(key, value)?);
+            continue;
+        }
+
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(key.as_str()) && a.get_id() != "config")
+            .ok_or_else(|| format!("unknown option {key}"))?;
+        if is_set(arg.get_id().as_str()) {
+            continue;
+        }
+
+        let is_flag = matches!(arg.get_action(), ArgAction::SetTrue);
+        for value in values(key, value)? {
+            match (is_flag, value.as_str()) {
+                (true, "true") => options.push(format!("--{key}")),
+                (true, "false") => {}
+                (true, _) => return Err(format!("{key} is a flag, expected true or false")),
+                (false, _) => options.push(format!("--{key}={value}")),
+            }
+        }
+    }
+
+    // Forwards first, where they can't be taken as the value of an option
+    Ok(forwards.into_iter().chain(options).map(OsString::from).collect())
+}
+
+/// The value of a key as argument values, with arrays giving one per element.
+fn values(key: &str, value: &toml::Value) -> Result<Vec<String>, String> {
+    match value {
+        toml::Value::Array(values) => values.iter().map(|v| value_string(key, v)).collect(),
+        v => Ok(vec![value_string(key, v)?]),
+    }
+}
+
+fn value_string(key: &str, value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("{key} must be a string, number, boolean or an array of them")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::cli::CliArgs;
+
+    fn args(config: &str, is_set: impl Fn(&str) -> bool) -> Result<Vec<OsString>, String> {
+        to_args(&config.parse().unwrap(), &CliArgs::command(), is_set)
+    }
+
+    #[test]
+    fn keys_become_arguments() {
+        let config = r#"
+            context = "staging"
+            ignore-readiness = true
+            lazy = false
+            prewarm = 2
+            allow-cidr = ["10.0.0.0/8", "fd00::/8"]
+            forwards = ["postgres:5432", "8080:web/frontend:80"]
+        "#;
+
+        assert_eq!(
+            args(config, |_| false).unwrap(),
+            [
+                "postgres:5432",
+                "8080:web/frontend:80",
+                "--allow-cidr=10.0.0.0/8",
+                "--allow-cidr=fd00::/8",
+                "--context=staging",
+                "--ignore-readiness",
+                "--prewarm=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn options_set_elsewhere_take_precedence() {
+        let config = r#"
+            context = "staging"
+            namespace = "web"
+            forwards = ["postgres:5432"]
+        "#;
+
+        assert_eq!(
+            args(config, |id| id == "context").unwrap(),
+            ["postgres:5432", "--namespace=web"]
+        );
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert_eq!(args("no-such-option = 1", |_| false).unwrap_err(), "unknown option no-such-option");
+        assert_eq!(args("config = \"other.toml\"", |_| false).unwrap_err(), "unknown option config");
+        assert!(args("lazy = \"yes\"", |_| false).is_err());
+        assert!(args("[context]\nname = \"staging\"", |_| false).is_err());
+    }
+}
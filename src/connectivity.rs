@@ -0,0 +1,179 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use kube::Client;
+use tracing::{debug, info, warn};
+
+use crate::{
+    errors::MyError,
+    hook::{self, Event},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Tracks whether a client's API server is reachable, so an outage (eg. a laptop waking from
+/// sleep) is reported once and probed with backoff instead of by every new connection.
+pub struct Connectivity {
+    client: Client,
+    context: Option<String>,
+    online: AtomicBool,
+}
+
+impl Connectivity {
+    pub fn new(client: Client, context: Option<String>) -> Arc<Self> {
+        Arc::new(Self {
+            client,
+            context,
+            online: AtomicBool::new(true),
+        })
+    }
+
+    /// Fails fast while the API server is known to be unreachable.
+    pub fn check(&self) -> Result<(), MyError> {
+        match self.online.load(Ordering::Relaxed) {
+            true => Ok(()),
+            false => Err(MyError::ApiServerUnreachable()),
+        }
+    }
+
+    /// Looks at a failed connection's error, and starts probing the API server if it was the
+    /// first sign of it being unreachable. Returns whether the error was down to the outage.
+    pub fn observe(self: &Arc<Self>, error: &anyhow::Error) -> bool {
+        if !is_unreachable(error) {
+            return false;
+        }
+
+        if self.online.swap(false, Ordering::Relaxed) {
+            warn!(
+                error = error.as_ref() as &dyn std::error::Error,
+                context = self.context,
+                "API server unreachable, rejecting connections until it recovers"
+            );
+            hook::fire(Event::ApiUnreachable, self.context.as_deref().unwrap_or_default(), None);
+            tokio::spawn(self.clone().probe());
+        }
+
+        true
+    }
+
+    /// Waits for the API server to answer, probing with backoff, for at most `deadline`.
+    pub async fn wait_until_reachable(&self, deadline: Duration) -> Result<(), MyError> {
+        let started = Instant::now();
+        let wait = async {
+            for attempt in 0.. {
+                match self.client.apiserver_version().await {
+                    Ok(_) => return,
+                    Err(e) if attempt == 0 => info!(
+                        error = &e as &dyn std::error::Error,
+                        context = self.context,
+                        "waiting for the API server to become reachable"
+                    ),
+                    Err(e) => debug!(error = &e as &dyn std::error::Error, attempt, "API server still unreachable"),
+                }
+
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+        };
+
+        match tokio::time::timeout(deadline, wait).await {
+            Ok(()) => {
+                info!(
+                    context = self.context,
+                    waited_ms = started.elapsed().as_millis() as u64,
+                    "API server reachable"
+                );
+                Ok(())
+            }
+            Err(_) => Err(MyError::ApiServerWaitTimedOut(deadline.as_secs())),
+        }
+    }
+
+    async fn probe(self: Arc<Self>) {
+        let mut attempt = 0;
+
+        loop {
+            tokio::time::sleep(backoff(attempt)).await;
+            attempt += 1;
+
+            if self.client.apiserver_version().await.is_ok() {
+                self.online.store(true, Ordering::Relaxed);
+                info!(context = self.context, "API server reachable again");
+                hook::fire(Event::ApiReachable, self.context.as_deref().unwrap_or_default(), None);
+                return;
+            }
+        }
+    }
+}
+
+/// Delay before the given probe attempt, doubling from [`INITIAL_BACKOFF`] up to [`MAX_BACKOFF`].
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF
+        .checked_mul(2u32.saturating_pow(attempt))
+        .map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF))
+}
+
+/// Whether an error means the API server couldn't be reached at all, as opposed to it answering
+/// with an error.
+fn is_unreachable(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<kube::Error>(),
+            Some(kube::Error::HyperError(_) | kube::Error::Service(_))
+        ) || matches!(e.downcast_ref::<MyError>(), Some(MyError::ApiServerUnreachable()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(16));
+        assert_eq!(backoff(5), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn transport_errors_are_unreachable() {
+        let error = kube::Error::Service(Box::new(std::io::Error::other("connection refused")));
+
+        assert!(is_unreachable(&anyhow::Error::from(error)));
+        assert!(is_unreachable(&MyError::ApiServerUnreachable().into()));
+    }
+
+    #[test]
+    fn api_errors_are_not_unreachable() {
+        let error = kube::Error::Api(kube::core::ErrorResponse {
+            status: "Failure".to_owned(),
+            message: "services \"test\" not found".to_owned(),
+            reason: "NotFound".to_owned(),
+            code: 404,
+        });
+
+        assert!(!is_unreachable(&anyhow::Error::from(error)));
+        assert!(!is_unreachable(&MyError::NoPodsMatchSelector().into()));
+    }
+
+    #[tokio::test]
+    async fn wait_gives_up_at_deadline() {
+        // Nothing listens on the discard port, so connecting is refused straight away
+        let config = kube::Config::new("http://127.0.0.1:9".parse().unwrap());
+        let connectivity = Connectivity::new(Client::try_from(config).unwrap(), None);
+
+        let err = connectivity
+            .wait_until_reachable(Duration::from_millis(100))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, MyError::ApiServerWaitTimedOut(0)));
+    }
+}
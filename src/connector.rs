@@ -0,0 +1,301 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    process::Stdio,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::Uri;
+use hyper_timeout::TimeoutConnector;
+use hyper_util::{
+    client::legacy::connect::{Connected, Connection},
+    rt::{TokioExecutor, TokioIo},
+};
+use kube::{
+    client::{Body, ConfigExt},
+    Client, Config,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+use tower::{BoxError, ServiceBuilder};
+use tower_http::trace::TraceLayer;
+use tracing::debug;
+
+use crate::errors::MyError;
+
+/// Builds a client whose connections to the API server, including port-forwards, are made
+/// through `--connect-via`, with the timeouts and request tracing of kube's own clients.
+pub fn client(config: Config, connect_via: &str) -> Result<Client, kube::Error> {
+    let connector = config.rustls_https_connector_with_connector(CommandConnector::new(connect_via))?;
+    let mut connector = TimeoutConnector::new(connector);
+    connector.set_connect_timeout(config.connect_timeout);
+    connector.set_read_timeout(config.read_timeout);
+    connector.set_write_timeout(config.write_timeout);
+    let http = hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build::<_, Body>(connector);
+
+    let service = ServiceBuilder::new()
+        .layer(config.base_uri_layer())
+        .option_layer(config.auth_layer()?)
+        .layer(config.extra_headers_layer()?)
+        .layer(TraceLayer::new_for_http())
+        .map_err(BoxError::from)
+        .service(http);
+
+    Ok(Client::new(service, config.default_namespace))
+}
+
+/// Expands `ssh://[USER@]HOST[:PORT]` into the equivalent `ssh -W` command, leaving any other
+/// command as it is.
+pub fn parse_connect_via(connect_via: &str) -> Result<String, MyError> {
+    let Some(destination) = connect_via.strip_prefix("ssh://") else {
+        return match connect_via.split_whitespace().next() {
+            Some(_) => Ok(connect_via.to_owned()),
+            None => Err(MyError::InvalidConnectVia(connect_via.to_owned())),
+        };
+    };
+
+    let destination = destination.trim_end_matches('/');
+    let (user_host, port) = match destination.rsplit_once(':') {
+        Some((user_host, port)) => (
+            user_host,
+            Some(port.parse::<u16>().map_err(|_| MyError::InvalidConnectVia(connect_via.to_owned()))?),
+        ),
+        None => (destination, None),
+    };
+    if user_host.is_empty() || user_host.ends_with('@') || user_host.contains(char::is_whitespace) {
+        return Err(MyError::InvalidConnectVia(connect_via.to_owned()));
+    }
+
+    Ok(match port {
+        Some(port) => format!("ssh -W {{host}}:{{port}} -p {port} {user_host}"),
+        None => format!("ssh -W {{host}}:{{port}} {user_host}"),
+    })
+}
+
+/// Connects by running a command for each connection, with `{host}` and `{port}` substituted,
+/// and talking to the API server over its stdin and stdout.
+#[derive(Clone)]
+struct CommandConnector {
+    template: Arc<str>,
+}
+
+impl CommandConnector {
+    fn new(template: &str) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+}
+
+impl tower::Service<Uri> for CommandConnector {
+    type Response = TokioIo<CommandStream>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let template = self.template.clone();
+
+        Box::pin(async move {
+            let host = uri
+                .host()
+                .map(|h| h.trim_start_matches('[').trim_end_matches(']'))
+                .ok_or_else(|| std::io::Error::other(format!("{uri} has no host to connect to")))?;
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("http") => 80,
+                _ => 443,
+            });
+
+            CommandStream::spawn(&template, host, port).map(TokioIo::new)
+        })
+    }
+}
+
+/// A connection made through a running command.
+struct CommandStream {
+    // Kept so the command is killed when the connection is dropped
+    _child: Child,
+    // Taken on shutdown, as closing the pipe is the only way to signal the end of the stream
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl CommandStream {
+    fn spawn(template: &str, host: &str, port: u16) -> std::io::Result<Self> {
+        let command = build_command(template, host, port);
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| std::io::Error::other("--connect-via command is empty"))?;
+
+        debug!(command = command.join(" "), "connecting via command");
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().ok_or_else(|| std::io::Error::other("command has no stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| std::io::Error::other("command has no stdout"))?;
+
+        Ok(Self {
+            _child: child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+}
+
+impl Connection for CommandStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        match &mut self.get_mut().stdin {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        match &mut self.get_mut().stdin {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let stdin = &mut self.get_mut().stdin;
+
+        if let Some(s) = stdin {
+            std::task::ready!(Pin::new(s).poll_flush(cx))?;
+        }
+        *stdin = None;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Splits the command template into arguments, substituting `{host}` and `{port}`. An IPv6
+/// host followed by `:{port}` keeps its brackets, as `ssh -W` needs.
+fn build_command(template: &str, host: &str, port: u16) -> Vec<String> {
+    let host_port = match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    };
+
+    template
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{host}:{port}", &host_port)
+                .replace("{host}", host)
+                .replace("{port}", &port.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn ssh_shorthand() {
+        assert_eq!(
+            parse_connect_via("ssh://ops@jump.example.test").unwrap(),
+            "ssh -W {host}:{port} ops@jump.example.test"
+        );
+        assert_eq!(
+            parse_connect_via("ssh://jump.example.test:2222/").unwrap(),
+            "ssh -W {host}:{port} -p 2222 jump.example.test"
+        );
+        assert_eq!(
+            parse_connect_via("nc -X connect -x proxy:3128 {host} {port}").unwrap(),
+            "nc -X connect -x proxy:3128 {host} {port}"
+        );
+    }
+
+    #[test]
+    fn invalid_connect_via() {
+        for arg in ["", "  ", "ssh://", "ssh://ops@", "ssh://jump:port"] {
+            assert!(matches!(parse_connect_via(arg), Err(MyError::InvalidConnectVia(_))), "{arg:?}");
+        }
+    }
+
+    #[test]
+    fn command_substitution() {
+        assert_eq!(
+            build_command("ssh -W {host}:{port} jump", "10.0.0.1", 6443),
+            ["ssh", "-W", "10.0.0.1:6443", "jump"]
+        );
+        assert_eq!(
+            build_command("ssh -W {host}:{port} jump", "fd00::1", 6443),
+            ["ssh", "-W", "[fd00::1]:6443", "jump"]
+        );
+        assert_eq!(
+            build_command("nc {host} {port}", "fd00::1", 6443),
+            ["nc", "fd00::1", "6443"]
+        );
+    }
+
+    #[tokio::test]
+    async fn ipv6_api_server() {
+        let mut connector = CommandConnector::new("echo {host}:{port}");
+        let uri = Uri::from_static("https://[fd00::1]:6443");
+
+        let mut stream = tower::Service::call(&mut connector, uri).await.unwrap().into_inner();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, "[fd00::1]:6443\n");
+    }
+
+    #[tokio::test]
+    async fn talks_over_stdin_and_stdout() {
+        let mut stream = CommandStream::spawn("cat", "unused", 0).unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut echoed = vec![];
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_read_timeout() {
+        let mut config = Config::new("http://kube.example.test".parse().unwrap());
+        config.read_timeout = Some(std::time::Duration::from_millis(100));
+        let client = client(config, "sleep 30").unwrap();
+
+        let request = tokio::time::timeout(std::time::Duration::from_secs(10), client.apiserver_version());
+        assert!(request.await.expect("timed out by the client").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{path::Path, time::Duration};
+
+use futures::{StreamExt, TryStreamExt};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, info, info_span, trace, Instrument};
+
+use crate::{
+    accept,
+    cli::{Command, Forward},
+    errors::MyError,
+    events,
+    listener::{self, Listener},
+    shutdown,
+};
+
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest request read, far more than any forward needs
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// A request made through --control-socket, sent as a single line per connection.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// `add FORWARD`
+    Add(Forward),
+    /// `remove FORWARD`, the forward as it was given
+    Remove(Forward),
+    /// `list`
+    List,
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Self, String> {
+        let (verb, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let forward = || Forward::parse(arg.trim()).map_err(|e| e.to_string());
+
+        match verb {
+            "add" => Ok(Request::Add(forward()?)),
+            "remove" => Ok(Request::Remove(forward()?)),
+            "list" if arg.is_empty() => Ok(Request::List),
+            _ => Err(format!("unknown request {line:?}, expected add FORWARD, remove FORWARD or list")),
+        }
+    }
+}
+
+/// The lines answering a request, or why it failed.
+pub type Reply = Result<Vec<String>, String>;
+
+/// The requests made through the socket, each with where to send its reply.
+pub type Requests = mpsc::Receiver<(Request, oneshot::Sender<Reply>)>;
+
+/// Listens on the --control-socket at `path`, handing the requests made through it over to be
+/// carried out.
+pub fn bind(path: &Path) -> anyhow::Result<(JoinHandle<anyhow::Result<()>>, Requests)> {
+    let control_span = info_span!("control").entered();
+
+    let listener = listener::bind_unix(path)?;
+    let local_addr = format!("unix:{}", path.display());
+    info!(local_addr, "bound");
+    events::bound("control", &local_addr);
+
+    let (tx, rx) = mpsc::channel(16);
+    let control_span = control_span.exit();
+    Ok((tokio::spawn(serve(listener, tx).instrument(control_span)), rx))
+}
+
+async fn serve(listener: Listener, requests: mpsc::Sender<(Request, oneshot::Sender<Reply>)>) -> anyhow::Result<()> {
+    listener
+        .incoming()
+        .take_until(shutdown::requested())
+        .filter_map(|accepted| async { accept::recover(accepted).await.transpose() })
+        .try_for_each(|(conn, _)| async {
+            let requests = requests.clone();
+            tokio::spawn(
+                async move {
+                    if let Err(e) = handle(conn, requests).await {
+                        debug!(error = &e as &dyn std::error::Error, "failed to answer control request");
+                    }
+                }
+                .in_current_span(),
+            );
+
+            Ok(())
+        })
+        .await?;
+
+    trace!("closed");
+    Ok(())
+}
+
+async fn handle<T>(conn: T, requests: mpsc::Sender<(Request, oneshot::Sender<Reply>)>) -> std::io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut conn = BufReader::new(conn);
+    let mut line = String::new();
+    let mut limited = (&mut conn).take(MAX_REQUEST_LEN);
+    match tokio::time::timeout(REQUEST_TIMEOUT, limited.read_line(&mut line)).await {
+        Ok(read) => read?,
+        Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+    };
+
+    let reply = match Request::parse(&line) {
+        Ok(request) => {
+            let (tx, rx) = oneshot::channel();
+            match requests.send((request, tx)).await {
+                Ok(()) => rx.await.unwrap_or_else(|_| Err("kubempf is shutting down".to_owned())),
+                Err(_) => Err("kubempf is shutting down".to_owned()),
+            }
+        }
+        Err(e) => Err(e),
+    };
+
+    let conn = conn.get_mut();
+    conn.write_all(response(&reply).as_bytes()).await?;
+    conn.shutdown().await
+}
+
+/// `ok` followed by the reply's lines, or `error: ` followed by why it failed.
+fn response(reply: &Reply) -> String {
+    match reply {
+        Ok(lines) => ["ok".to_owned()].iter().chain(lines).map(|l| format!("{l}\n")).collect(),
+        Err(e) => format!("error: {e}\n"),
+    }
+}
+
+/// The lines of a response, or the error it reports.
+fn parse_response(response: &str) -> Result<Vec<String>, MyError> {
+    let mut lines = response.lines();
+    match lines.next() {
+        Some("ok") => Ok(lines.map(str::to_owned).collect()),
+        Some(line) => Err(MyError::ControlRequestFailed(
+            line.strip_prefix("error: ").unwrap_or(line).to_owned(),
+        )),
+        None => Err(MyError::ControlRequestFailed("no reply".to_owned())),
+    }
+}
+
+/// Describes a forward for `list`, as where it listens and what it forwards to.
+pub fn describe(forward: &Forward) -> String {
+    let local = match (&forward.local_path, forward.local_address) {
+        (Some(path), _) => format!("unix:{}", path.display()),
+        (None, Some(address)) if address.is_ipv6() => format!("[{address}]"),
+        (None, Some(address)) => address.to_string(),
+        (None, None) => "localhost".to_owned(),
+    };
+    let local_port = match (&forward.local_path, forward.local_port) {
+        (None, Some(port)) => format!(":{port}"),
+        _ => String::new(),
+    };
+
+    format!(
+        "{local}{local_port} -> {namespace}{kind}{service}{port}",
+        namespace = forward.namespace.as_ref().map(|ns| format!("{ns}/")).unwrap_or_default(),
+        kind = forward.kind.prefix(),
+        service = forward.service_name,
+        port = forward.service_port.as_ref().map(|p| format!(":{p}")).unwrap_or_default(),
+    )
+}
+
+/// Makes the request for `kubempf add`, `remove` or `list` to the kubempf listening on the
+/// control socket, printing its reply.
+pub async fn run(command: &Command) -> anyhow::Result<()> {
+    let (path, request) = match command {
+        Command::Add { forward, socket } => (&socket.control_socket, format!("add {forward}")),
+        Command::Remove { forward, socket } => (&socket.control_socket, format!("remove {forward}")),
+        Command::List { socket } => (&socket.control_socket, "list".to_owned()),
+    };
+
+    for line in parse_response(&send(path, &request).await?)? {
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn send(path: &Path, request: &str) -> Result<String, MyError> {
+    let unavailable = |e| MyError::ControlSocketUnavailable(path.display().to_string(), e);
+
+    let mut conn = tokio::net::UnixStream::connect(path).await.map_err(unavailable)?;
+    conn.write_all(format!("{request}\n").as_bytes()).await.map_err(unavailable)?;
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.map_err(unavailable)?;
+
+    Ok(response)
+}
+
+#[cfg(not(unix))]
+async fn send(path: &Path, _request: &str) -> Result<String, MyError> {
+    Err(MyError::UnixSocketUnsupported(path.display().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        assert_eq!(
+            Request::parse("add data/postgres:5432\n"),
+            Ok(Request::Add(Forward::parse("data/postgres:5432").unwrap()))
+        );
+        assert_eq!(
+            Request::parse("remove 15432:postgres:5432"),
+            Ok(Request::Remove(Forward::parse("15432:postgres:5432").unwrap()))
+        );
+        assert_eq!(Request::parse("list\n"), Ok(Request::List));
+        assert!(Request::parse("add").is_err());
+        assert!(Request::parse("restart web:80").is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (tx, mut rx) = mpsc::channel(1);
+        client.write_all(b"list\n").await.unwrap();
+
+        let answered = tokio::spawn(handle(server, tx));
+        let (request, reply) = rx.recv().await.unwrap();
+        reply.send(Ok(vec!["localhost:80 -> web:80".to_owned()])).unwrap();
+        answered.await.unwrap().unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert_eq!(request, Request::List);
+        assert_eq!(response, "ok\nlocalhost:80 -> web:80\n");
+        assert_eq!(parse_response(&response).unwrap(), ["localhost:80 -> web:80"]);
+        assert!(matches!(
+            parse_response("error: no such forward is running\n"),
+            Err(MyError::ControlRequestFailed(e)) if e == "no such forward is running"
+        ));
+    }
+
+    #[test]
+    fn describes_forwards() {
+        let describe = |f| describe(&Forward::parse(f).unwrap());
+
+        assert_eq!(describe("data/postgres:5432"), "localhost -> data/postgres:5432");
+        assert_eq!(describe("[::1]:8080:web:80"), "[::1]:8080 -> web:80");
+        assert_eq!(describe("unix:/tmp/pg.sock:postgres"), "unix:/tmp/pg.sock -> postgres");
+    }
+}
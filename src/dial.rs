@@ -0,0 +1,58 @@
+use std::net::SocketAddr;
+
+use crate::{cli::Forward, errors::MyError};
+
+/// A `--dial ADDRESS:PORT=TARGET`, connecting out to a local listener at `ADDRESS:PORT` once
+/// and bridging that single connection to `TARGET`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dial {
+    pub address: SocketAddr,
+    pub forward: Forward,
+}
+
+impl Dial {
+    pub fn parse(arg: &str) -> anyhow::Result<Dial> {
+        let invalid = |reason: &str| MyError::InvalidDial(arg.to_owned(), reason.to_owned());
+
+        let (address, target) = arg
+            .split_once('=')
+            .ok_or_else(|| invalid("expected ADDRESS:PORT=[NAMESPACE/]SERVICE[:PORT]"))?;
+        let address = address
+            .trim()
+            .parse()
+            .map_err(|_| invalid("expected an address and port to connect to, eg. 127.0.0.1:9000 or [::1]:9000"))?;
+
+        let forward = Forward::parse_remote(target)
+            .map_err(|_| invalid("expected [NAMESPACE/]SERVICE[:PORT][?OPTIONS] after the address"))?;
+
+        Ok(Dial { address, forward })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dial() {
+        let dial = Dial::parse("127.0.0.1:9000=monitoring/grafana:http?ignore-readiness").unwrap();
+
+        assert_eq!(dial.address, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(dial.forward.namespace.as_deref(), Some("monitoring"));
+        assert_eq!(dial.forward.service_name, "grafana");
+        assert_eq!(dial.forward.service_port.as_deref(), Some("http"));
+        assert_eq!(dial.forward.options.ignore_readiness, Some(true));
+
+        assert_eq!(Dial::parse("[::1]:9000=grafana").unwrap().address, "[::1]:9000".parse().unwrap());
+    }
+
+    #[test]
+    fn parse_dial_errors() {
+        for arg in ["grafana", "9000=grafana", "localhost:9000=grafana", "127.0.0.1:9000=", "127.0.0.1:9000=8080:grafana:80"] {
+            assert!(
+                matches!(Dial::parse(arg).map_err(|e| e.downcast::<MyError>()), Err(Ok(MyError::InvalidDial(..)))),
+                "{arg}"
+            );
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use clap::ValueEnum;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::pin;
+
+/// Which way --direction lets data through a forward.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Direction {
+    /// Relay data both ways
+    #[default]
+    Both,
+    /// Only relay data from the client to the pod, closing the pod to client direction at once
+    UpOnly,
+    /// Only relay data from the pod to the client, closing the client to pod direction at once
+    DownOnly,
+}
+
+/// Copies between `client` and `upstream` in the allowed direction until it ends, returning the
+/// bytes sent up and down. The suppressed direction is shut down before anything is copied, so
+/// its reader sees the end of the stream straight away.
+pub async fn copy<A, B>(direction: Direction, client: &mut A, upstream: &mut B) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    match direction {
+        Direction::Both => tokio::io::copy_bidirectional(client, upstream).await,
+        Direction::UpOnly => {
+            client.shutdown().await?;
+            let up = copy_discarding(client, upstream).await?;
+            Ok((up, 0))
+        }
+        Direction::DownOnly => {
+            upstream.shutdown().await?;
+            let down = copy_discarding(upstream, client).await?;
+            Ok((0, down))
+        }
+    }
+}
+
+/// Copies `from` to `to` and shuts `to` down, returning the bytes copied. Meanwhile anything
+/// `to` sends back is read and dropped, as a peer left with its writes unread can stall, eg.
+/// once the port-forward's buffer for the pod's side fills up.
+async fn copy_discarding<A, B>(from: &mut A, to: &mut B) -> std::io::Result<u64>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let (mut discarded, mut to) = tokio::io::split(to);
+
+    let copy = async {
+        let copied = tokio::io::copy(from, &mut to).await?;
+        to.shutdown().await?;
+        Ok::<_, std::io::Error>(copied)
+    };
+    let mut sink = tokio::io::sink();
+    let discard = tokio::io::copy(&mut discarded, &mut sink);
+    pin!(copy);
+    pin!(discard);
+
+    tokio::select! {
+        copied = &mut copy => copied,
+        // Once there is nothing more to discard, or it can't be read, only the copy is left
+        _ = &mut discard => copy.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+
+    use super::*;
+
+    /// Runs a copy in `direction` with each end sending `sent`, returning the copy's byte counts
+    /// and what the client and pod ends received.
+    async fn run(direction: Direction, sent: &[u8]) -> ((u64, u64), Vec<u8>, Vec<u8>) {
+        let (mut client, mut client_remote) = duplex(64);
+        let (mut upstream, mut upstream_remote) = duplex(64);
+
+        let copy = tokio::spawn(async move { copy(direction, &mut client, &mut upstream).await });
+
+        async fn send_and_receive(stream: &mut DuplexStream, sent: &[u8]) -> Vec<u8> {
+            stream.write_all(sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        }
+
+        let (client_received, upstream_received) = tokio::join!(
+            send_and_receive(&mut client_remote, sent),
+            send_and_receive(&mut upstream_remote, sent)
+        );
+
+        (copy.await.unwrap().unwrap(), client_received, upstream_received)
+    }
+
+    #[tokio::test]
+    async fn both_directions() {
+        assert_eq!(run(Direction::Both, b"ping").await, ((4, 4), b"ping".to_vec(), b"ping".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn up_only_sends_nothing_down() {
+        assert_eq!(run(Direction::UpOnly, b"ping").await, ((4, 0), Vec::new(), b"ping".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn down_only_sends_nothing_up() {
+        assert_eq!(run(Direction::DownOnly, b"ping").await, ((0, 4), b"ping".to_vec(), Vec::new()));
+    }
+
+    /// The suppressed direction is still read, so an end sending more than fits in the buffer
+    /// doesn't hold the copy up.
+    #[tokio::test]
+    async fn suppressed_direction_is_drained() {
+        let sent = [7; 1024];
+
+        assert_eq!(run(Direction::UpOnly, &sent).await, ((1024, 0), Vec::new(), sent.to_vec()));
+        assert_eq!(run(Direction::DownOnly, &sent).await, ((0, 1024), sent.to_vec(), Vec::new()));
+    }
+}
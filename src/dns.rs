@@ -0,0 +1,345 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+};
+
+use kube::Client;
+use tokio::{net::UdpSocket, sync::OnceCell, task::JoinHandle};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    alias::Aliases,
+    cli::{ControlArgs, Forward, ForwardOptions, TargetKind},
+    connectivity::Connectivity,
+    shutdown,
+    socks,
+};
+
+/// The domain service hostnames are answered under
+const SERVICE_DOMAIN: &str = "svc.cluster.local";
+
+/// How long resolvers may cache an answer for. The alias for a service doesn't change while
+/// kubempf runs, but kubempf may be restarted and hand out different ones.
+const TTL: u32 = 30;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const NO_ERROR: u8 = 0;
+const FORMAT_ERROR: u8 = 1;
+const SERVER_FAILURE: u8 = 2;
+const NAME_ERROR: u8 = 3;
+const REFUSED: u8 = 5;
+
+/// A query for a single name.
+#[derive(PartialEq, Eq, Debug)]
+struct Query {
+    id: u16,
+    /// The opcode and recursion desired flags, which are echoed back
+    flags: u16,
+    /// Lowercased, without a trailing `.`
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// The question as sent, to repeat in the response
+    question: Vec<u8>,
+}
+
+fn parse_query(packet: &[u8]) -> Option<Query> {
+    let header = packet.get(..HEADER_LEN)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    // A response, or more than one question, which no resolver sends
+    if flags & 0x8000 != 0 || questions != 1 {
+        return None;
+    }
+
+    let mut labels = vec![];
+    let mut at = HEADER_LEN;
+    loop {
+        let len = usize::from(*packet.get(at)?);
+        at += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers have no place in a query's only question
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(at..at + len)?).to_ascii_lowercase());
+        at += len;
+    }
+    let fields = packet.get(at..at + 4)?;
+
+    Some(Query {
+        id,
+        flags: flags & 0x7900,
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+        question: packet[HEADER_LEN..at + 4].to_vec(),
+    })
+}
+
+/// Builds the response to `query`, answering it with `address` if given.
+fn response(query: &Query, code: u8, address: Option<Ipv4Addr>) -> Vec<u8> {
+    // A response, authoritative, with the query's opcode and recursion desired flags
+    let flags = 0x8000 | 0x0400 | query.flags | u16::from(code);
+
+    let mut packet = Vec::with_capacity(HEADER_LEN + query.question.len() + 16);
+    packet.extend_from_slice(&query.id.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    packet.extend_from_slice(&u16::from(address.is_some()).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    packet.extend_from_slice(&query.question);
+
+    if let Some(address) = address {
+        // The name, as a pointer to the question's
+        packet.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        packet.extend_from_slice(&TYPE_A.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&TTL.to_be_bytes());
+        packet.extend_from_slice(&4u16.to_be_bytes());
+        packet.extend_from_slice(&address.octets());
+    }
+
+    packet
+}
+
+/// The `(namespace, service)` a name under [`SERVICE_DOMAIN`] is for, `None` when it's under
+/// the domain but not a service's name, or `Err` when it's outside of the domain.
+fn service_for_name(name: &str) -> Result<Option<(String, String)>, ()> {
+    let Some(service) = name.strip_suffix(SERVICE_DOMAIN).and_then(|s| s.strip_suffix('.')) else {
+        return Err(());
+    };
+
+    Ok(match socks::service_for_host(service) {
+        Some((Some(namespace), service)) => Some((namespace, service)),
+        _ => None,
+    })
+}
+
+/// The forwards started for the services looked up through --dns, each listening on every
+/// port of the service on a loopback alias of its own.
+pub struct Services {
+    client: Client,
+    connectivity: Arc<Connectivity>,
+    args: ControlArgs,
+    started: Mutex<Started>,
+}
+
+/// A service's alias, and its forwards listening on it once started. Queries for the service
+/// wait on the cell while its forwards start, so it's only started once, without holding up
+/// queries for other services.
+struct Service {
+    alias: Ipv4Addr,
+    forwards: Arc<OnceCell<JoinHandle<anyhow::Result<()>>>>,
+}
+
+struct Started {
+    aliases: Aliases,
+    /// By namespace and service
+    services: HashMap<(String, String), Service>,
+}
+
+impl Services {
+    pub fn new(client: Client, connectivity: Arc<Connectivity>, args: ControlArgs, aliases: Aliases) -> Self {
+        Self {
+            client,
+            connectivity,
+            // A query can't wait on a service being created, it's answered as not found instead
+            args: ControlArgs {
+                wait_for_service: false,
+                ..args
+            },
+            started: Mutex::new(Started {
+                aliases,
+                services: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The alias the service's forwards listen on, starting them when first asked for, or
+    /// again if they have stopped.
+    async fn address(&self, namespace: &str, service: &str) -> anyhow::Result<Ipv4Addr> {
+        let (alias, forwards) = {
+            let mut started = self.started.lock().unwrap();
+            let Started { aliases, services } = &mut *started;
+            let running = match services.entry((namespace.to_owned(), service.to_owned())) {
+                Entry::Occupied(running) => running.into_mut(),
+                Entry::Vacant(entry) => {
+                    let alias = aliases.next().ok_or_else(|| anyhow::anyhow!("no loopback aliases left"))?;
+                    entry.insert(Service {
+                        alias,
+                        forwards: Arc::default(),
+                    })
+                }
+            };
+            if running.forwards.get().is_some_and(|handle| handle.is_finished()) {
+                running.forwards = Arc::default();
+            }
+
+            (running.alias, running.forwards.clone())
+        };
+
+        forwards.get_or_try_init(|| self.start(namespace, service, alias)).await?;
+        Ok(alias)
+    }
+
+    /// Starts the service's forwards listening on `alias`.
+    async fn start(
+        &self,
+        namespace: &str,
+        service: &str,
+        alias: Ipv4Addr,
+    ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+        let forward = Forward {
+            service_name: service.to_owned(),
+            service_port: Some("*".to_owned()),
+            namespace: Some(namespace.to_owned()),
+            local_address: Some(IpAddr::V4(alias)),
+            local_port: None,
+            local_path: None,
+            options: ForwardOptions::default(),
+            kind: TargetKind::Service,
+        };
+        let handle = crate::create_forward(
+            self.client.clone(),
+            self.connectivity.clone(),
+            &forward,
+            self.args.clone(),
+            crate::Lifecycle::global(),
+        )
+        .await?;
+        info!(namespace, service, alias = %alias, "started forwards for service looked up through --dns");
+
+        Ok(handle)
+    }
+
+    async fn answer(&self, query: &Query) -> Vec<u8> {
+        let (namespace, service) = match service_for_name(&query.name) {
+            Err(()) => return response(query, REFUSED, None),
+            Ok(None) => return response(query, NAME_ERROR, None),
+            Ok(Some(s)) => s,
+        };
+
+        let address = match self.address(&namespace, &service).await {
+            Ok(address) => address,
+            Err(e) => {
+                warn!(error = e.as_ref() as &dyn std::error::Error, "failed to start forwards for service");
+                let code = match e.downcast_ref::<kube::Error>() {
+                    Some(kube::Error::Api(r)) if r.code == 404 => NAME_ERROR,
+                    _ => SERVER_FAILURE,
+                };
+                return response(query, code, None);
+            }
+        };
+
+        // Services only get an IPv4 alias, so any other type of record has no data
+        match (query.qtype, query.qclass) {
+            (TYPE_A, CLASS_IN) => response(query, NO_ERROR, Some(address)),
+            _ => response(query, NO_ERROR, None),
+        }
+    }
+}
+
+/// Answers queries for service names on `socket` until shutting down.
+pub async fn serve(socket: UdpSocket, services: Arc<Services>) -> anyhow::Result<()> {
+    let socket = Arc::new(socket);
+    let mut buf = [0u8; 512];
+
+    loop {
+        let (len, peer) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = shutdown::requested() => return Ok(()),
+        };
+
+        let Some(query) = parse_query(&buf[..len]) else {
+            debug!(peer_addr = peer.to_string(), "ignored malformed query");
+            if len >= 2 {
+                // Echoing the id, so the sender isn't left waiting
+                let mut reply = vec![buf[0], buf[1], 0x80, FORMAT_ERROR];
+                reply.extend_from_slice(&[0; HEADER_LEN - 4]);
+                let _ = socket.send_to(&reply, peer).await;
+            }
+            continue;
+        };
+
+        // Starting a service's forwards can take a while, which shouldn't hold up other queries
+        let (socket, services) = (socket.clone(), services.clone());
+        let span = info_span!("query", name = query.name, peer_addr = peer.to_string());
+        tokio::spawn(
+            async move {
+                let answer = services.answer(&query).await;
+                if let Err(e) = socket.send_to(&answer, peer).await {
+                    debug!(error = &e as &dyn std::error::Error, "failed to send answer");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn parses_queries() {
+        let packet = query("Postgres.Data.svc.cluster.local", TYPE_A);
+
+        let parsed = parse_query(&packet).unwrap();
+
+        assert_eq!(parsed.id, 0x1234);
+        assert_eq!(parsed.name, "postgres.data.svc.cluster.local");
+        assert_eq!(parsed.qtype, TYPE_A);
+        assert_eq!(parsed.question, &packet[HEADER_LEN..]);
+
+        assert_eq!(parse_query(&packet[..packet.len() - 1]), None);
+        assert_eq!(parse_query(&[0x12, 0x34]), None);
+    }
+
+    #[test]
+    fn answers_with_the_alias() {
+        let packet = query("postgres.data.svc.cluster.local", TYPE_A);
+        let parsed = parse_query(&packet).unwrap();
+
+        let answer = response(&parsed, NO_ERROR, Some(Ipv4Addr::new(127, 0, 2, 1)));
+
+        // id, then a recursion desired response with one question and one answer
+        assert_eq!(answer[..8], [0x12, 0x34, 0x85, 0x00, 0, 1, 0, 1]);
+        assert_eq!(answer[HEADER_LEN..HEADER_LEN + parsed.question.len()], parsed.question);
+        assert_eq!(answer[answer.len() - 4..], [127, 0, 2, 1]);
+
+        let refused = response(&parsed, REFUSED, None);
+        assert_eq!(refused[2..8], [0x85, REFUSED, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn service_names() {
+        assert_eq!(
+            service_for_name("postgres.data.svc.cluster.local"),
+            Ok(Some(("data".to_owned(), "postgres".to_owned())))
+        );
+        assert_eq!(service_for_name("pod-0.postgres.data.svc.cluster.local"), Ok(None));
+        assert_eq!(service_for_name("data.svc.cluster.local"), Ok(None));
+        assert_eq!(service_for_name("example.com"), Err(()));
+        assert_eq!(service_for_name("svc.cluster.local"), Err(()));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::api::{Api, ListParams};
+
+use crate::{errors::MyError, pod::PodChoice};
+
+/// A headless service's endpoints, as listed in its EndpointSlices, which --headless-endpoints
+/// forwards round-robin rather than picking a pod by the service's selector.
+pub struct Endpoints {
+    api: Api<EndpointSlice>,
+    params: ListParams,
+    service_name: String,
+    /// The service port's name, which the slices list the pod port under
+    port_name: String,
+    next: AtomicUsize,
+}
+
+/// An endpoint that can be forwarded to, ie. one backed by a pod.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub pod_name: String,
+    pub port: u16,
+}
+
+impl Endpoints {
+    pub fn new(api: Api<EndpointSlice>, service_name: &str, port_name: Option<&str>) -> Self {
+        Self {
+            api,
+            params: ListParams::default().labels(&format!("kubernetes.io/service-name={service_name}")),
+            service_name: service_name.to_owned(),
+            port_name: port_name.unwrap_or_default().to_owned(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts the service's endpoints, returning `(ready, total)`.
+    pub async fn count(&self) -> anyhow::Result<(usize, usize)> {
+        let slices = self.api.list(&self.params).await?.items;
+        let ready = collect_endpoints(&slices, &self.port_name, false).len();
+        let total = collect_endpoints(&slices, &self.port_name, true).len();
+
+        Ok((ready, total))
+    }
+
+    /// Picks the endpoint after the one picked last time, including those that aren't ready when
+    /// `ignore_readiness` is set.
+    pub async fn next(&self, ignore_readiness: bool) -> anyhow::Result<(Endpoint, PodChoice)> {
+        let slices = self.api.list(&self.params).await?.items;
+        let candidates = collect_endpoints(&slices, &self.port_name, true).len();
+        let mut eligible = collect_endpoints(&slices, &self.port_name, ignore_readiness);
+
+        if eligible.is_empty() {
+            return Err(MyError::NoEndpoints(self.service_name.clone()).into());
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % eligible.len();
+        let choice = PodChoice {
+            strategy: "round-robin",
+            candidates,
+            eligible: eligible.len(),
+            index,
+        };
+
+        Ok((eligible.swap_remove(index), choice))
+    }
+}
+
+/// Collects the pod backed endpoints serving `port_name`, ordered by pod name so the rotation is
+/// stable. A pod appearing in more than one slice (eg. one per address family) is only counted
+/// once.
+fn collect_endpoints(slices: &[EndpointSlice], port_name: &str, include_unready: bool) -> Vec<Endpoint> {
+    let mut endpoints = BTreeMap::new();
+
+    for slice in slices {
+        let Some(port) = slice
+            .ports
+            .iter()
+            .flatten()
+            .find(|p| p.name.as_deref().unwrap_or_default() == port_name)
+            .and_then(|p| p.port)
+            .and_then(|p| u16::try_from(p).ok())
+        else {
+            continue;
+        };
+
+        for endpoint in &slice.endpoints {
+            // Port-forwarding goes through a pod, so endpoints for anything else can't be used
+            let Some(pod_name) = endpoint
+                .target_ref
+                .as_ref()
+                .filter(|r| r.kind.as_deref() == Some("Pod"))
+                .and_then(|r| r.name.clone())
+            else {
+                continue;
+            };
+
+            // A missing ready condition means ready
+            let ready = endpoint.conditions.as_ref().and_then(|c| c.ready).unwrap_or(true);
+            if ready || include_unready {
+                endpoints.insert(pod_name, port);
+            }
+        }
+    }
+
+    endpoints
+        .into_iter()
+        .map(|(pod_name, port)| Endpoint { pod_name, port })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::{
+        core::v1::ObjectReference,
+        discovery::v1::{Endpoint as SliceEndpoint, EndpointConditions, EndpointPort},
+    };
+
+    use super::*;
+
+    fn slice(port_name: Option<&str>, port: i32, endpoints: &[(&str, Option<bool>)]) -> EndpointSlice {
+        EndpointSlice {
+            ports: Some(vec![EndpointPort {
+                name: port_name.map(str::to_owned),
+                port: Some(port),
+                ..Default::default()
+            }]),
+            endpoints: endpoints
+                .iter()
+                .map(|(pod, ready)| SliceEndpoint {
+                    addresses: vec!["10.0.0.1".to_owned()],
+                    conditions: Some(EndpointConditions {
+                        ready: *ready,
+                        ..Default::default()
+                    }),
+                    target_ref: Some(ObjectReference {
+                        kind: Some("Pod".to_owned()),
+                        name: Some(pod.to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn pod_names(endpoints: Vec<Endpoint>) -> Vec<String> {
+        endpoints.into_iter().map(|e| e.pod_name).collect()
+    }
+
+    #[test]
+    fn readiness_is_respected_unless_ignored() {
+        let slices = [slice(None, 8080, &[("b", Some(false)), ("a", None), ("c", Some(true))])];
+
+        assert_eq!(pod_names(collect_endpoints(&slices, "", false)), ["a", "c"]);
+        assert_eq!(pod_names(collect_endpoints(&slices, "", true)), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn endpoints_are_for_the_named_port_and_deduplicated() {
+        let slices = [
+            slice(Some("http"), 8080, &[("a", None)]),
+            slice(Some("http"), 8080, &[("a", None), ("b", None)]),
+            slice(Some("metrics"), 9090, &[("c", None)]),
+        ];
+
+        assert_eq!(
+            collect_endpoints(&slices, "http", false),
+            [
+                Endpoint { pod_name: "a".to_owned(), port: 8080 },
+                Endpoint { pod_name: "b".to_owned(), port: 8080 },
+            ]
+        );
+    }
+
+    #[test]
+    fn endpoints_without_a_pod_are_skipped() {
+        let mut slice = slice(None, 8080, &[("a", None)]);
+        slice.endpoints[0].target_ref = None;
+
+        assert!(collect_endpoints(&[slice], "", true).is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use kube::Client;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    cli::{CliArgs, ClientKey, Forward},
+    connectivity::Connectivity,
+    pod::{self, PodSelection},
+    target::{self, Target},
+};
+
+/// Printed ahead of the commands, as kubectl can only approximate what kubempf does.
+const APPROXIMATE: &str = "# Approximate equivalents: kubectl port-forward sticks to the one pod picked now for as \
+long as it runs, without kubempf's readiness checks, --select-where conditions or picking a pod for each connection";
+
+/// Resolves every forward to the pod it would currently use and prints the `kubectl
+/// port-forward` command doing the same, for --print-equivalent.
+pub async fn print_equivalent(
+    clients: &HashMap<ClientKey, (Client, Arc<Connectivity>)>,
+    args: &CliArgs,
+) -> anyhow::Result<()> {
+    let mut lines = vec![APPROXIMATE.to_owned()];
+
+    for forward in &args.forwards {
+        let key = args.client_key(forward);
+        let Some((client, connectivity)) = clients.get(&key) else {
+            continue;
+        };
+
+        let forwards = match forward.all_ports() {
+            true => target::forwards_for_ports(client.clone(), forward).await?,
+            false => vec![forward.clone()],
+        };
+        for forward in forwards {
+            let target = Target::new(
+                client.clone(),
+                connectivity.clone(),
+                forward.clone(),
+                args.control.with_options(&forward.options),
+            );
+            let span = info_span!("forward", target = target.name, context = key.context.as_deref());
+
+            lines.push(equivalent(&target, &forward, &key, args).instrument(span).await?);
+        }
+    }
+
+    for route in &args.routes {
+        lines.push(format!("# --route {}: kubectl port-forward can't route by hostname", route.host));
+    }
+    if let Some(socks5) = args.socks5 {
+        lines.push(format!("# --socks5 {socks5}: kubectl port-forward can't proxy to services by hostname"));
+    }
+
+    // Printed together at the end so the commands aren't interleaved with the logs
+    for line in lines {
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+async fn equivalent(target: &Target, forward: &Forward, key: &ClientKey, args: &CliArgs) -> anyhow::Result<String> {
+    let control = args.control.with_options(&forward.options);
+    let resolved = target.resolve().await?;
+
+    let local_port = match forward.local_port {
+        Some(p) => p,
+        None => target::local_port_for(resolved.port, control.port_offset)?,
+    };
+
+    if let Some(path) = &forward.local_path {
+        return Ok(format!(
+            "# {}: listens on the UNIX socket {}, which kubectl port-forward can't do",
+            target.name,
+            path.display()
+        ));
+    }
+    if resolved.cluster_ip.is_some() {
+        return Ok(format!(
+            "# {}: relayed to the service's cluster IP from inside a pod, which kubectl port-forward can't do",
+            target.name
+        ));
+    }
+
+    let (pod_name, pod_port) = match &resolved.endpoints {
+        Some(endpoints) => {
+            let (endpoint, _) = endpoints.next(control.ignore_readiness).await?;
+            (endpoint.pod_name, endpoint.port)
+        }
+        None => {
+            let (pod, _) = pod::find_pod(&resolved.pod_api, &resolved.selector, &PodSelection::from_args(&control)).await?;
+            let port = pod::find_pod_port(&resolved.pod_port, &pod)?;
+            (pod.metadata.name.unwrap_or_default(), port)
+        }
+    };
+
+    Ok(kubectl_command(
+        key,
+        &target.namespace,
+        &pod_name,
+        forward.local_address,
+        local_port,
+        pod_port,
+    ))
+}
+
+/// Builds the `kubectl port-forward` command forwarding `local_port` to `pod_port` on a pod.
+fn kubectl_command(
+    key: &ClientKey,
+    namespace: &str,
+    pod_name: &str,
+    local_address: Option<IpAddr>,
+    local_port: u16,
+    pod_port: u16,
+) -> String {
+    let mut command = "kubectl".to_owned();
+
+    if let Some(kubeconfig) = &key.kubeconfig {
+        command.push_str(&format!(" --kubeconfig {}", kubeconfig.display()));
+    }
+    if let Some(context) = &key.context {
+        command.push_str(&format!(" --context {context}"));
+    }
+    command.push_str(&format!(" --namespace {namespace} port-forward pod/{pod_name}"));
+    // kubectl binds localhost on both IPv4 and IPv6 by default, as kubempf does
+    if let Some(address) = local_address {
+        command.push_str(&format!(" --address {address}"));
+    }
+    command.push_str(&format!(" {local_port}:{pod_port}"));
+
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn command() {
+        assert_eq!(
+            kubectl_command(&ClientKey::default(), "default", "web-5d4f9", None, 8080, 80),
+            "kubectl --namespace default port-forward pod/web-5d4f9 8080:80"
+        );
+        assert_eq!(
+            kubectl_command(
+                &ClientKey {
+                    kubeconfig: Some("/etc/kube/prod.yaml".into()),
+                    context: Some("prod".to_owned()),
+                },
+                "shop",
+                "db-0",
+                Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                5432,
+                5432
+            ),
+            "kubectl --kubeconfig /etc/kube/prod.yaml --context prod --namespace shop port-forward pod/db-0 --address 0.0.0.0 5432:5432"
+        );
+    }
+}
@@ -5,14 +5,113 @@ use thiserror::Error;
 pub enum MyError {
     #[error("unable to parse argument {0}")]
     ArgumentParseError(String),
+    #[error("unknown forward option {0}")]
+    UnknownForwardOption(String),
+    #[error("invalid value {1} for forward option {0}")]
+    InvalidForwardOption(String, String),
+    #[error("invalid --span-target-format {0}: {1}")]
+    InvalidSpanTargetFormat(String, String),
+    #[error("--until-file-removed file {0} does not exist")]
+    UntilFileMissing(String),
+    #[error("not forwarding from {0}, which matches --prod-pattern, as it wasn't confirmed")]
+    ProdNotConfirmed(String),
+    #[error("not forwarding from {0}, which matches --prod-pattern, as --confirm-prod can't ask without a terminal")]
+    ProdConfirmationUnavailable(String),
+    #[error("unable to read namespace file {0}")]
+    NamespaceFileError(String, #[source] std::io::Error),
+    #[error("namespace file {0} is empty")]
+    NamespaceFileEmpty(String),
+    #[error("unable to read client certificate or key {0}")]
+    ClientIdentityFileError(String, #[source] std::io::Error),
+    #[error("{0} does not contain a PEM encoded client certificate or key")]
+    ClientIdentityInvalid(String),
+    #[error("the client certificate and key could not be used, check they are a matching pair")]
+    ClientIdentityMismatch(#[source] kube::Error),
+    #[error("invalid --connect-via {0}, expected a command or ssh://[USER@]HOST[:PORT]")]
+    InvalidConnectVia(String),
     #[error("unable to find named port {0} on service {1}")]
     MissingNamedPort(String, String),
+    #[error("service {0} has more than one port, one of {1} must be specified")]
+    AmbiguousServicePort(String, String),
+    #[error("service {0} does not have any ports")]
+    ServiceHasNoPorts(String),
+    #[error("service port {0} with a port offset of {1} is not a valid local port")]
+    InvalidLocalPort(i32, u16),
+    #[error("not permitted to bind {0}, ports below 1024 need root or `setcap cap_net_bind_service=+ep` on kubempf - or use --fallback-port")]
+    PrivilegedPortDenied(std::net::SocketAddr, #[source] std::io::Error),
+    #[error("no services match the labels {0}")]
+    NoServicesMatchLabels(String),
+    #[error("more than one service matches the labels {0}: {1}")]
+    AmbiguousServiceLabels(String, String),
     #[error("service {0} not found or invalid")]
     ServiceNotFound(String),
     #[error("service {0} not compatiable as it is is missing selectors")]
     ServiceMissingSelectors(String),
-    #[error("no matching ready pods")]
+    #[error("service {0} is not headless, so --headless-endpoints can't be used with it")]
+    ServiceNotHeadless(String),
+    #[error("headless service {0} has no endpoints backed by a pod that can be forwarded to")]
+    NoEndpoints(String),
+    #[error("service {0} does not have a cluster IP to relay to")]
+    ServiceMissingClusterIp(String),
+    #[error("{0} is forwarded to without a service, so --via-cluster-ip, --via-pod and --headless-endpoints, which need one, can't be used with it")]
+    TargetNeedsService(String),
+    #[error("{0} has a selector that can't be used to find its pods: {1}")]
+    InvalidWorkloadSelector(String, String),
+    #[error("UNIX socket {0} is already being listened on")]
+    UnixSocketInUse(String),
+    #[cfg(not(unix))]
+    #[error("can't listen on UNIX socket {0}, UNIX sockets aren't supported on this platform")]
+    UnixSocketUnsupported(String),
+    #[error("the API server was still unreachable after waiting {0}s for it")]
+    ApiServerWaitTimedOut(u64),
+    #[error("the API server is unreachable")]
+    ApiServerUnreachable(),
+    #[error("invalid --inject-header {0}, expected an HTTP header name such as X-Kubempf-Pod")]
+    InvalidHeaderName(String),
+    #[error("--bench needs exactly one forward, not {0}")]
+    BenchForwardCount(usize),
+    #[error("can't log to --log-file {0}: {1}")]
+    InvalidLogFile(String, String),
+    #[error("unable to update hosts file {0}")]
+    HostsFileError(String, #[source] std::io::Error),
+    #[error("unable to reach kubempf on control socket {0}, is it running with --control-socket?")]
+    ControlSocketUnavailable(String, #[source] std::io::Error),
+    #[error("{0}")]
+    ControlRequestFailed(String),
+    #[error("invalid --config {0}: {1}")]
+    InvalidConfig(String, String),
+    #[error("invalid --dial {0}: {1}")]
+    InvalidDial(String, String),
+    #[error("invalid --route {0}: {1}")]
+    InvalidRoute(String, String),
+    #[error("more than one --route for {0}")]
+    DuplicateRoute(String),
+    #[error("invalid CIDR {0}, expected eg. 10.0.0.0/8, fd00::/8 or a single address")]
+    InvalidCidr(String),
+    #[error("invalid --select-where {0}: {1}")]
+    InvalidSelectWhere(String, String),
+    #[error("invalid --ready-label {0}, expected KEY=VALUE")]
+    InvalidReadyLabel(String),
+    #[error("invalid --owner {0}, expected KIND/NAME, eg. replicaset/web-5d4f9")]
+    InvalidOwner(String),
+    #[error("no pods matching the selector are owned by {0}")]
+    NoPodsWithOwner(String),
+    #[error("pods owned by {0} match the selector but none of them are ready")]
+    NoReadyPodsWithOwner(String),
+    #[error("no pods match the --select-where conditions")]
+    NoPodsMatchSelectWhere(),
+    #[error("no pods match the selector")]
+    NoPodsMatchSelector(),
+    #[error("pods match the selector but none of them are ready")]
     MatchingReadyPodNotFound(),
+    #[error("service is targeting port {0} on the pod, which is not a valid port - check the service and pod definitions")]
+    InvalidTargetPort(i32),
+    #[error("not permitted to port-forward to pod {0}, the pods/portforward permission (verb create) is missing")]
+    PortForwardForbidden(String),
+    #[error("not permitted to port-forward to pods in namespace {0}, the pods/portforward permission (verb create) is missing")]
+    PortForwardNotPermitted(String),
+    #[error("port-forward to pod {0} has no stream for port {1}")]
+    PortNotInForwarder(String, u16),
     #[error("service is referencing `{0:#?}` in pod - but this does not exist on the pod")]
     CouldNotFindPort(IntOrString),
 }
@@ -0,0 +1,349 @@
+use std::{
+    io::Write,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::oneshot,
+};
+use tracing::Span;
+
+use crate::{stall::Stalls, status};
+
+/// Version of the event schema, bumped whenever a field is removed or changes meaning.
+pub const SCHEMA_VERSION: u32 = 1;
+
+static WRITER: OnceLock<mpsc::Sender<Output>> = OnceLock::new();
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+enum Output {
+    Line(String),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Starts writing events to stdout, one JSON object per line.
+///
+/// The lines are written by a thread of their own, so a consumer slow to read them holds up
+/// neither the forwards nor the runtime.
+pub fn enable() {
+    WRITER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || write_lines(rx));
+        tx
+    });
+}
+
+/// Waits for the events emitted so far to be written.
+pub async fn flush() {
+    if let Some(writer) = WRITER.get() {
+        let (tx, rx) = oneshot::channel();
+        if writer.send(Output::Flush(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+fn write_lines(rx: mpsc::Receiver<Output>) {
+    let mut stdout = std::io::stdout();
+    for output in rx {
+        match output {
+            // A closed stdout (eg. the consumer exiting) shouldn't take the forwards down with it
+            Output::Line(line) => {
+                let _ = writeln!(stdout, "{line}");
+            }
+            Output::Flush(done) => {
+                let _ = stdout.flush();
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+fn emit(event: &str, fields: Value) {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+
+    let _ = writer.send(Output::Line(line(event, timestamp, fields)));
+}
+
+fn line(event: &str, timestamp: u64, fields: Value) -> String {
+    let mut line = json!({
+        "version": SCHEMA_VERSION,
+        "event": event,
+        "timestamp": timestamp,
+    });
+
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+
+    line.to_string()
+}
+
+/// A forward started listening on `local_addr`, an address and port or `unix:PATH`.
+pub fn bound(target: &str, local_addr: impl std::fmt::Display) {
+    emit(
+        "bound",
+        json!({ "target": target, "local_addr": local_addr.to_string() }),
+    );
+}
+
+/// A forward was refused its privileged `requested_addr`, and bound --fallback-port as
+/// `local_addr` instead.
+pub fn port_fallback(target: &str, requested_addr: impl std::fmt::Display, local_addr: impl std::fmt::Display) {
+    emit(
+        "port_fallback",
+        json!({
+            "target": target,
+            "requested_addr": requested_addr.to_string(),
+            "local_addr": local_addr.to_string(),
+        }),
+    );
+}
+
+/// The events of a single accepted connection, tied together by a process unique id.
+///
+/// Also records `ttfb_ms` (the time from accepting the connection to the first byte sent back
+/// to the client) and `duration_ms` on the current span, which must declare both fields.
+pub struct Connection {
+    id: u64,
+    target: String,
+    up: Arc<AtomicU64>,
+    down: Arc<AtomicU64>,
+    stalls: Arc<Stalls>,
+    timing: Arc<Timing>,
+}
+
+struct Timing {
+    span: Span,
+    opened: Instant,
+    first_byte: OnceLock<u64>,
+}
+
+impl Timing {
+    fn elapsed_ms(&self) -> u64 {
+        self.opened.elapsed().as_millis() as u64
+    }
+}
+
+impl Connection {
+    pub fn opened(target: &str, peer_addr: impl std::fmt::Display) -> Self {
+        let connection = Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            target: target.to_owned(),
+            up: Arc::default(),
+            down: Arc::default(),
+            stalls: Arc::default(),
+            timing: Arc::new(Timing {
+                span: Span::current(),
+                opened: Instant::now(),
+                first_byte: OnceLock::new(),
+            }),
+        };
+
+        status::connection_opened(
+            connection.id,
+            target,
+            peer_addr.to_string(),
+            connection.up.clone(),
+            connection.down.clone(),
+            connection.stalls.clone(),
+        );
+        emit(
+            "connection_opened",
+            json!({
+                "target": connection.target,
+                "connection_id": connection.id,
+                "peer_addr": peer_addr.to_string(),
+            }),
+        );
+
+        connection
+    }
+
+    /// Wraps the client side of the connection to count the bytes reported when it closes.
+    pub fn count<T>(&self, stream: T) -> Counted<T>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Counted {
+            stream,
+            up: self.up.clone(),
+            down: self.down.clone(),
+            timing: self.timing.clone(),
+        }
+    }
+
+    /// What the connection's ends are blocked on, for wrapping them in a
+    /// [`StallGuard`](crate::stall::StallGuard).
+    pub fn stalls(&self) -> &Arc<Stalls> {
+        &self.stalls
+    }
+
+    pub fn pod_selected(&self, pod_name: &str, pod_port: u16) {
+        status::pod_selected(self.id, pod_name, pod_port);
+        emit(
+            "pod_selected",
+            json!({
+                "target": self.target,
+                "connection_id": self.id,
+                "pod_name": pod_name,
+                "pod_port": pod_port,
+            }),
+        );
+    }
+
+    /// The connection finished, cleanly when `error` is `None`.
+    pub fn closed(&self, error: Option<&anyhow::Error>) {
+        let duration_ms = self.timing.elapsed_ms();
+        self.timing.span.record("duration_ms", duration_ms);
+
+        emit(
+            "connection_closed",
+            json!({
+                "target": self.target,
+                "connection_id": self.id,
+                "up": self.up.load(Ordering::Relaxed),
+                "down": self.down.load(Ordering::Relaxed),
+                "reason": match error {
+                    Some(_) => "error",
+                    None => "closed",
+                },
+                "error": error.map(|e| format!("{e:#}")),
+                "ttfb_ms": self.timing.first_byte.get(),
+                "duration_ms": duration_ms,
+            }),
+        );
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        status::connection_closed(self.id);
+    }
+}
+
+/// Counts the bytes read from (`up`) and written to (`down`) a client stream.
+pub struct Counted<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: T,
+    up: Arc<AtomicU64>,
+    down: Arc<AtomicU64>,
+    timing: Arc<Timing>,
+}
+
+impl<T> AsyncRead for Counted<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut_self = self.get_mut();
+        let before = buf.filled().len();
+
+        let result = Pin::new(&mut mut_self.stream).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            mut_self
+                .up
+                .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+impl<T> AsyncWrite for Counted<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let mut_self = self.get_mut();
+
+        let result = Pin::new(&mut mut_self.stream).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = result {
+            mut_self.down.fetch_add(n as u64, Ordering::Relaxed);
+
+            let timing = &mut_self.timing;
+            if n > 0 && timing.first_byte.get().is_none() {
+                let ttfb_ms = *timing.first_byte.get_or_init(|| timing.elapsed_ms());
+                timing.span.record("ttfb_ms", ttfb_ms);
+            }
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn event_line() {
+        let line = line("bound", 1700000000000, json!({ "target": "default/test:80" }));
+
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({
+                "version": 1,
+                "event": "bound",
+                "timestamp": 1700000000000u64,
+                "target": "default/test:80",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_each_direction() {
+        let connection = Connection::opened("default/test:80", "127.0.0.1:5000");
+
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut counted = connection.count(client);
+
+        peer.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        counted.read_exact(&mut buf).await.unwrap();
+        counted.write_all(b"ok").await.unwrap();
+
+        assert_eq!(connection.up.load(Ordering::Relaxed), 7);
+        assert_eq!(connection.down.load(Ordering::Relaxed), 2);
+        assert!(connection.timing.first_byte.get().is_some());
+    }
+}
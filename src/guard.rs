@@ -0,0 +1,91 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use tracing::warn;
+
+use crate::errors::MyError;
+
+/// Warns when a context's name or API server matches a --prod-pattern, and with --confirm-prod
+/// asks for confirmation before going on. Without a terminal to ask on, it fails instead.
+pub fn check_context(
+    patterns: &[String],
+    confirm: bool,
+    context: Option<&str>,
+    server: &str,
+) -> Result<(), MyError> {
+    let Some(pattern) = matched(patterns, context, server) else {
+        return Ok(());
+    };
+    let described = context.unwrap_or(server);
+
+    warn!(
+        context,
+        server,
+        pattern,
+        "!!! context looks like PRODUCTION, forwards will reach production services !!!"
+    );
+
+    if !confirm {
+        return Ok(());
+    }
+
+    // Never wait on a prompt nobody will see
+    if !std::io::stdin().is_terminal() {
+        return Err(MyError::ProdConfirmationUnavailable(described.to_owned()));
+    }
+
+    eprint!("Forward from {described}, which matches --prod-pattern {pattern}? [y/N] ");
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|_| MyError::ProdConfirmationUnavailable(described.to_owned()))?;
+
+    match is_yes(&answer) {
+        true => Ok(()),
+        false => Err(MyError::ProdNotConfirmed(described.to_owned())),
+    }
+}
+
+/// The first pattern found in the context name or server URL, ignoring case.
+fn matched<'a>(patterns: &'a [String], context: Option<&str>, server: &str) -> Option<&'a str> {
+    let context = context.unwrap_or_default().to_lowercase();
+    let server = server.to_lowercase();
+
+    patterns
+        .iter()
+        .find(|p| {
+            let p = p.to_lowercase();
+            context.contains(&p) || server.contains(&p)
+        })
+        .map(String::as_str)
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_context_or_server_ignoring_case() {
+        let patterns = ["prod".to_owned(), ".live.".to_owned()];
+
+        assert_eq!(matched(&patterns, Some("eu-PROD-1"), "https://10.0.0.1"), Some("prod"));
+        assert_eq!(matched(&patterns, Some("eu-1"), "https://api.live.example.com"), Some(".live."));
+        assert_eq!(matched(&patterns, None, "https://api.prod.example.com"), Some("prod"));
+        assert_eq!(matched(&patterns, Some("staging"), "https://api.staging.example.com"), None);
+        assert_eq!(matched(&[], Some("prod"), "https://prod"), None);
+    }
+
+    #[test]
+    fn only_yes_confirms() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" Yes \n"));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no\n"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use kube::Client;
+use tokio::task::JoinHandle;
+
+use crate::{
+    cli::{ControlArgs, Forward},
+    connectivity::Connectivity,
+    Lifecycle,
+};
+
+/// How a [`ForwardSpec`] forwards, as kubempf's options of the same name would. Those not
+/// here can be given as the forward's own options, eg. `postgres:5432?direction=up`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Options {
+    /// Don't check the readiness of the pod when selecting which pod to forward to
+    pub ignore_readiness: bool,
+    /// Only forward to pods that are Running, Ready, have an IP and aren't being deleted
+    pub strict_ready: bool,
+    /// Close the connection when the pod goes unready
+    pub close_on_unready: bool,
+    /// With `close_on_unready`, how long to spend closing each side of a connection cleanly
+    pub graceful_close: Option<Duration>,
+    /// How long the pod side of a connection can be idle before its closing is hidden from the
+    /// client, and the port-forward reopened on its next write
+    pub reconnect_idle: Option<Duration>,
+    /// How long the client or pod can stop accepting data before the connection is closed
+    pub stall_timeout: Option<Duration>,
+    /// Keep sending each client address's connections to the same pod while it stays eligible
+    pub sticky: bool,
+    /// Choose the pod to connect to randomly instead of the first in the list
+    pub randomise: bool,
+    /// How many port-forward streams to keep open ahead of connections needing them
+    pub prewarm: usize,
+    /// Defer looking up the service until the first connection, and release it again once idle
+    pub lazy: bool,
+    /// Wait for the service to be created rather than failing when it doesn't exist yet
+    pub wait_for_service: bool,
+    /// Relay connections to the service's cluster IP from inside a ready pod, instead of
+    /// forwarding to the pod itself
+    pub via_cluster_ip: bool,
+    /// After binding, connect to each listener to check it is reachable, warning if not
+    pub verify_bind: bool,
+    /// The port to bind when binding a privileged local port is refused, 0 for any free port
+    pub fallback_port: Option<u16>,
+}
+
+impl Options {
+    /// The options as they'd be given to kubempf, for clap to check and fill in the rest of.
+    fn to_args(&self) -> Vec<String> {
+        let flags = [
+            ("--ignore-readiness", self.ignore_readiness),
+            ("--strict-ready", self.strict_ready),
+            ("--close-on-unready", self.close_on_unready),
+            ("--sticky", self.sticky),
+            ("--randomise", self.randomise),
+            ("--lazy", self.lazy),
+            ("--wait-for-service", self.wait_for_service),
+            ("--via-cluster-ip", self.via_cluster_ip),
+            ("--verify-bind", self.verify_bind),
+        ];
+        let values = [
+            ("--graceful-close", self.graceful_close.map(secs)),
+            ("--reconnect-idle", self.reconnect_idle.map(secs)),
+            ("--stall-timeout", self.stall_timeout.map(secs)),
+            ("--prewarm", (self.prewarm > 0).then(|| self.prewarm.to_string())),
+            ("--fallback-port", self.fallback_port.map(|p| p.to_string())),
+        ];
+
+        let flags = flags.into_iter().filter(|(_, set)| *set).map(|(flag, _)| flag.to_owned());
+        let values = values.into_iter().flat_map(|(option, value)| value.map(|v| [option.to_owned(), v]));
+
+        flags.chain(values.flatten()).collect()
+    }
+}
+
+/// The options take whole seconds, so any part of a second is rounded up.
+fn secs(duration: Duration) -> String {
+    (duration.as_secs() + u64::from(duration.subsec_nanos() > 0)).to_string()
+}
+
+/// What to forward and how.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ForwardSpec {
+    forward: Forward,
+    options: ControlArgs,
+}
+
+/// Just the options that apply to each forward, for [`ForwardSpec::parse`].
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct Defaults {
+    #[command(flatten)]
+    control: ControlArgs,
+}
+
+impl ForwardSpec {
+    /// Parses a forward as given to kubempf, eg. `data/postgres:5432` or
+    /// `15432:postgres:5432?ignore-readiness`, to forward with `options`. Unlike kubempf, the
+    /// options aren't taken from its environment variables.
+    pub fn parse(forward: &str, options: Options) -> anyhow::Result<Self> {
+        let forward = Forward::parse(forward)?;
+        let matches = Defaults::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(options.to_args())?;
+        let options = Defaults::from_arg_matches(&matches)?.control;
+
+        let merged = options.with_options(&forward.options);
+        if let Some((option, other)) = merged.conflict() {
+            anyhow::bail!("{option} cannot be used with {other}, as combined by the forward's options");
+        }
+        if let Some(option) = merged.needs_close_on_unready() {
+            anyhow::bail!("{option} requires --close-on-unready, or ?close-on-unready");
+        }
+
+        Ok(Self { forward, options })
+    }
+}
+
+/// A running forward, started without the command line, eg. from another tool. The forward
+/// stops when the handle is dropped.
+///
+/// Each handle is stopped and paused on its own, leaving other forwards in the process alone,
+/// including kubempf's own as stopped by Ctrl-C and paused by SIGUSR1.
+pub struct ForwardHandle {
+    handle: Option<JoinHandle<anyhow::Result<()>>>,
+    lifecycle: Lifecycle,
+}
+
+impl ForwardHandle {
+    /// Binds the forward's listeners and starts accepting connections on them, forwarding
+    /// through `client`. Without `lazy`, the service is looked up first, failing if it can't
+    /// be.
+    pub async fn start(client: Client, spec: ForwardSpec) -> anyhow::Result<Self> {
+        let connectivity = Connectivity::new(client.clone(), None);
+        let options = spec.options.with_options(&spec.forward.options);
+        let lifecycle = Lifecycle::default();
+        let handle = crate::create_forward(client, connectivity, &spec.forward, options, lifecycle.clone()).await?;
+
+        Ok(Self {
+            handle: Some(handle),
+            lifecycle,
+        })
+    }
+
+    /// Stops accepting connections. Connections already forwarding carry on until they close.
+    pub fn stop(&self) {
+        self.lifecycle.shutdown.trigger();
+    }
+
+    /// Closes new connections as they are accepted until resumed, while connections already
+    /// forwarding carry on.
+    pub fn pause(&self) {
+        self.lifecycle.pause.set(true);
+    }
+
+    /// Accepts new connections again after [`ForwardHandle::pause`].
+    pub fn resume(&self) {
+        self.lifecycle.pause.set(false);
+    }
+
+    /// Waits for the forward to stop, returning the error that stopped it, if any.
+    pub async fn wait(mut self) -> anyhow::Result<()> {
+        let Some(handle) = self.handle.take() else {
+            return Ok(());
+        };
+
+        match handle.await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for ForwardHandle {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.handle {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_specs() {
+        let options = Options {
+            lazy: true,
+            stall_timeout: Some(Duration::from_millis(1500)),
+            ..Options::default()
+        };
+        let spec = ForwardSpec::parse("15432:data/postgres:5432?ignore-readiness", options).unwrap();
+
+        assert_eq!(spec.forward, Forward::parse("15432:data/postgres:5432?ignore-readiness").unwrap());
+        assert!(spec.options.lazy);
+        assert_eq!(spec.options.stall_timeout, Some(2));
+        assert!(!spec.options.ignore_readiness);
+    }
+
+    #[test]
+    fn rejects_conflicting_options() {
+        let clap_conflict = Options {
+            ignore_readiness: true,
+            strict_ready: true,
+            ..Options::default()
+        };
+        let sticky = Options {
+            sticky: true,
+            ..Options::default()
+        };
+
+        assert!(ForwardSpec::parse("postgres:5432", clap_conflict).is_err());
+        assert!(ForwardSpec::parse("postgres:5432?randomise", sticky.clone()).is_err());
+        assert!(ForwardSpec::parse("postgres:5432?randomise=false", sticky).is_ok());
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use tokio::{
+    process::Command,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, warn, Instrument};
+
+/// Hooks allowed to run at once, further events waiting until one finishes
+const MAX_RUNNING: usize = 4;
+/// How long a hook may run before it is killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+static HOOK: OnceLock<Hook> = OnceLock::new();
+
+struct Hook {
+    command: String,
+    running: Arc<Semaphore>,
+    /// Events waiting for a running hook to finish, in the order they first arrived, with at
+    /// most one for each target
+    pending: Mutex<Vec<Pending>>,
+}
+
+/// An event waiting to run the hook.
+struct Pending {
+    event: Event,
+    target: String,
+    ready_pods: Option<usize>,
+}
+
+impl Pending {
+    /// Whether `other` is about the same thing, and so supersedes this event.
+    fn is_superseded_by(&self, other: &Pending) -> bool {
+        self.target == other.target && self.event.subject() == other.event.subject()
+    }
+}
+
+/// A change in a forward's state that --on-event runs its command for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// The last ready pod of a forward went away
+    PodsUnavailable,
+    /// A forward without ready pods has one again
+    PodsAvailable,
+    /// A context's API server stopped responding
+    ApiUnreachable,
+    /// A context's API server is responding again
+    ApiReachable,
+    /// A forward was stopped and started again, as it changed in --config
+    ForwardRestarted,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::PodsUnavailable => "pods-unavailable",
+            Event::PodsAvailable => "pods-available",
+            Event::ApiUnreachable => "api-unreachable",
+            Event::ApiReachable => "api-reachable",
+            Event::ForwardRestarted => "forward-restarted",
+        }
+    }
+
+    /// What the event is about, a forward's pods, an API server or a forward itself, as events
+    /// about different things never supersede each other.
+    fn subject(&self) -> &'static str {
+        match self {
+            Event::PodsUnavailable | Event::PodsAvailable => "pods",
+            Event::ApiUnreachable | Event::ApiReachable => "api",
+            Event::ForwardRestarted => "forward",
+        }
+    }
+}
+
+/// Starts running `command` on each event.
+pub fn enable(command: &str) {
+    let _ = HOOK.set(Hook {
+        command: command.to_owned(),
+        running: Arc::new(Semaphore::new(MAX_RUNNING)),
+        pending: Mutex::new(Vec::new()),
+    });
+}
+
+pub fn enabled() -> bool {
+    HOOK.get().is_some()
+}
+
+/// Runs the hook for `event` in the background, if enabled. `target` is the forward's target,
+/// or the context for API server events, and `ready_pods` is only given for pod events.
+///
+/// When too many hooks are already running the event waits for one to finish, replacing any
+/// event still waiting for the same target, so a slow hook can't build a backlog and is only
+/// told the latest state of each target.
+pub fn fire(event: Event, target: &str, ready_pods: Option<usize>) {
+    let Some(hook) = HOOK.get() else {
+        return;
+    };
+
+    let fired = Pending {
+        event,
+        target: target.to_owned(),
+        ready_pods,
+    };
+    queue(&mut hook.pending.lock().unwrap(), fired);
+
+    hook.run_pending();
+}
+
+/// Adds `fired` to the waiting events, in place of any it supersedes.
+fn queue(pending: &mut Vec<Pending>, fired: Pending) {
+    match pending.iter_mut().find(|p| p.is_superseded_by(&fired)) {
+        Some(superseded) => {
+            debug!(event = superseded.event.as_str(), "--on-event hook superseded while waiting to run");
+            *superseded = fired;
+        }
+        None => pending.push(fired),
+    }
+}
+
+impl Hook {
+    /// Starts hooks for the waiting events, for as long as fewer than [`MAX_RUNNING`] are running.
+    fn run_pending(&'static self) {
+        while !self.pending.lock().unwrap().is_empty() {
+            let Ok(permit) = self.running.clone().try_acquire_owned() else {
+                debug!("--on-event hooks are still running, event waiting for one to finish");
+                return;
+            };
+            let next = {
+                let mut pending = self.pending.lock().unwrap();
+                (!pending.is_empty()).then(|| pending.remove(0))
+            };
+            let Some(Pending { event, target, ready_pods }) = next else {
+                return;
+            };
+
+            self.run(permit, event, &target, ready_pods);
+        }
+    }
+
+    fn run(&'static self, permit: OwnedSemaphorePermit, event: Event, target: &str, ready_pods: Option<usize>) {
+        let (args, env) = command(&self.command, event, target, ready_pods);
+
+        tokio::spawn(
+            async move {
+                run_command(args, env, event).await;
+                drop(permit);
+                self.run_pending();
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Runs the hook's command, killing it once it has run for [`HOOK_TIMEOUT`].
+async fn run_command(args: Vec<String>, env: Vec<(&'static str, String)>, event: Event) {
+    let Some((program, args)) = args.split_first() else {
+        return;
+    };
+    let child = Command::new(program)
+        .args(args)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(error = &e as &dyn std::error::Error, "failed to run --on-event hook");
+            return;
+        }
+    };
+
+    match tokio::time::timeout(HOOK_TIMEOUT, child.wait()).await {
+        Ok(Ok(status)) if status.success() => debug!(event = event.as_str(), "ran --on-event hook"),
+        Ok(Ok(status)) => warn!(status = status.to_string(), "--on-event hook failed"),
+        Ok(Err(e)) => warn!(error = &e as &dyn std::error::Error, "failed to run --on-event hook"),
+        Err(_) => warn!("--on-event hook timed out, killing it"),
+    }
+}
+
+/// Builds the hook's arguments, the command followed by `EVENT TARGET READY_PODS`, and the
+/// environment variables describing the event.
+fn command(
+    template: &str,
+    event: Event,
+    target: &str,
+    ready_pods: Option<usize>,
+) -> (Vec<String>, Vec<(&'static str, String)>) {
+    let ready_pods = ready_pods.map(|n| n.to_string()).unwrap_or_default();
+
+    let args = template
+        .split_whitespace()
+        .map(str::to_owned)
+        .chain([event.as_str().to_owned(), target.to_owned(), ready_pods.clone()])
+        .collect();
+    let env = vec![
+        ("KUBEMPF_EVENT", event.as_str().to_owned()),
+        ("KUBEMPF_TARGET", target.to_owned()),
+        ("KUBEMPF_READY_PODS", ready_pods),
+    ];
+
+    (args, env)
+}
+
+/// Tracks whether a forward has any ready pods, to notice when that changes.
+#[derive(Default)]
+pub struct Availability {
+    available: Option<bool>,
+}
+
+impl Availability {
+    /// Notes the current number of ready pods, returning the event when pods have become
+    /// available or unavailable. The first count is only noted.
+    pub fn update(&mut self, ready_pods: usize) -> Option<Event> {
+        let available = ready_pods > 0;
+        let previous = self.available.replace(available);
+
+        match (previous, available) {
+            (Some(false), true) => Some(Event::PodsAvailable),
+            (Some(true), false) => Some(Event::PodsUnavailable),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_arguments_and_environment() {
+        let (args, env) = command("notify-send kubempf", Event::PodsUnavailable, "default/web:80", Some(0));
+
+        assert_eq!(args, ["notify-send", "kubempf", "pods-unavailable", "default/web:80", "0"]);
+        assert_eq!(
+            env,
+            [
+                ("KUBEMPF_EVENT", "pods-unavailable".to_owned()),
+                ("KUBEMPF_TARGET", "default/web:80".to_owned()),
+                ("KUBEMPF_READY_PODS", "0".to_owned()),
+            ]
+        );
+
+        let (args, _) = command("./hook.sh", Event::ApiReachable, "staging", None);
+        assert_eq!(args, ["./hook.sh", "api-reachable", "staging", ""]);
+    }
+
+    #[test]
+    fn waiting_events_are_superseded_per_target() {
+        let fired = |event, target: &str| Pending {
+            event,
+            target: target.to_owned(),
+            ready_pods: None,
+        };
+        let mut pending = vec![];
+
+        queue(&mut pending, fired(Event::PodsUnavailable, "default/web:80"));
+        queue(&mut pending, fired(Event::PodsUnavailable, "default/api:80"));
+        queue(&mut pending, fired(Event::PodsAvailable, "default/web:80"));
+        // API server events are kept apart from pod events, whatever the names
+        queue(&mut pending, fired(Event::ApiUnreachable, "default/web:80"));
+        queue(&mut pending, fired(Event::ForwardRestarted, "default/web:80"));
+
+        let waiting: Vec<_> = pending.iter().map(|p| (p.event, p.target.as_str())).collect();
+        assert_eq!(
+            waiting,
+            [
+                (Event::PodsAvailable, "default/web:80"),
+                (Event::PodsUnavailable, "default/api:80"),
+                (Event::ApiUnreachable, "default/web:80"),
+                (Event::ForwardRestarted, "default/web:80"),
+            ]
+        );
+    }
+
+    #[test]
+    fn availability_transitions() {
+        let mut availability = Availability::default();
+
+        assert_eq!(availability.update(2), None);
+        assert_eq!(availability.update(1), None);
+        assert_eq!(availability.update(0), Some(Event::PodsUnavailable));
+        assert_eq!(availability.update(0), None);
+        assert_eq!(availability.update(3), Some(Event::PodsAvailable));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    cli::{Forward, TargetKind},
+    errors::MyError,
+};
+
+/// Starts the block of entries written by a kubempf, followed by its process id
+const BEGIN: &str = "# BEGIN kubempf";
+/// Ends the block of entries written by a kubempf, followed by its process id
+const END: &str = "# END kubempf";
+
+/// The hostnames a service is known by inside the cluster, bar the bare service name, which
+/// would be too easily mistaken for a local one.
+fn hostnames(service: &str, namespace: &str) -> [String; 3] {
+    [
+        format!("{service}.{namespace}"),
+        format!("{service}.{namespace}.svc"),
+        format!("{service}.{namespace}.svc.cluster.local"),
+    ]
+}
+
+/// The address to reach each service forward on, along with its hostnames, given the
+/// namespace each forward is in. Forwards bound to every address are reached on loopback.
+pub fn entries<'a>(forwards: impl IntoIterator<Item = (&'a Forward, String)>) -> Vec<(IpAddr, Vec<String>)> {
+    let mut entries: Vec<(IpAddr, Vec<String>)> = vec![];
+
+    for (forward, namespace) in forwards {
+        if forward.kind != TargetKind::Service || forward.local_path.is_some() {
+            continue;
+        }
+        let address = match forward.local_address {
+            None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Some(IpAddr::V4(a)) if a.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Some(IpAddr::V6(a)) if a.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            Some(a) => a,
+        };
+        let names = hostnames(&forward.service_name, &namespace);
+
+        // A service forwarded more than once is reached on the address it was first bound to
+        if entries.iter().any(|(_, n)| n.contains(&names[0])) {
+            continue;
+        }
+        match entries.iter_mut().find(|(a, _)| *a == address) {
+            Some((_, n)) => n.extend(names),
+            None => entries.push((address, names.to_vec())),
+        }
+    }
+
+    entries
+}
+
+/// The hosts file with the blocks written by kubempfs that are no longer running, or by this
+/// one, taken out.
+fn without_blocks(contents: &str, is_running: impl Fn(u32) -> bool) -> String {
+    let own = std::process::id();
+    let mut kept = String::with_capacity(contents.len());
+    let mut removing = false;
+
+    for line in contents.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if let Some(pid) = trimmed.strip_prefix(BEGIN).and_then(|p| p.trim().parse::<u32>().ok()) {
+            removing = pid == own || !is_running(pid);
+        }
+        if !removing {
+            kept.push_str(line);
+        }
+        if trimmed.starts_with(END) {
+            removing = false;
+        }
+    }
+
+    kept
+}
+
+fn block(entries: &[(IpAddr, Vec<String>)]) -> String {
+    let pid = std::process::id();
+    let mut block = format!("{BEGIN} {pid}\n");
+    for (address, names) in entries {
+        block.push_str(&format!("{address} {}\n", names.join(" ")));
+    }
+    block.push_str(&format!("{END} {pid}\n"));
+
+    block
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    // Without a way to tell, blocks are only removed by the kubempf that wrote them
+    true
+}
+
+fn update(path: &Path, entries: &[(IpAddr, Vec<String>)]) -> Result<(), MyError> {
+    let error = |e| MyError::HostsFileError(path.display().to_string(), e);
+
+    let contents = std::fs::read_to_string(path).map_err(error)?;
+    let mut updated = without_blocks(&contents, is_running);
+    if !entries.is_empty() {
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        updated.push_str(&block(entries));
+    }
+
+    // Written in place rather than replaced, as the file may be a mount, eg. in containers
+    if updated != contents {
+        std::fs::write(path, updated).map_err(error)?;
+    }
+
+    Ok(())
+}
+
+/// The entries written to the hosts file for --write-hosts, removed again when dropped.
+pub struct HostsEntries {
+    path: PathBuf,
+}
+
+impl Drop for HostsEntries {
+    fn drop(&mut self) {
+        match update(&self.path, &[]) {
+            Ok(()) => info!(hosts_file = %self.path.display(), "removed hosts file entries"),
+            Err(e) => warn!(error = &e as &dyn std::error::Error, "failed to remove hosts file entries"),
+        }
+    }
+}
+
+/// Adds the entries to the hosts file, in a block of their own, first removing any left behind
+/// by kubempfs that didn't get to remove their own.
+pub fn write(path: &Path, entries: &[(IpAddr, Vec<String>)]) -> Result<HostsEntries, MyError> {
+    update(path, entries)?;
+    info!(hosts_file = %path.display(), "wrote hosts file entries");
+
+    Ok(HostsEntries { path: path.to_owned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_entries() {
+        let forwards: Vec<_> = [
+            "data/postgres:5432",
+            "web:80",
+            "web:443",
+            "192.0.2.31:8080:api:80",
+            "pod/web-0:80",
+        ]
+        .into_iter()
+        .map(|f| Forward::parse(f).unwrap())
+        .collect();
+
+        let entries = entries(forwards.iter().map(|f| (f, f.namespace.clone().unwrap_or("default".to_owned()))));
+
+        assert_eq!(
+            entries,
+            [
+                (
+                    IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    [hostnames("postgres", "data"), hostnames("web", "default")].concat()
+                ),
+                ("192.0.2.31".parse().unwrap(), hostnames("api", "default").to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_and_own_blocks_are_removed() {
+        let own = std::process::id();
+        let contents = format!(
+            "127.0.0.1 localhost\n\
+             {BEGIN} 1\n127.0.0.1 a.default\n{END} 1\n\
+             {BEGIN} 2\n127.0.0.1 b.default\n{END} 2\n\
+             {BEGIN} {own}\n127.0.0.1 c.default\n{END} {own}\n\
+             ::1 localhost\n"
+        );
+
+        let kept = without_blocks(&contents, |pid| pid == 2);
+
+        assert_eq!(
+            kept,
+            format!("127.0.0.1 localhost\n{BEGIN} 2\n127.0.0.1 b.default\n{END} 2\n::1 localhost\n")
+        );
+    }
+
+    #[test]
+    fn entries_are_written_and_removed() {
+        let path = std::env::temp_dir().join(format!("kubempf-{}-hosts", std::process::id()));
+        std::fs::write(&path, "127.0.0.1 localhost").unwrap();
+        let entries = [(IpAddr::V4(Ipv4Addr::LOCALHOST), hostnames("web", "default").to_vec())];
+
+        let written = write(&path, &entries).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, format!("127.0.0.1 localhost\n{}", block(&entries)));
+        assert!(contents.contains("127.0.0.1 web.default web.default.svc web.default.svc.cluster.local\n"));
+
+        drop(written);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "127.0.0.1 localhost\n");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::{debug, warn};
+
+use crate::errors::MyError;
+
+/// Request heads longer than this are passed on without the header
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Checks an --inject-header name is a valid HTTP header name, keeping its case.
+pub fn parse_header_name(name: &str) -> Result<String, MyError> {
+    match http::HeaderName::from_bytes(name.as_bytes()) {
+        Ok(_) => Ok(name.to_owned()),
+        Err(_) => Err(MyError::InvalidHeaderName(name.to_owned())),
+    }
+}
+
+enum State {
+    /// Collecting the first request's head from the client
+    Buffering(Vec<u8>),
+    /// Handing on the head, with the header added or not, from the offset
+    Draining(Vec<u8>, usize),
+    Passthrough,
+}
+
+/// Adds a `NAME: VALUE` header to the first HTTP/1.x request read from the client, for
+/// --inject-header. Anything that isn't an HTTP/1.x request is passed on unchanged, and later
+/// requests on the same connection are never touched. Writes to the client are passed
+/// straight through.
+pub struct InjectHeader<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: T,
+    header: Vec<u8>,
+    state: State,
+}
+
+impl<T> InjectHeader<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Only injects when `name` is given.
+    pub fn new(stream: T, name: Option<&str>, value: &str) -> Self {
+        let (header, state) = match name {
+            Some(name) => (format!("{name}: {value}\r\n").into_bytes(), State::Buffering(vec![])),
+            None => (vec![], State::Passthrough),
+        };
+
+        Self { stream, header, state }
+    }
+}
+
+enum Head {
+    /// More is needed to tell
+    Incomplete,
+    /// The head is complete, and the header goes at this offset
+    Request(usize),
+    NotHttp,
+}
+
+/// Looks for a complete HTTP/1.x request head at the start of `bytes`, giving up as soon as
+/// the bytes can't be one.
+fn parse_head(bytes: &[u8]) -> Head {
+    let method_len = bytes.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    match bytes.get(method_len) {
+        None => return Head::Incomplete,
+        Some(b' ') if method_len > 0 => {}
+        _ => return Head::NotHttp,
+    }
+
+    let Some(line_end) = bytes.windows(2).position(|w| w == b"\r\n") else {
+        return match bytes.len() > MAX_HEAD {
+            true => Head::NotHttp,
+            false => Head::Incomplete,
+        };
+    };
+    let is_http1 = bytes[..line_end]
+        .rsplit(|b| *b == b' ')
+        .next()
+        .is_some_and(|v| v.starts_with(b"HTTP/1."));
+    if !is_http1 {
+        return Head::NotHttp;
+    }
+
+    match bytes.windows(4).any(|w| w == b"\r\n\r\n") {
+        true => Head::Request(line_end + 2),
+        false if bytes.len() > MAX_HEAD => Head::NotHttp,
+        false => Head::Incomplete,
+    }
+}
+
+impl<T> AsyncRead for InjectHeader<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut_self = self.get_mut();
+
+        loop {
+            match &mut mut_self.state {
+                State::Passthrough => return Pin::new(&mut mut_self.stream).poll_read(cx, buf),
+                State::Draining(head, offset) => {
+                    let n = buf.remaining().min(head.len() - *offset);
+                    buf.put_slice(&head[*offset..*offset + n]);
+                    *offset += n;
+                    if *offset == head.len() {
+                        mut_self.state = State::Passthrough;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                State::Buffering(head) => {
+                    let mut chunk = [0u8; 4096];
+                    let mut read = ReadBuf::new(&mut chunk);
+                    match Pin::new(&mut mut_self.stream).poll_read(cx, &mut read) {
+                        Poll::Ready(Ok(())) => {}
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                    let closed = read.filled().is_empty();
+                    head.extend_from_slice(read.filled());
+
+                    let mut head = std::mem::take(head);
+                    match parse_head(&head) {
+                        Head::Request(at) => {
+                            head.splice(at..at, mut_self.header.iter().copied());
+                            debug!("injected --inject-header into the request");
+                        }
+                        Head::NotHttp => {
+                            warn!("connection doesn't start with an HTTP/1.x request, not injecting --inject-header")
+                        }
+                        Head::Incomplete if closed => {}
+                        Head::Incomplete => {
+                            mut_self.state = State::Buffering(head);
+                            continue;
+                        }
+                    }
+
+                    mut_self.state = match head.is_empty() {
+                        true => State::Passthrough,
+                        false => State::Draining(head, 0),
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl<T> AsyncWrite for InjectHeader<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Sends `sent` through an injecting stream, which reads at most `chunk` bytes at a time,
+    /// returning what is read.
+    async fn inject(sent: &[u8], chunk: usize) -> Vec<u8> {
+        let (stream, mut client) = tokio::io::duplex(chunk);
+        let mut injecting = InjectHeader::new(stream, Some("X-Kubempf-Pod"), "web-5d4f9");
+
+        let sent = sent.to_vec();
+        tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        let mut received = vec![];
+        injecting.read_to_end(&mut received).await.unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn injects_into_first_request() {
+        let request = b"GET / HTTP/1.1\r\nHost: web\r\n\r\nGET /next HTTP/1.1\r\nHost: web\r\n\r\n";
+        let expected = b"GET / HTTP/1.1\r\nX-Kubempf-Pod: web-5d4f9\r\nHost: web\r\n\r\nGET /next HTTP/1.1\r\nHost: web\r\n\r\n";
+
+        assert_eq!(inject(request, request.len()).await, expected);
+        // Split over several reads
+        assert_eq!(inject(request, 3).await, expected);
+    }
+
+    #[tokio::test]
+    async fn passes_other_protocols_unchanged() {
+        for sent in [
+            &b"\x16\x03\x01\x02\x00\x01\x00\x01"[..],
+            b"SSH-2.0-OpenSSH_9.6\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET / HTTP/1.1\r\n",
+            b"",
+        ] {
+            assert_eq!(inject(sent, sent.len().max(1)).await, sent);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_head_is_passed_unchanged() {
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        request.extend(std::iter::repeat_n(b'a', MAX_HEAD * 2));
+        request.extend(b"\r\n\r\n");
+
+        assert_eq!(inject(&request, 4096).await, request);
+    }
+
+    #[tokio::test]
+    async fn disabled_passes_through() {
+        let (stream, mut client) = tokio::io::duplex(1024);
+        let mut stream = InjectHeader::new(stream, None, "web-5d4f9");
+
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn header_names() {
+        assert_eq!(parse_header_name("X-Kubempf-Pod").unwrap(), "X-Kubempf-Pod");
+        assert!(parse_header_name("X Kubempf").is_err());
+        assert!(parse_header_name("").is_err());
+    }
+}
@@ -0,0 +1,1076 @@
+//! The forwarding engine behind the kubempf binary, for embedding it in other tools. See
+//! [`ForwardHandle`] to start forwards without the command line.
+
+mod accept;
+mod access;
+mod alias;
+mod bench;
+mod bind;
+mod cancelable_stream;
+mod capture;
+mod config;
+mod connectivity;
+mod connector;
+mod control;
+mod dial;
+mod direction;
+mod dns;
+mod endpoints;
+mod equivalent;
+mod events;
+mod guard;
+mod handle;
+mod hook;
+mod hosts;
+mod inject;
+mod listener;
+mod log_file;
+mod pause;
+pub mod cli;
+pub mod errors;
+mod pod;
+mod pod_cache;
+mod prewarm;
+mod relay;
+mod reload;
+mod rotate;
+mod route;
+mod select;
+mod shutdown;
+mod socks;
+mod stall;
+mod status;
+mod target;
+
+pub use handle::{ForwardHandle, ForwardSpec, Options};
+
+use crate::{
+    access::AccessRules,
+    capture::{Capture, CaptureReadWrite},
+    cli::{CliArgs, ClientKey, Forward},
+    connectivity::Connectivity,
+    errors::MyError,
+    listener::{Listener, Peer},
+    pause::Pause,
+    shutdown::Shutdown,
+    target::Target,
+};
+use cli::ControlArgs;
+use futures::{future::join_all, StreamExt, TryStreamExt};
+use kube::{config::Kubeconfig, Api, Client, Config};
+use std::{collections::HashMap, io::IsTerminal, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{
+    fmt::writer::{BoxMakeWriter, MakeWriterExt},
+    EnvFilter,
+};
+use tracing::*;
+
+/// How long a connection to the route port has to name its host
+const ROUTE_SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection to the --socks5 port has to make its request
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Logs to stdout, or to stderr with --events-json, and to any --log-file. The guard returned
+/// must be held until exiting, so queued logs are written out first.
+///
+/// What is logged follows RUST_LOG when it is set, and otherwise --quiet and --verbose.
+pub fn init_logging(args: &CliArgs) -> anyhow::Result<Option<WorkerGuard>> {
+    let format = tracing_subscriber::fmt::format()
+        .without_time()
+        .with_level(false)
+        .with_target(false);
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_directives(args)));
+
+    // Events get stdout to themselves, so logs move out of their way
+    let (writer, is_terminal) = match args.events_json {
+        true => (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal()),
+        false => (BoxMakeWriter::new(std::io::stdout), std::io::stdout().is_terminal()),
+    };
+    let mut log_file_guard = None;
+    let (writer, is_terminal) = match &args.log_file {
+        Some(path) => {
+            let (file, guard) = log_file::writer(path, args.log_rotation, args.log_max_bytes)?;
+            log_file_guard = Some(guard);
+
+            match args.log_console {
+                true => (BoxMakeWriter::new(file.and(writer)), false),
+                false => (BoxMakeWriter::new(file), false),
+            }
+        }
+        None => (writer, is_terminal),
+    };
+    let ansi = args
+        .color
+        .use_ansi(std::env::var("NO_COLOR").ok().as_deref(), is_terminal);
+
+    if args.compact {
+        tracing_subscriber::fmt()
+            .event_format(format.compact())
+            .with_env_filter(filter)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .init();
+    } else {
+        tracing_subscriber::fmt()
+            .event_format(format.pretty().with_source_location(false))
+            .with_env_filter(filter)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .init();
+    }
+
+    Ok(log_file_guard)
+}
+
+/// The log filter for --quiet and --verbose, which only turns on kubempf's own debug logs.
+fn log_directives(args: &CliArgs) -> &'static str {
+    match (args.quiet, args.verbose) {
+        (true, _) => "warn",
+        (false, true) => "info,kubempf=debug,kubempf_core=debug",
+        (false, false) => "info",
+    }
+}
+
+/// Runs kubempf as the arguments ask, until shutting down.
+pub async fn run(args: CliArgs) -> anyhow::Result<()> {
+    if let Some(command) = &args.command {
+        return control::run(command).await;
+    }
+
+    tokio::spawn(shutdown::on_ctrl_c());
+
+    if args.events_json {
+        events::enable();
+    }
+    if args.status_addr.is_some() {
+        status::enable();
+    }
+    if let Some(command) = &args.on_event {
+        hook::enable(command);
+    }
+    if let Some(seed) = args.seed {
+        pod::seed(seed);
+    }
+
+    if let Some(path) = &args.config {
+        info!(config = %path.display(), "read forwards and options from config");
+    }
+
+    args.check_client_identity()?;
+
+    // Forwards without a kubeconfig or context of their own share the client for the global --context
+    let mut clients: HashMap<ClientKey, (Client, Arc<Connectivity>)> = HashMap::new();
+    let mut client_error = None;
+    for key in args
+        .forwards
+        .iter()
+        .chain(args.routes.iter().map(|r| &r.forward))
+        .chain(args.dials.iter().map(|d| &d.forward))
+        .map(|f| args.client_key(f))
+        .chain((args.socks5.is_some() || args.dns.is_some() || args.control_socket.is_some()).then(|| args.default_client_key()))
+    {
+        if clients.contains_key(&key) {
+            continue;
+        }
+
+        match create_client(&args, &key).await {
+            Ok(client) => {
+                let connectivity = Connectivity::new(client.clone(), key.context.clone());
+                clients.insert(key, (client, connectivity));
+            }
+            Err(e) => {
+                error!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    context = key.context,
+                    kubeconfig = key.kubeconfig.as_ref().map(|k| k.display().to_string()),
+                    "failed to create client, skipping its forwards"
+                );
+                client_error = Some(e);
+            }
+        }
+    }
+
+    if clients.is_empty() {
+        return Err(client_error.unwrap_or_else(|| anyhow::anyhow!("no forwards")));
+    }
+
+    if let Some(wait) = args.wait_for_api {
+        let deadline = Duration::from_secs(wait);
+        for result in join_all(clients.values().map(|(_, c)| c.wait_until_reachable(deadline))).await {
+            result?;
+        }
+    }
+
+    if args.print_equivalent {
+        return equivalent::print_equivalent(&clients, &args).await;
+    }
+    if args.bench {
+        return bench::run(&clients, &args).await;
+    }
+
+    let handles: anyhow::Result<Vec<JoinHandle<anyhow::Result<()>>>> =
+        join_all(
+                args.forwards
+                    .iter()
+                    .filter_map(|forward| {
+                        clients.get(&args.client_key(forward)).map(|client| (client, forward))
+                    })
+                    .map(|((client, connectivity), forward)| {
+                        create_forward(
+                            client.clone(),
+                            connectivity.clone(),
+                            forward,
+                            args.control.with_options(&forward.options),
+                            Lifecycle::global(),
+                        )
+                    })
+            )
+            .await
+            .into_iter()
+            .collect();
+
+    let mut handles = handles?;
+    if args.loopback_aliases {
+        info!("bound forwards to loopback aliases:\n{}", alias::summary(&args.forwards));
+    }
+    // Held until main returns, so the entries are removed again on the way out
+    let _hosts_entries = match args.write_hosts {
+        true => {
+            let forwards = args.forwards.iter().filter_map(|f| {
+                let (client, _) = clients.get(&args.client_key(f))?;
+                Some((f, f.namespace.clone().unwrap_or_else(|| client.default_namespace().to_owned())))
+            });
+            Some(hosts::write(&args.hosts_file, &hosts::entries(forwards))?)
+        }
+        false => None,
+    };
+    let (control, requests) = args.control_socket.as_deref().map(control::bind).transpose()?.unzip();
+    // The forwards are handed over, to be restarted as any --config file changes and added or
+    // removed through any --control-socket
+    let supervisor = reload::Supervisor::new(
+        args.config.clone(),
+        requests,
+        args.clone(),
+        clients.clone(),
+        std::mem::take(&mut handles),
+    );
+    handles.extend(control);
+    handles.extend(create_routes(&clients, &args).await?);
+    handles.extend(create_dials(&clients, &args).await?);
+    handles.extend(create_socks(&clients, &args).await?);
+    handles.extend(create_dns(&clients, &args).await?);
+    handles.extend(create_status(&args).await?);
+
+    if let Some(path) = &args.until_file_removed {
+        tokio::spawn(shutdown::until_file_removed(path.clone())?);
+    }
+    if let Some(pod) = &args.until_pod_gone {
+        let (namespace, name) = match pod.split_once('/') {
+            Some((ns, name)) => (Some(ns), name),
+            None => (None, pod.as_str()),
+        };
+        let client = match clients.get(&args.default_client_key()) {
+            Some((client, _)) => client.clone(),
+            None => create_client(&args, &args.default_client_key()).await?,
+        };
+        let api = match namespace {
+            Some(ns) => Api::namespaced(client, ns),
+            None => Api::default_namespaced(client),
+        };
+        tokio::spawn(shutdown::until_pod_gone(api, name.to_owned()).await?);
+    }
+
+    pause::watch_signals()?;
+
+    info!("Ctrl-C to stop the server");
+    tokio::join!(join_all(handles), supervisor.run());
+    events::flush().await;
+
+    Ok(())
+}
+
+/// Builds a client for the key's context, or the in-cluster or current context when `None`,
+/// from the key's kubeconfig or the default one.
+async fn create_client(args: &CliArgs, key: &ClientKey) -> anyhow::Result<Client> {
+    let context = key.context.as_deref();
+    let kube_opts = kube::config::KubeConfigOptions {
+        context: key.context.clone(),
+        cluster: None,
+        user: None,
+    };
+    let kubeconfig = key.kubeconfig.as_deref().map(Kubeconfig::read_from).transpose()?;
+    let mut config = match (args.in_cluster, context, &kubeconfig) {
+        (true, None, None) => Config::incluster()?,
+        (_, _, Some(kubeconfig)) => Config::from_custom_kubeconfig(kubeconfig.clone(), &kube_opts).await?,
+        _ => Config::from_kubeconfig(&kube_opts).await?,
+    };
+    if let Some(ns) = args.default_namespace()? {
+        config.default_namespace = ns;
+    }
+
+    if !args.prod_patterns.is_empty() {
+        let context = match (args.in_cluster, context, kubeconfig) {
+            (_, Some(c), _) => Some(c.to_owned()),
+            (_, None, Some(kubeconfig)) => kubeconfig.current_context,
+            (true, None, None) => None,
+            (false, None, None) => Kubeconfig::read().ok().and_then(|k| k.current_context),
+        };
+        guard::check_context(
+            &args.prod_patterns,
+            args.confirm_prod,
+            context.as_deref(),
+            &config.cluster_url.to_string(),
+        )?;
+    }
+
+    if let (Some(cert), Some(key)) = (&args.client_cert, &args.client_key) {
+        config.auth_info.client_certificate = Some(cert.display().to_string());
+        config.auth_info.client_certificate_data = None;
+        config.auth_info.client_key = Some(key.display().to_string());
+        config.auth_info.client_key_data = None;
+    }
+
+    let client = match &args.connect_via {
+        Some(command) => connector::client(config, command),
+        None => Client::try_from(config),
+    };
+
+    Ok(match args.client_cert {
+        Some(_) => client.map_err(MyError::ClientIdentityMismatch)?,
+        None => client?,
+    })
+}
+
+async fn create_forward(
+    client: Client,
+    connectivity: Arc<Connectivity>,
+    forward: &Forward,
+    args: ControlArgs,
+    lifecycle: Lifecycle,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    if forward.all_ports() {
+        return create_port_forwards(client, connectivity, forward, args, lifecycle).await;
+    }
+
+    let target = Arc::new(Target::new(client, connectivity, forward.clone(), args.clone()));
+
+    let forward_span = info_span!(
+        "forward",
+        target = field::Empty,
+        context = forward.options.context.as_deref()
+    );
+
+    bind_forward(target, forward, args, lifecycle).instrument(forward_span).await
+}
+
+/// Binds a forward's listeners and starts serving them, within the forward's span.
+async fn bind_forward(
+    target: Arc<Target>,
+    forward: &Forward,
+    args: ControlArgs,
+    lifecycle: Lifecycle,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let span_target = |local_port| match &args.span_target_format {
+        Some(f) => f.render(&target, local_port),
+        None => target.name.clone(),
+    };
+    // Forwards binding the service port, or any free port, can only fill in {local_port} once bound
+    let span_target_pending = forward.local_path.is_none()
+        && matches!(forward.local_port, None | Some(0))
+        && args.span_target_format.as_ref().is_some_and(|f| f.needs_local_port());
+    if !span_target_pending {
+        Span::current().record("target", span_target(forward.local_port));
+    }
+
+    let capture = args
+        .capture
+        .as_ref()
+        .map(|dir| Capture::new(dir.clone(), &target.name, args.capture_max_bytes))
+        .transpose()?
+        .map(Arc::new);
+
+    if !args.lazy {
+        match args.wait_for_service {
+            true => target::wait_for(&lifecycle.shutdown, || target.resolve()).await?,
+            false => target.resolve().await?,
+        };
+    }
+
+    // Every address listened on, for --status-addr
+    let mut local_addrs = vec![];
+    let mut fallback_from = None;
+    let listener = match &forward.local_path {
+        Some(path) => {
+            let listener = listener::bind_unix(path)?;
+            let local_addr = format!("unix:{}", path.display());
+            info!(local_addr, "bound");
+            events::bound(&target.name, &local_addr);
+            local_addrs.push(local_addr);
+
+            listener
+        }
+        None => {
+            let local_port = match forward.local_port {
+                Some(p) => p,
+                None => {
+                    // Named or omitted ports need the service resolved to know what to bind
+                    let port = match forward.service_port.as_deref().map(str::parse::<i32>) {
+                        Some(Ok(p)) if !forward.options.port_is_name => p,
+                        _ => target.resolve().await?.port,
+                    };
+                    target::local_port_for(port, args.port_offset)?
+                }
+            };
+
+            let addrs = match forward.local_address {
+                Some(addr) => vec![addr],
+                None => bind::loopback_addresses().to_vec(),
+            };
+            let sock_addr = SocketAddr::from((addrs[0], local_port));
+
+            let socket = bind::bind(sock_addr, args.fallback_port, &target.name).await?;
+            if args.verify_bind {
+                bind::verify_listener(&socket).await?;
+            }
+            // Any fallback port, or the free port picked for 0, is used for the IPv6 listener too
+            let requested_port = local_port;
+            let local_port = socket.local_addr()?.port();
+            if requested_port != 0 && requested_port != local_port {
+                fallback_from = Some(requested_port);
+            }
+            if span_target_pending {
+                Span::current().record("target", span_target(Some(local_port)));
+            }
+            info!(local_addr = socket.local_addr()?.to_string(), "bound");
+            events::bound(&target.name, socket.local_addr()?);
+            local_addrs.push(socket.local_addr()?.to_string());
+
+            let socket_2 = match addrs.get(1) {
+                None => None,
+                Some(addr) => {
+                    let sock_addr = SocketAddr::from((*addr, local_port));
+
+                    let socket = bind::bind(sock_addr, args.fallback_port, &target.name).await?;
+                    if args.verify_bind {
+                        bind::verify_listener(&socket).await?;
+                    }
+                    info!(local_addr = socket.local_addr()?.to_string(), "bound");
+                    events::bound(&target.name, socket.local_addr()?);
+                    local_addrs.push(socket.local_addr()?.to_string());
+
+                    Some(socket)
+                }
+            };
+
+            Listener::Tcp(socket, socket_2)
+        }
+    };
+
+    let listening = status::listening(&target.name, local_addrs, fallback_from);
+    Ok(tokio::spawn(
+        async move {
+            let _listening = listening;
+            serve(listener, target, args, capture, lifecycle).await
+        }
+        .in_current_span(),
+    ))
+}
+
+async fn serve(
+    listener: Listener,
+    target: Arc<Target>,
+    args: ControlArgs,
+    capture: Option<Arc<Capture>>,
+    lifecycle: Lifecycle,
+) -> anyhow::Result<()> {
+    let release = match args.lazy {
+        true => {
+            let target = target.clone();
+            let idle = Duration::from_secs(args.lazy_idle_timeout);
+
+            Some(tokio::spawn(
+                async move {
+                    loop {
+                        tokio::time::sleep(idle / 4).await;
+                        target.release_if_idle(idle).await;
+                    }
+                }
+                .in_current_span(),
+            ))
+        }
+        false => None,
+    };
+    let rotate = spawn_rotation(&target, &args);
+    let _background = Background(release.iter().chain(&rotate).map(JoinHandle::abort_handle).collect());
+
+    let access = AccessRules::from_args(&args);
+
+    listener
+        .incoming()
+        .take_until(lifecycle.shutdown.requested())
+        .filter_map(|accepted| async { accept::recover(accepted).await.transpose() })
+        .try_for_each(|(client_conn, peer)| async {
+            let _connection_span = info_span!(
+                "connection",
+                peer_addr = peer.to_string(),
+                ttfb_ms = field::Empty,
+                duration_ms = field::Empty
+            )
+            .entered();
+
+            // UNIX socket clients are limited by the socket file's permissions instead
+            if peer.ip().is_some_and(|ip| !access.permits(&ip)) {
+                warn!("closed connection from client not permitted by --allow-cidr/--deny-cidr");
+                return Ok(());
+            }
+            if lifecycle.pause.is_paused() {
+                debug!("closed connection while paused");
+                return Ok(());
+            }
+
+            tokio::spawn(
+                handle_connection(client_conn, peer, target.clone(), args.clone(), capture.clone())
+                    .in_current_span(),
+            );
+
+            Ok(())
+        })
+        .await?;
+
+    trace!("closed");
+    Ok(())
+}
+
+/// Starts a forward for each port of a `SERVICE:*` forward's service, which run and stop
+/// together as the one forward.
+async fn create_port_forwards(
+    client: Client,
+    connectivity: Arc<Connectivity>,
+    forward: &Forward,
+    args: ControlArgs,
+    lifecycle: Lifecycle,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let forwards = match args.wait_for_service {
+        true => target::wait_for(&lifecycle.shutdown, || target::forwards_for_ports(client.clone(), forward)).await?,
+        false => target::forwards_for_ports(client.clone(), forward).await?,
+    };
+
+    // Those already started are stopped again if a later port fails to start
+    let mut started = Background(vec![]);
+    let mut handles = vec![];
+    for forward in &forwards {
+        let handle = Box::pin(create_forward(
+            client.clone(),
+            connectivity.clone(),
+            forward,
+            args.clone(),
+            lifecycle.clone(),
+        ))
+        .await?;
+        started.0.push(handle.abort_handle());
+        handles.push(handle);
+    }
+
+    Ok(tokio::spawn(async move {
+        let _started = started;
+        for result in join_all(handles).await {
+            result??;
+        }
+
+        Ok(())
+    }))
+}
+
+/// Aborts a forward's background tasks once it stops, including when the forward is itself
+/// aborted after being removed from --config.
+struct Background(Vec<AbortHandle>);
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// What shuts a forward down and pauses it: the process wide ones for kubempf itself, or a
+/// [`ForwardHandle`]'s own, so forwards started by different tools in one process don't
+/// stop or pause each other.
+#[derive(Clone, Default)]
+struct Lifecycle {
+    shutdown: Shutdown,
+    pause: Pause,
+}
+
+impl Lifecycle {
+    fn global() -> Self {
+        Self {
+            shutdown: shutdown::global().clone(),
+            pause: pause::global().clone(),
+        }
+    }
+}
+
+/// Starts closing a share of the target's connections every --rotate-interval, if given.
+fn spawn_rotation(target: &Arc<Target>, args: &ControlArgs) -> Option<JoinHandle<()>> {
+    let interval = Duration::from_secs(args.rotate_interval?);
+    let target = target.clone();
+    let percent = args.rotate_percent;
+
+    Some(tokio::spawn(
+        async move { target.rotation.run(interval, percent).await }.in_current_span(),
+    ))
+}
+
+/// Where connections to the route port for a hostname go.
+struct RouteTarget {
+    target: Arc<Target>,
+    args: ControlArgs,
+    capture: Option<Arc<Capture>>,
+}
+
+/// Binds --route-bind, when given, and routes the connections to it by hostname.
+async fn create_routes(
+    clients: &HashMap<ClientKey, (Client, Arc<Connectivity>)>,
+    args: &CliArgs,
+) -> anyhow::Result<Option<JoinHandle<anyhow::Result<()>>>> {
+    let Some(bind_addr) = args.route_bind else {
+        return Ok(None);
+    };
+
+    let routes_span = info_span!("routes").entered();
+
+    let mut routes = HashMap::new();
+    for route in &args.routes {
+        let Some((client, connectivity)) = clients.get(&args.client_key(&route.forward)) else {
+            continue;
+        };
+
+        let control = args.control.with_options(&route.forward.options);
+        let target = Arc::new(Target::new(
+            client.clone(),
+            connectivity.clone(),
+            route.forward.clone(),
+            control.clone(),
+        ));
+        let _route_span = info_span!("route", host = route.host, target = target.name).entered();
+
+        let capture = control
+            .capture
+            .as_ref()
+            .map(|dir| Capture::new(dir.clone(), &target.name, control.capture_max_bytes))
+            .transpose()?
+            .map(Arc::new);
+
+        if !control.lazy {
+            target.resolve().await?;
+        }
+
+        let route_target = RouteTarget {
+            target,
+            args: control,
+            capture,
+        };
+        if routes.insert(route.host.clone(), route_target).is_some() {
+            return Err(MyError::DuplicateRoute(route.host.clone()).into());
+        }
+    }
+
+    let socket = bind::bind(bind_addr, args.control.fallback_port, "routes").await?;
+    if args.control.verify_bind {
+        bind::verify_listener(&socket).await?;
+    }
+    info!(local_addr = socket.local_addr()?.to_string(), "bound");
+    events::bound("routes", socket.local_addr()?);
+
+    let routes_span = routes_span.exit();
+    Ok(Some(tokio::spawn(
+        serve_routes(socket, Arc::new(routes), args.control.clone()).instrument(routes_span),
+    )))
+}
+
+async fn serve_routes(
+    socket: TcpListener,
+    routes: Arc<HashMap<String, RouteTarget>>,
+    args: ControlArgs,
+) -> anyhow::Result<()> {
+    let access = AccessRules::from_args(&args);
+    let rotate: Vec<_> = routes
+        .values()
+        .filter_map(|r| {
+            let _route_span = info_span!("route", target = r.target.name).entered();
+            spawn_rotation(&r.target, &r.args)
+        })
+        .collect();
+
+    TcpListenerStream::new(socket)
+        .take_until(shutdown::requested())
+        .filter_map(|accepted| async { accept::recover(accepted).await.transpose() })
+        .try_for_each(|client_conn| async {
+            let Ok(peer_addr) = client_conn.peer_addr() else {
+                debug!("client disconnected before its connection was accepted");
+                return Ok(());
+            };
+            let connection_span = info_span!(
+                "connection",
+                peer_addr = peer_addr.to_string(),
+                host = field::Empty,
+                target = field::Empty,
+                ttfb_ms = field::Empty,
+                duration_ms = field::Empty
+            );
+
+            if !access.permits(&peer_addr.ip()) {
+                connection_span.in_scope(|| {
+                    warn!("closed connection from client not permitted by --allow-cidr/--deny-cidr")
+                });
+                return Ok(());
+            }
+            if pause::global().is_paused() {
+                connection_span.in_scope(|| debug!("closed connection while paused"));
+                return Ok(());
+            }
+
+            let routes = routes.clone();
+            tokio::spawn(
+                async move {
+                    let mut client_conn = client_conn;
+
+                    let (host, read) =
+                        match tokio::time::timeout(ROUTE_SNIFF_TIMEOUT, route::read_host(&mut client_conn)).await {
+                            Ok(Ok(sniffed)) => sniffed,
+                            Ok(Err(e)) => {
+                                warn!(error = &e as &dyn std::error::Error, "failed to read hostname");
+                                return;
+                            }
+                            Err(_) => {
+                                warn!("timed out waiting for a TLS ClientHello or HTTP request, closing");
+                                return;
+                            }
+                        };
+
+                    if let Some(h) = &host {
+                        Span::current().record("host", h.as_str());
+                    }
+
+                    let Some(route) = host
+                        .as_deref()
+                        .and_then(|h| routes.get(h))
+                        .or_else(|| routes.get(route::FALLBACK_HOST))
+                    else {
+                        warn!(host, "no --route for hostname, closing");
+                        return;
+                    };
+                    Span::current().record("target", route.target.name.as_str());
+
+                    handle_connection(
+                        route::Prefixed::new(read, client_conn),
+                        peer_addr.into(),
+                        route.target.clone(),
+                        route.args.clone(),
+                        route.capture.clone(),
+                    )
+                    .await
+                }
+                .instrument(connection_span),
+            );
+
+            Ok(())
+        })
+        .await?;
+
+    for r in rotate {
+        r.abort();
+    }
+    trace!("closed");
+    Ok(())
+}
+
+/// Binds --socks5, when given, and forwards the connections requested through it to the
+/// services they name.
+async fn create_socks(
+    clients: &HashMap<ClientKey, (Client, Arc<Connectivity>)>,
+    args: &CliArgs,
+) -> anyhow::Result<Option<JoinHandle<anyhow::Result<()>>>> {
+    let Some(bind_addr) = args.socks5 else {
+        return Ok(None);
+    };
+    let Some((client, connectivity)) = clients.get(&args.default_client_key()) else {
+        return Ok(None);
+    };
+
+    let socks_span = info_span!("socks5").entered();
+
+    let socket = bind::bind(bind_addr, args.control.fallback_port, "socks5").await?;
+    if args.control.verify_bind {
+        bind::verify_listener(&socket).await?;
+    }
+    info!(local_addr = socket.local_addr()?.to_string(), "bound");
+    events::bound("socks5", socket.local_addr()?);
+
+    let targets = socks::Targets::new(client.clone(), connectivity.clone(), args.control.clone());
+
+    let socks_span = socks_span.exit();
+    Ok(Some(tokio::spawn(
+        serve_socks(socket, Arc::new(targets), args.control.clone()).instrument(socks_span),
+    )))
+}
+
+async fn serve_socks(socket: TcpListener, targets: Arc<socks::Targets>, args: ControlArgs) -> anyhow::Result<()> {
+    let access = AccessRules::from_args(&args);
+
+    TcpListenerStream::new(socket)
+        .take_until(shutdown::requested())
+        .filter_map(|accepted| async { accept::recover(accepted).await.transpose() })
+        .try_for_each(|client_conn| async {
+            let Ok(peer_addr) = client_conn.peer_addr() else {
+                debug!("client disconnected before its connection was accepted");
+                return Ok(());
+            };
+            let connection_span = info_span!(
+                "connection",
+                peer_addr = peer_addr.to_string(),
+                host = field::Empty,
+                target = field::Empty,
+                ttfb_ms = field::Empty,
+                duration_ms = field::Empty
+            );
+
+            if !access.permits(&peer_addr.ip()) {
+                connection_span.in_scope(|| {
+                    warn!("closed connection from client not permitted by --allow-cidr/--deny-cidr")
+                });
+                return Ok(());
+            }
+            if pause::global().is_paused() {
+                connection_span.in_scope(|| debug!("closed connection while paused"));
+                return Ok(());
+            }
+
+            let (targets, args) = (targets.clone(), args.clone());
+            tokio::spawn(
+                async move {
+                    let mut client_conn = client_conn;
+
+                    let request = match tokio::time::timeout(SOCKS_HANDSHAKE_TIMEOUT, socks::read_request(&mut client_conn)).await {
+                        Ok(Ok(request)) => request,
+                        Ok(Err(e)) => {
+                            warn!(error = &e as &dyn std::error::Error, "failed to read SOCKS5 request");
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("timed out waiting for a SOCKS5 request, closing");
+                            return;
+                        }
+                    };
+                    Span::current().record("host", request.host.as_str());
+
+                    let Some((namespace, service)) = socks::service_for_host(&request.host) else {
+                        warn!("not a service hostname, closing");
+                        let _ = socks::reply(&mut client_conn, socks::Reply::HostUnreachable).await;
+                        return;
+                    };
+                    let (target, capture) = match targets.get(namespace, service, request.port) {
+                        Ok(target) => target,
+                        Err(e) => {
+                            error!(error = e.as_ref() as &dyn std::error::Error, "failed to start capture");
+                            let _ = socks::reply(&mut client_conn, socks::Reply::HostUnreachable).await;
+                            return;
+                        }
+                    };
+                    Span::current().record("target", target.name.as_str());
+
+                    // Resolved before answering, so a service that doesn't exist is reported to the client
+                    if let Err(e) = target.resolve().await {
+                        warn!(error = e.as_ref() as &dyn std::error::Error, "failed to resolve service, closing");
+                        let _ = socks::reply(&mut client_conn, socks::Reply::HostUnreachable).await;
+                        return;
+                    }
+                    if let Err(e) = socks::reply(&mut client_conn, socks::Reply::Succeeded).await {
+                        debug!(error = &e as &dyn std::error::Error, "client disconnected before forwarding");
+                        return;
+                    }
+
+                    handle_connection(client_conn, peer_addr.into(), target, args, capture).await
+                }
+                .instrument(connection_span),
+            );
+
+            Ok(())
+        })
+        .await?;
+
+    trace!("closed");
+    Ok(())
+}
+
+/// Binds --dns, when given, and answers queries for services by starting their forwards.
+async fn create_dns(
+    clients: &HashMap<ClientKey, (Client, Arc<Connectivity>)>,
+    args: &CliArgs,
+) -> anyhow::Result<Option<JoinHandle<anyhow::Result<()>>>> {
+    let Some(bind_addr) = args.dns else {
+        return Ok(None);
+    };
+    let Some((client, connectivity)) = clients.get(&args.default_client_key()) else {
+        return Ok(None);
+    };
+
+    let dns_span = info_span!("dns");
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+    dns_span.in_scope(|| info!(local_addr = bind_addr.to_string(), "bound"));
+    events::bound("dns", socket.local_addr()?);
+
+    // Clear of the aliases given to forwards by --loopback-aliases
+    let aliases = alias::Aliases::after(&args.forwards);
+    let services = dns::Services::new(client.clone(), connectivity.clone(), args.control.clone(), aliases);
+    Ok(Some(tokio::spawn(dns::serve(socket, Arc::new(services)).instrument(dns_span))))
+}
+
+/// Binds --status-addr, when given, and answers requests for the forwards and connections.
+async fn create_status(args: &CliArgs) -> anyhow::Result<Option<JoinHandle<anyhow::Result<()>>>> {
+    let Some(bind_addr) = args.status_addr else {
+        return Ok(None);
+    };
+
+    let status_span = info_span!("status").entered();
+
+    let socket = bind::bind(bind_addr, args.control.fallback_port, "status").await?;
+    info!(local_addr = socket.local_addr()?.to_string(), "bound");
+    events::bound("status", socket.local_addr()?);
+
+    let status_span = status_span.exit();
+    Ok(Some(tokio::spawn(status::serve(socket).instrument(status_span))))
+}
+
+/// Resolves each --dial's target and connects out to its address.
+async fn create_dials(
+    clients: &HashMap<ClientKey, (Client, Arc<Connectivity>)>,
+    args: &CliArgs,
+) -> anyhow::Result<Vec<JoinHandle<anyhow::Result<()>>>> {
+    let mut handles = vec![];
+
+    for dial in &args.dials {
+        let Some((client, connectivity)) = clients.get(&args.client_key(&dial.forward)) else {
+            continue;
+        };
+
+        let control = args.control.with_options(&dial.forward.options);
+        let target = Arc::new(Target::new(
+            client.clone(),
+            connectivity.clone(),
+            dial.forward.clone(),
+            control.clone(),
+        ));
+        let dial_span = info_span!("dial", address = dial.address.to_string(), target = target.name).entered();
+
+        let capture = control
+            .capture
+            .as_ref()
+            .map(|dir| Capture::new(dir.clone(), &target.name, control.capture_max_bytes))
+            .transpose()?
+            .map(Arc::new);
+
+        // There is only the one connection, so there's nothing to gain from waiting
+        target.resolve().await?;
+
+        let dial_span = dial_span.exit();
+        handles.push(tokio::spawn(
+            serve_dial(dial.address, target, control, capture).instrument(dial_span),
+        ));
+    }
+
+    Ok(handles)
+}
+
+/// Connects to `address` and forwards that one connection, without reconnecting once it closes.
+async fn serve_dial(
+    address: SocketAddr,
+    target: Arc<Target>,
+    args: ControlArgs,
+    capture: Option<Arc<Capture>>,
+) -> anyhow::Result<()> {
+    let client_conn = match TcpStream::connect(address).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!(error = &e as &dyn std::error::Error, "failed to connect to --dial address");
+            return Err(e.into());
+        }
+    };
+    info!("connected");
+
+    let connection_span = info_span!(
+        "connection",
+        peer_addr = address.to_string(),
+        ttfb_ms = field::Empty,
+        duration_ms = field::Empty
+    );
+    let forward = handle_connection(client_conn, address.into(), target, args, capture).instrument(connection_span);
+
+    tokio::select! {
+        _ = forward => info!("dialled connection closed, not reconnecting"),
+        _ = shutdown::requested() => {}
+    }
+
+    trace!("closed");
+    Ok(())
+}
+
+/// Forwards a single accepted connection to `target`, within the connection's span.
+async fn handle_connection(
+    client_conn: impl AsyncRead + AsyncWrite + Unpin + Send,
+    peer: Peer,
+    target: Arc<Target>,
+    args: ControlArgs,
+    capture: Option<Arc<Capture>>,
+) {
+    let connection = events::Connection::opened(&target.name, &peer);
+
+    trace!("accepted new connection");
+
+    let files = capture.as_ref().map(|c| c.start());
+    let client_conn = CaptureReadWrite::new(connection.count(client_conn), files);
+    let mut rotatable = target.rotation.register();
+
+    let forward = async {
+        target.connectivity.check()?;
+        let resolved = target.resolve().await?;
+
+        pod::forward_connection(&resolved, client_conn, args, &connection, peer.ip()).await
+    };
+
+    let result = tokio::select! {
+        result = forward => result,
+        _ = rotatable.rotated() => {
+            info!("closed connection for --rotate-interval");
+            connection.closed(None);
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        connection.closed(Some(&e));
+
+        // Outages are reported once by the connectivity monitor
+        if target.connectivity.observe(&e) {
+            debug!(
+                error = e.as_ref() as &dyn std::error::Error,
+                "failed to forward connection"
+            );
+        } else {
+            error!(
+                error = e.as_ref() as &dyn std::error::Error,
+                "failed to forward connection"
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use futures::{stream::BoxStream, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use tracing::info;
+
+use crate::errors::MyError;
+
+/// A client connection, from whichever kind of socket it was accepted on.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Where a connection came from.
+#[derive(Clone, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A UNIX socket client, which has no address of its own, so is named by the socket's path
+    Unix(PathBuf),
+}
+
+impl Peer {
+    /// The client's IP address, for --allow-cidr and --deny-cidr, which UNIX socket clients
+    /// don't have.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => addr.fmt(f),
+            Peer::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The sockets a forward accepts connections on.
+pub enum Listener {
+    /// One or two TCP listeners, for the IPv4 and IPv6 loopback addresses
+    Tcp(TcpListener, Option<TcpListener>),
+    #[cfg(unix)]
+    Unix(UnixListener, SocketFile),
+}
+
+impl Listener {
+    /// The connections accepted on every socket, until the stream is dropped, which also
+    /// closes the sockets.
+    pub fn incoming(self) -> BoxStream<'static, std::io::Result<(Box<dyn Io>, Peer)>> {
+        match self {
+            Listener::Tcp(socket, socket_2) => {
+                let incoming = futures::stream::select_all(
+                    [Some(socket), socket_2]
+                        .into_iter()
+                        .flatten()
+                        .map(|socket| accept_tcp(socket).boxed()),
+                );
+                incoming.boxed()
+            }
+            #[cfg(unix)]
+            Listener::Unix(socket, file) => futures::stream::poll_fn(move |cx| {
+                socket.poll_accept(cx).map(|accepted| {
+                    Some(accepted.map(|(conn, _)| (Box::new(conn) as Box<dyn Io>, Peer::Unix(file.0.clone()))))
+                })
+            })
+            .boxed(),
+        }
+    }
+}
+
+fn accept_tcp(socket: TcpListener) -> impl Stream<Item = std::io::Result<(Box<dyn Io>, Peer)>> {
+    futures::stream::poll_fn(move |cx| {
+        socket
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(conn, addr)| (Box::new(conn) as Box<dyn Io>, Peer::Tcp(addr)))))
+    })
+}
+
+/// A UNIX socket's file, removed once the socket is no longer listened on.
+#[cfg(unix)]
+pub struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Listens on a UNIX socket at `path`, replacing the file of a socket left behind by a process
+/// that has gone, but never one still being listened on or a file that isn't a socket.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path) -> anyhow::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(MyError::UnixSocketInUse(path.display().to_string()).into());
+        }
+        info!(local_path = %path.display(), "removing stale socket");
+        std::fs::remove_file(path)?;
+    }
+
+    let socket = UnixListener::bind(path)?;
+    Ok(Listener::Unix(socket, SocketFile(path.to_owned())))
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix(path: &Path) -> anyhow::Result<Listener> {
+    Err(MyError::UnixSocketUnsupported(path.display().to_string()).into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kubempf-{}-{name}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn unix_socket_accepts_and_is_removed() {
+        let path = socket_path("accepts");
+        let mut incoming = bind_unix(&path).unwrap().incoming();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut conn, peer) = incoming.next().await.unwrap().unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        conn.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"ping");
+        assert_eq!(peer.to_string(), format!("unix:{}", path.display()));
+        assert_eq!(peer.ip(), None);
+
+        drop(incoming);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn stale_sockets_are_replaced_but_not_live_ones() {
+        let path = socket_path("stale");
+        // Left behind as if by a process that was killed
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let live = bind_unix(&path).unwrap();
+        let err = bind_unix(&path).err().unwrap();
+
+        assert!(matches!(err.downcast_ref(), Some(MyError::UnixSocketInUse(_))));
+        drop(live);
+    }
+}
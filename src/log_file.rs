@@ -0,0 +1,150 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+
+use crate::errors::MyError;
+
+/// How often --log-file starts a new file.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogRotation {
+    /// Always append to the one file
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl LogRotation {
+    fn rotation(&self) -> Rotation {
+        match self {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+        }
+    }
+}
+
+/// Opens `path` for logs, in the background so writing them never blocks a connection. Rotated
+/// files have the date, and hour, appended to the name, eg. `kubempf.log.2024-05-01`, or with
+/// `max_bytes` are rotated by size instead, see [`SizeRotating`].
+///
+/// Logs are written out until the guard is dropped, which waits for those still queued.
+pub fn writer(
+    path: &Path,
+    rotation: LogRotation,
+    max_bytes: Option<u64>,
+) -> Result<(NonBlocking, WorkerGuard), MyError> {
+    let invalid = |reason: String| MyError::InvalidLogFile(path.display().to_string(), reason);
+
+    let Some(file_name) = path.file_name() else {
+        return Err(invalid("expected a path to a file".to_owned()));
+    };
+    if let Some(max_bytes) = max_bytes {
+        let file = SizeRotating::open(path.to_owned(), max_bytes).map_err(|e| invalid(e.to_string()))?;
+        return Ok(tracing_appender::non_blocking(file));
+    }
+    let directory = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation.rotation())
+        .filename_prefix(file_name.to_string_lossy())
+        .build(directory)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    Ok(tracing_appender::non_blocking(appender))
+}
+
+/// A log file started afresh once writing to it would take it past `max_bytes`, the full one
+/// being kept as `<path>.1` in place of any kept before it.
+///
+/// Each write is kept to one file, so logs aren't split across the two.
+pub struct SizeRotating {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+}
+
+impl SizeRotating {
+    pub fn open(path: PathBuf, max_bytes: u64) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(Self { path, file, written, max_bytes })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let mut kept = self.path.clone().into_os_string();
+        kept.push(".1");
+        std::fs::rename(&self.path, kept)?;
+
+        *self = Self::open(self.path.clone(), self.max_bytes)?;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotating {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queued_logs_are_written_when_the_guard_drops() {
+        let path = std::env::temp_dir().join(format!("kubempf-{}-log", std::process::id()));
+
+        let (mut writer, guard) = writer(&path, LogRotation::Never, None).unwrap();
+        writer.write_all(b"forwarding started\n").unwrap();
+        drop(guard);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "forwarding started\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn directory_is_not_a_file() {
+        assert!(matches!(writer(Path::new("/"), LogRotation::Daily, None), Err(MyError::InvalidLogFile(..))));
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let path = std::env::temp_dir().join(format!("kubempf-{}-sized-log", std::process::id()));
+        let kept = path.with_file_name(format!("{}.1", path.file_name().unwrap().to_string_lossy()));
+
+        let mut file = SizeRotating::open(path.clone(), 10).unwrap();
+        file.write_all(b"first\n").unwrap();
+        file.write_all(b"second\n").unwrap();
+        file.write_all(b"third\n").unwrap();
+        file.write_all(b"a line longer than the limit\n").unwrap();
+        drop(file);
+
+        assert_eq!(std::fs::read_to_string(&kept).unwrap(), "third\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a line longer than the limit\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&kept).unwrap();
+    }
+}
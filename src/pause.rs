@@ -0,0 +1,85 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, OnceLock,
+};
+
+use tracing::{info, warn};
+
+static PAUSED: OnceLock<Pause> = OnceLock::new();
+
+/// Whether the forwards sharing it turn new connections away. kubempf's own share the
+/// [`global`] one, while each [`ForwardHandle`](crate::ForwardHandle) has one of its own.
+#[derive(Clone, Default)]
+pub struct Pause(Arc<AtomicBool>);
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Pauses or resumes, returning whether that changed anything.
+    pub fn set(&self, paused: bool) -> bool {
+        self.0.swap(paused, Ordering::Relaxed) != paused
+    }
+}
+
+/// The pause of kubempf itself, set by [`watch_signals`].
+pub fn global() -> &'static Pause {
+    PAUSED.get_or_init(Pause::default)
+}
+
+/// Pauses or resumes every forward, returning whether that changed anything.
+fn set_paused(paused: bool) -> bool {
+    let changed = global().set(paused);
+
+    match (changed, paused) {
+        (true, true) => warn!("paused, new connections are closed as they are accepted until SIGUSR2"),
+        (true, false) => info!("resumed, accepting new connections again"),
+        (false, _) => {}
+    }
+
+    changed
+}
+
+/// Pauses every forward on SIGUSR1 and resumes them on SIGUSR2. While paused, connections
+/// already forwarding carry on, but new ones are closed straight away.
+#[cfg(unix)]
+pub fn watch_signals() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut pause = signal(SignalKind::user_defined1())?;
+    let mut resume = signal(SignalKind::user_defined2())?;
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = pause.recv() => set_paused(true),
+                Some(()) = resume.recv() => set_paused(false),
+                else => break,
+            };
+        }
+    });
+
+    Ok(())
+}
+
+/// There are no signals to pause with outside of unix.
+#[cfg(not(unix))]
+pub fn watch_signals() -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_transitions_change_state() {
+        assert!(!set_paused(false));
+        assert!(set_paused(true));
+        assert!(global().is_paused());
+        assert!(!set_paused(true));
+        assert!(set_paused(false));
+        assert!(!global().is_paused());
+    }
+}
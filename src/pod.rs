@@ -1,170 +1,1059 @@
 use crate::{
-    cancelable_stream::CancelableReadWrite,
+    cancelable_stream::{CancelableReadWrite, ConcealedError},
     cli::ControlArgs,
+    direction::{self, Direction},
+    events,
+    hook::{self, Availability},
+    inject::InjectHeader,
+    pod_cache::OnChange,
+    relay,
+    select::{PodOwner, PodPredicate},
+    stall::{Peer, StallGuard, Stalls},
+    target::Resolved,
 };
 use anyhow::Context;
+use clap::ValueEnum;
 use futures::future::Either;
-use futures::{stream::AbortHandle, TryStreamExt};
+use futures::{stream::AbortHandle, Stream, TryStreamExt};
 use k8s_openapi::{
-    api::core::v1::{ContainerPort, Pod},
+    api::{
+        apps::v1::ReplicaSet,
+        core::v1::{ContainerPort, Pod},
+    },
     apimachinery::pkg::util::intstr::IntOrString,
 };
 use kube::{
-    api::ListParams,
+    api::{ListParams, Portforwarder},
     runtime::{watcher, watcher::Config, WatchStreamExt},
     Api,
 };
-use rand::Rng;
-use tokio::io::{AsyncRead, AsyncWrite};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{BTreeSet, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::pin;
-use tracing::{error, info, info_span, Instrument};
+use tracing::{debug, error, field::Empty, info, info_span, warn, Instrument, Span};
 
 use crate::errors::MyError;
 
+/// What --on-pod-loss does when the port-forward to the pod picked for a connection can't be
+/// opened.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PodLossPolicy {
+    /// Pick another eligible pod, up to --pod-attempts pods per connection
+    #[default]
+    Reselect,
+    /// Fail the connection
+    Fail,
+}
+
+/// What --on-unready does with a connection to a pod that becomes unready, with
+/// --close-on-unready.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum UnreadyPolicy {
+    /// Close the connection
+    #[default]
+    Close,
+    /// Move the connection to another eligible pod, keeping the client connected
+    Reselect,
+}
+
+/// How --policy picks among the eligible pods, unless --randomise is given.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PodPolicy {
+    /// The first eligible pod, as the API server lists them
+    #[default]
+    First,
+    /// Each eligible pod in turn, ordered by name, carrying on from the last one the forward used
+    RoundRobin,
+    /// The eligible pod with the fewest of the forward's connections open to it
+    LeastConn,
+}
+
+/// What a forward's --policy and --sticky pick pods by: the pod it picked last, how many
+/// connections are open to each pod, and the pod each client is pinned to. Shared by everything
+/// picking pods for the forward's target, for as long as it stays resolved.
+#[derive(Debug, Default)]
+pub struct PodBalance {
+    last: Mutex<Option<String>>,
+    active: Mutex<HashMap<String, usize>>,
+    pinned: Mutex<HashMap<IpAddr, String>>,
+}
+
+impl PodBalance {
+    /// The index of the pod to pick for `policy`, remembered as picked. Pods are taken in turn
+    /// by name, starting after the one picked last and wrapping round, so with --policy
+    /// least-conn pods tied on connections are picked in turn too.
+    fn next(&self, pods: &[Pod], policy: PodPolicy) -> usize {
+        let mut names: Vec<(&str, usize)> = pods
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (p.metadata.name.as_deref().unwrap_or_default(), i))
+            .collect();
+        names.sort();
+
+        let mut last = self.last.lock().unwrap();
+        let after = names
+            .iter()
+            .position(|(name, _)| last.as_deref().is_some_and(|l| *name > l))
+            .unwrap_or_default();
+        let mut in_turn = names[after..].iter().chain(&names[..after]);
+        let picked = match policy {
+            PodPolicy::LeastConn => {
+                let active = self.active.lock().unwrap();
+                let count = |name: &str| active.get(name).copied().unwrap_or_default();
+                in_turn.reduce(|fewest, p| if count(p.0) < count(fewest.0) { p } else { fewest })
+            }
+            _ => in_turn.next(),
+        };
+        let (name, index) = picked.expect("at least one pod to pick from");
+        *last = Some((*name).to_owned());
+
+        *index
+    }
+
+    /// The index of the pod the client at `peer` is pinned to for --sticky, for as long as it is
+    /// among the eligible `pods`. Otherwise the client is pinned to the pod whose name hashes
+    /// highest along with its address, so clients are spread over the pods, and a client
+    /// moved off a pod that has gone lands on the same pod each time.
+    fn pinned(&self, pods: &[Pod], peer: IpAddr) -> usize {
+        let name = |pod: &Pod| pod.metadata.name.clone().unwrap_or_default();
+        let mut pinned = self.pinned.lock().unwrap();
+        if let Some(index) = pinned.get(&peer).and_then(|p| pods.iter().position(|pod| name(pod) == *p)) {
+            return index;
+        }
+
+        let weight = |pod: &Pod| {
+            let mut hasher = DefaultHasher::new();
+            (peer, name(pod)).hash(&mut hasher);
+            hasher.finish()
+        };
+        let index = (0..pods.len()).max_by_key(|&i| weight(&pods[i])).unwrap_or_default();
+        pinned.insert(peer, name(&pods[index]));
+
+        index
+    }
+
+    /// Counts a connection as open to the pod until the returned guard is dropped.
+    pub fn connected(self: &Arc<Self>, pod_name: &str) -> ActiveConnection {
+        *self.active.lock().unwrap().entry(pod_name.to_owned()).or_default() += 1;
+
+        ActiveConnection {
+            balance: self.clone(),
+            pod_name: pod_name.to_owned(),
+        }
+    }
+}
+
+/// A connection counted as open to a pod, for --policy least-conn.
+pub struct ActiveConnection {
+    balance: Arc<PodBalance>,
+    pod_name: String,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut active = self.balance.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.pod_name) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.pod_name);
+            }
+        }
+    }
+}
+
 pub async fn forward_connection(
-    pod_api: &Api<Pod>,
-    selector: &ListParams,
-    pod_port: &IntOrString,
+    resolved: &Resolved,
     client_conn: impl AsyncRead + AsyncWrite + Unpin,
     args: ControlArgs,
+    connection: &events::Connection,
+    peer: Option<IpAddr>,
 ) -> anyhow::Result<()> {
-    let pod = find_pod(pod_api, selector, args.ignore_readiness, args.randomise).await?;
-    let port = find_pod_port(pod_port, &pod)?;
+    let pod_api = &resolved.pod_api;
+    let selection = PodSelection {
+        sticky: peer.filter(|_| args.sticky),
+        ..PodSelection::for_target(&args, resolved)
+    };
+    let prewarmed = resolved.prewarm.as_ref().and_then(|pool| pool.take());
+
+    let relay_port = || {
+        u16::try_from(resolved.port).map_err(|_| MyError::CouldNotFindPort(IntOrString::Int(resolved.port)))
+    };
+
+    let is_prewarmed = prewarmed.is_some();
+    let (name_string, port, upstream, choice) = match (prewarmed, &resolved.via_pod, &resolved.cluster_ip) {
+        (Some(p), _, _) => (p.pod_name, p.port, Some(p.upstream), p.choice),
+        (None, Some((_, bastion)), _) => (bastion.clone(), relay_port()?, None, PodChoice::VIA_POD),
+        (None, None, Some(_)) => {
+            let (pod, choice) = resolved.find_pod(&selection).await?;
 
-    let name_string = pod.metadata.name.unwrap(); // how on earth you would end up here without a pod name is beyond me
+            let name_string = pod.metadata.name.unwrap(); // how on earth you would end up here without a pod name is beyond me
+            (name_string, relay_port()?, None, choice)
+        }
+        (None, None, None) => {
+            let (pod_name, port, upstream, choice) = open_with_fallback(resolved, &args, &selection).await?;
+            (pod_name, port, Some(upstream), choice)
+        }
+    };
     let pod_name = name_string.as_str();
+    connection.pod_selected(pod_name, port);
+    let mut active = resolved.balance.connected(pod_name);
+
+    let stall_timeout = args.stall_timeout.map(Duration::from_secs);
+    let stalls = connection.stalls();
+    let client_conn = StallGuard::new(client_conn, Peer::Client, stall_timeout, stalls.clone());
+    let client_conn = InjectHeader::new(client_conn, args.inject_header.as_deref(), pod_name);
+
+    let span = pod_span();
+    choice.record(&span, pod_name, port, is_prewarmed);
 
     async move {
-        let result = match args.close_on_unready {
-            true => _forward_connection_with_unready(pod_api, pod_name, port, client_conn).await,
-            false => _forward_connection(pod_api, pod_name, port, client_conn).await,
-        };
+        let result = async {
+            if let Some(cluster_ip) = &resolved.cluster_ip {
+                let relay_api = resolved.via_pod.as_ref().map_or(pod_api, |(api, _)| api);
+
+                return relay::relay_connection(
+                    relay_api,
+                    pod_name,
+                    &args.relay_command,
+                    cluster_ip,
+                    port,
+                    client_conn,
+                )
+                .await;
+            }
+
+            let upstream = match upstream {
+                Some(u) => u,
+                None => open_upstream(pod_api, pod_name, port).await?,
+            }
+            .with_stall_guard(stall_timeout, stalls);
 
-        if let Err(e) = result {
-            error!(
+            match (args.close_on_unready, args.reconnect_idle) {
+                // A one-way forward's closed direction would have to be closed again on every pod
+                (true, _) if args.on_unready == UnreadyPolicy::Reselect && args.direction == Direction::Both => {
+                    let reselected = |name: &str, port| {
+                        connection.pod_selected(name, port);
+                        active = resolved.balance.connected(name);
+                    };
+                    _forward_connection_reselecting(
+                        resolved,
+                        &args,
+                        &selection,
+                        pod_name,
+                        upstream,
+                        client_conn,
+                        stalls,
+                        reselected,
+                    )
+                    .await
+                }
+                (true, _) => _forward_connection_with_unready(pod_api, pod_name, &args, upstream, client_conn).await,
+                // A one-way forward has no reply to wait on before deciding the pod side is idle
+                (false, Some(idle)) if args.direction == Direction::Both => {
+                    _forward_connection_reconnecting(
+                        resolved,
+                        &args,
+                        &selection,
+                        upstream,
+                        client_conn,
+                        stalls,
+                        Duration::from_secs(idle),
+                    )
+                    .await
+                }
+                (false, _) => _forward_connection(args.direction, upstream, client_conn).await,
+            }
+        }
+        .await;
+
+        connection.closed(result.as_ref().err());
+
+        match result {
+            Ok((up, down)) => info!(
+                up = format!("{0:#}", byte_unit::Byte::from_u64(up)),
+                down = format!("{0:#}", byte_unit::Byte::from_u64(down)),
+                "forwarding finished"
+            ),
+            Err(e) => error!(
                 error = e.as_ref() as &dyn std::error::Error,
                 "an error occurred while forwarding the connection"
-            );
+            ),
         }
     }
-    .instrument(info_span!(
-        "pod",
-        pod_name = pod_name.to_string(),
-        pod_port = port
-    ))
+    .instrument(span)
     .await;
 
     Ok(())
 }
 
+/// Picks a pod and opens a port-forward to it. When opening fails, eg. as the pod went away
+/// after it was picked, another eligible pod is picked instead with --on-pod-loss reselect, up
+/// to --pod-attempts pods in all, returning the last failure when none can be opened.
+async fn open_with_fallback(
+    resolved: &Resolved,
+    args: &ControlArgs,
+    selection: &PodSelection,
+) -> anyhow::Result<(String, u16, Upstream, PodChoice)> {
+    let mut selection = selection.clone();
+    let mut last_error = None;
+    let max_attempts = match args.on_pod_loss {
+        PodLossPolicy::Reselect => args.pod_attempts,
+        PodLossPolicy::Fail => 1,
+    };
+
+    for attempt in 1..=max_attempts {
+        let picked = match &resolved.endpoints {
+            Some(endpoints) => endpoints
+                .next(args.ignore_readiness)
+                .await
+                .map(|(endpoint, choice)| (endpoint.pod_name, endpoint.port, choice)),
+            None => match resolved.find_pod(&selection).await {
+                Ok((pod, choice)) => find_pod_port(&resolved.pod_port, &pod)
+                    .map(|port| (pod.metadata.name.unwrap_or_default(), port, choice))
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            },
+        };
+
+        let (pod_name, port, choice) = match picked {
+            Ok(p) if !selection.excluded.contains(&p.0) => p,
+            Err(e) if last_error.is_none() => return Err(e),
+            // Out of other pods to try, where the failure to open says more
+            _ => break,
+        };
+
+        match open_upstream(&resolved.pod_api, &pod_name, port).await {
+            Ok(upstream) => return Ok((pod_name, port, upstream, choice)),
+            // Permissions are the same for every pod
+            Err(e) if matches!(e.downcast_ref(), Some(MyError::PortForwardForbidden(_))) => return Err(e),
+            Err(e) => {
+                warn!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    pod_name = pod_name.as_str(),
+                    attempt,
+                    max_attempts,
+                    "failed to open port-forward to pod"
+                );
+                selection.excluded.insert(pod_name);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.expect("only out of attempts after failing to open"))
+}
+
+/// Object safe combination of the traits needed of a forwarded stream
+pub trait ReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T> ReadWrite for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// An established port-forward stream to a single port on a pod.
+pub struct Upstream {
+    forwarder: Portforwarder,
+    stream: Box<dyn ReadWrite>,
+}
+
+impl Upstream {
+    /// Tracks the stream being blocked in `stalls`, closing it once the pod stops accepting data
+    /// for `timeout`, see [`StallGuard`].
+    pub fn with_stall_guard(self, timeout: Option<Duration>, stalls: &Arc<Stalls>) -> Self {
+        Self {
+            forwarder: self.forwarder,
+            stream: Box::new(StallGuard::new(self.stream, Peer::Pod, timeout, stalls.clone())),
+        }
+    }
+
+    /// Tears down the underlying port-forward without waiting for it to finish.
+    pub fn abort(self) {
+        self.forwarder.abort();
+    }
+}
+
+pub async fn open_upstream(pod_api: &Api<Pod>, pod_name: &str, port: u16) -> anyhow::Result<Upstream> {
+    let mut forwarder = match pod_api.portforward(pod_name, &[port]).await {
+        Ok(f) => f,
+        Err(e) if is_forbidden(&e) => return Err(MyError::PortForwardForbidden(pod_name.to_owned()).into()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // The forwarder creates a stream for each requested port as it is created, rather than as
+    // they are negotiated, so a missing stream is a bug rather than something to wait out
+    let Some(stream) = forwarder.take_stream(port) else {
+        forwarder.abort();
+        return Err(MyError::PortNotInForwarder(pod_name.to_owned(), port).into());
+    };
+
+    Ok(Upstream {
+        forwarder,
+        stream: Box::new(stream),
+    })
+}
+
+/// Whether the API server refused the port-forward, which it does by failing the WebSocket
+/// upgrade rather than with an API error.
+fn is_forbidden(error: &kube::Error) -> bool {
+    match error {
+        kube::Error::UpgradeConnection(kube::client::UpgradeConnectionError::ProtocolSwitch(status)) => {
+            *status == http::StatusCode::FORBIDDEN
+        }
+        kube::Error::Api(response) => response.code == 403,
+        _ => false,
+    }
+}
+
 async fn _forward_connection(
-    pod_api: &Api<Pod>,
-    pod_name: &str,
-    port: u16,
+    direction: Direction,
+    upstream: Upstream,
     mut client: impl AsyncRead + AsyncWrite + Unpin,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<(u64, u64)> {
     info!("forwarding started");
 
-    let mut forwarder = pod_api.portforward(pod_name, &[port]).await?;
-    let mut upstream = forwarder
-        .take_stream(port)
-        .context("port not found in forwarder")?;
+    let Upstream {
+        forwarder,
+        stream: mut upstream,
+    } = upstream;
 
-    let (up, down) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    let (up, down) = direction::copy(direction, &mut client, &mut upstream).await?;
 
     forwarder.join().await.context("forwarder join error")?;
 
-    info!(
-        up = format!("{0:#}", byte_unit::Byte::from_u64(up)),
-        down = format!("{0:#}", byte_unit::Byte::from_u64(down)),
-        "forwarding finished"
-    );
-
-    Ok(())
+    Ok((up, down))
 }
 
 async fn _forward_connection_with_unready(
     pod_api: &Api<Pod>,
     pod_name: &str,
-    port: u16,
+    args: &ControlArgs,
+    upstream: Upstream,
     mut client: impl AsyncRead + AsyncWrite + Unpin,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<(u64, u64)> {
     info!("forwarding started");
 
-    let mut forwarder = pod_api.portforward(pod_name, &[port]).await?;
-    let mut upstream = forwarder
-        .take_stream(port)
-        .context("port not found in forwarder")?;
+    let selection = PodSelection::from_args(args);
+    let conceal: Vec<_> = args.conceal_error.iter().map(ConcealedError::kind).collect();
+    let graceful_close = args.graceful_close.map(Duration::from_secs);
+
+    let Upstream {
+        forwarder,
+        stream: mut upstream,
+    } = upstream;
 
     let (abort_handle, abort_registration) = AbortHandle::new_pair();
 
-    let unready = wait_for_unready(pod_api.clone(), pod_name, abort_registration.handle());
+    let grace_period = args.unready_grace_period.map(Duration::from_secs);
+    let unready = wait_for_unready(pod_api.clone(), pod_name, &selection, grace_period, abort_registration.handle());
 
-    let mut cancelable_upstream = CancelableReadWrite::new(&mut upstream, &abort_registration);
-    let mut cancelable_client = CancelableReadWrite::new(&mut client, &abort_registration);
+    let mut cancelable_upstream =
+        CancelableReadWrite::new(&mut upstream, &abort_registration, &conceal).graceful(graceful_close.is_some());
+    let mut cancelable_client =
+        CancelableReadWrite::new(&mut client, &abort_registration, &conceal).graceful(graceful_close.is_some());
 
-    let copy = tokio::io::copy_bidirectional(&mut cancelable_client, &mut cancelable_upstream);
+    let copied = {
+        let copy = direction::copy(args.direction, &mut cancelable_client, &mut cancelable_upstream);
 
-    pin!(unready);
-    pin!(copy);
+        pin!(unready);
+        pin!(copy);
 
-    let (up, down) = match futures::future::select(copy, unready).await {
-        Either::Left((left, _)) => {
-            abort_handle.abort();
-            left.context("copy_bidirectional")?
-        }
-        Either::Right((right, left)) => {
-            abort_handle.abort();
+        match futures::future::select(copy, unready).await {
+            Either::Left((left, _)) => {
+                abort_handle.abort();
+                left
+            }
+            Either::Right((right, left)) => {
+                abort_handle.abort();
+
+                right.context("wait_for_unready")?;
 
-            right.context("wait_for_unready")?;
+                info!("closing connection due to pod transitioning to unready");
 
-            info!("closing connection due to pod transitioning to unready");
+                match graceful_close {
+                    Some(timeout) => tokio::time::timeout(timeout, left)
+                        .await
+                        .context("timed out closing the connection gracefully")?,
+                    None => left.await,
+                }
+            }
+        }
+    };
 
-            left.await?
+    // A concealed error ends its stream by refusing further writes, which is a clean close
+    let (up, down) = match copied {
+        Err(e)
+            if e.kind() == std::io::ErrorKind::WriteZero
+                && (cancelable_client.is_finished() || cancelable_upstream.is_finished()) =>
+        {
+            (cancelable_upstream.written(), cancelable_client.written())
         }
+        copied => copied.context("copy")?,
     };
 
     forwarder.join().await.context("forwarder join error")?;
 
-    info!(
-        up = format!("{0:#}", byte_unit::Byte::from_u64(up)),
-        down = format!("{0:#}", byte_unit::Byte::from_u64(down)),
-        "forwarding finished"
-    );
+    Ok((up, down))
+}
 
-    Ok(())
+
+/// Forwards like [`_forward_connection_with_unready`], except that when the pod becomes unready
+/// the connection is moved to another eligible pod instead of being closed, for --on-unready
+/// reselect. The client stays connected throughout, though anything in flight to or from the
+/// old pod is lost. `reselected` is told of each pod moved to.
+#[allow(clippy::too_many_arguments)]
+async fn _forward_connection_reselecting(
+    resolved: &Resolved,
+    args: &ControlArgs,
+    selection: &PodSelection,
+    pod_name: &str,
+    mut upstream: Upstream,
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    stalls: &Arc<Stalls>,
+    mut reselected: impl FnMut(&str, u16),
+) -> anyhow::Result<(u64, u64)> {
+    info!("forwarding started");
+
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let up = AtomicU64::new(0);
+    let down = AtomicU64::new(0);
+    let last_activity = Mutex::new(Instant::now());
+    let mut pod_name = pod_name.to_owned();
+
+    loop {
+        let Upstream { forwarder, stream } = upstream;
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(stream);
+
+        // Each direction is counted as it goes, as a move drops the copy part way through
+        let relay = async {
+            let (mut unsent_up, mut unsent_down) = (Vec::new(), Vec::new());
+            tokio::try_join!(
+                copy_tracked(&mut client_read, &mut upstream_write, &up, &last_activity, &mut unsent_up, || true),
+                copy_tracked(&mut upstream_read, &mut client_write, &down, &last_activity, &mut unsent_down, || true),
+            )
+        };
+        // Stops with the copy, so never needs aborting
+        let (_, registration) = AbortHandle::new_pair();
+        let grace_period = args.unready_grace_period.map(Duration::from_secs);
+        let unready = wait_for_unready(
+            resolved.pod_api.clone(),
+            &pod_name,
+            selection,
+            grace_period,
+            registration.handle(),
+        );
+
+        tokio::select! {
+            result = relay => {
+                result.context("copy")?;
+                forwarder.join().await.context("forwarder join error")?;
+                break;
+            }
+            result = unready => result.context("wait_for_unready")?,
+        }
+
+        forwarder.abort();
+        info!("pod transitioned to unready, moving connection to another pod");
+
+        // The watch may not have seen the pod go unready yet
+        let mut others = selection.clone();
+        others.excluded.insert(pod_name.clone());
+        let (pod, choice) = resolved.find_pod(&others).await?;
+        let port = find_pod_port(&resolved.pod_port, &pod)?;
+        pod_name = pod.metadata.name.unwrap_or_default();
+        choice.record(&Span::current(), &pod_name, port, false);
+
+        upstream = open_upstream(&resolved.pod_api, &pod_name, port)
+            .await?
+            .with_stall_guard(args.stall_timeout.map(Duration::from_secs), stalls);
+        reselected(&pod_name, port);
+
+        info!(pod_name, pod_port = port, "moved connection");
+    }
+
+    Ok((up.into_inner(), down.into_inner()))
+}
+
+/// How a pod is chosen from those matching a service's selector.
+#[derive(Clone, Debug, Default)]
+pub struct PodSelection {
+    pub ignore_readiness: bool,
+    pub strict_ready: bool,
+    pub randomise: bool,
+    pub policy: PodPolicy,
+    /// What --policy picks pods by
+    pub balance: Arc<PodBalance>,
+    pub select_where: Vec<PodPredicate>,
+    pub owner: Option<PodOwner>,
+    pub ready_labels: Vec<PodPredicate>,
+    pub newest_revision: bool,
+    /// Pods never picked, eg. having already failed to open a port-forward
+    pub excluded: BTreeSet<String>,
+    /// For --sticky, the client address whose pod to pick
+    pub sticky: Option<IpAddr>,
 }
 
+impl PodSelection {
+    pub fn from_args(args: &ControlArgs) -> Self {
+        Self {
+            ignore_readiness: args.ignore_readiness,
+            strict_ready: args.strict_ready,
+            randomise: args.randomise,
+            policy: args.policy,
+            balance: Arc::default(),
+            select_where: args.select_where.clone(),
+            owner: args.owner.clone(),
+            ready_labels: args.ready_label.clone(),
+            newest_revision: args.newest_revision,
+            excluded: BTreeSet::new(),
+            sticky: None,
+        }
+    }
 
-async fn find_pod(api: &Api<Pod>, selector: &ListParams, ignore_readiness: bool, randomise: bool) -> anyhow::Result<Pod> {
+    /// As [`PodSelection::from_args`], balancing the pods picked with the forward's other
+    /// connections.
+    pub fn for_target(args: &ControlArgs, resolved: &Resolved) -> Self {
+        Self {
+            balance: resolved.balance.clone(),
+            ..Self::from_args(args)
+        }
+    }
+
+    /// Whether connections may be forwarded to the pod.
+    pub fn is_eligible(&self, pod: &Pod) -> bool {
+        self.is_ready(pod) && self.meets_conditions(pod)
+    }
+
+    /// Whether the pod counts as ready, both when selecting it and for --close-on-unready. Every
+    /// criterion must hold: the `Ready` condition (or the --strict-ready checks) unless readiness
+    /// is ignored, and each --ready-label.
+    pub fn is_ready(&self, pod: &Pod) -> bool {
+        let ready = match (self.ignore_readiness, self.strict_ready) {
+            (true, _) => true,
+            (false, true) => is_pod_serveable(pod),
+            (false, false) => is_pod_ready(pod),
+        };
+
+        ready && self.ready_labels.iter().all(|l| l.matches(pod))
+    }
+
+    fn meets_conditions(&self, pod: &Pod) -> bool {
+        self.owner.as_ref().is_none_or(|o| o.owns(pod)) && self.select_where.iter().all(|p| p.matches(pod))
+    }
+}
+
+/// Why a pod was picked, recorded on the `pod` span so the choice is explained alongside
+/// everything logged for the connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PodChoice {
+    /// `first`, `random`, `round-robin`, `least-conn`, `sticky` or `via-pod`
+    pub strategy: &'static str,
+    /// Pods matching the selector
+    pub candidates: usize,
+    /// Candidates passing the readiness checks and --select-where conditions
+    pub eligible: usize,
+    /// Index of the chosen pod among the eligible pods
+    pub index: usize,
+}
+
+impl PodChoice {
+    /// The --via-pod bastion, which is always used
+    pub const VIA_POD: Self = Self {
+        strategy: "via-pod",
+        candidates: 1,
+        eligible: 1,
+        index: 0,
+    };
+
+    /// Records the choice of `pod_name` on `span`, a [`pod_span`], replacing any pod recorded
+    /// before it. `prewarmed` when the stream was opened ahead of time.
+    pub fn record(&self, span: &Span, pod_name: &str, pod_port: u16, prewarmed: bool) {
+        span.record("pod_name", pod_name);
+        span.record("pod_port", pod_port);
+        span.record("strategy", self.strategy);
+        span.record("candidates", self.candidates);
+        span.record("eligible", self.eligible);
+        span.record("index", self.index);
+        span.record("prewarmed", prewarmed);
+    }
+}
+
+/// The span of a connection forwarded to a pod, with the pod and why it was picked left to be
+/// recorded by [`PodChoice::record`].
+fn pod_span() -> Span {
+    info_span!(
+        "pod",
+        pod_name = Empty,
+        pod_port = Empty,
+        strategy = Empty,
+        candidates = Empty,
+        eligible = Empty,
+        index = Empty,
+        prewarmed = Empty
+    )
+}
+
+impl std::fmt::Display for PodChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} index {} of {} eligible ({} matching)",
+            self.strategy, self.index, self.eligible, self.candidates
+        )
+    }
+}
+
+/// Forwards like [`_forward_connection`], except that when the pod side of the connection
+/// closes after it has been idle for at least `idle` (eg. the port-forward channel being timed
+/// out by the API server or a proxy), the client is kept open and a new port-forward is opened
+/// for the next bytes it sends. Bytes read from the client but not yet written when the pod
+/// side closed are sent to the new port-forward first.
+async fn _forward_connection_reconnecting(
+    resolved: &Resolved,
+    args: &ControlArgs,
+    selection: &PodSelection,
+    mut upstream: Upstream,
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    stalls: &Arc<Stalls>,
+    idle: Duration,
+) -> anyhow::Result<(u64, u64)> {
+    info!("forwarding started");
+
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let up = AtomicU64::new(0);
+    let down = AtomicU64::new(0);
+    let last_activity = Mutex::new(Instant::now());
+    let mut unsent = Vec::new();
+
+    loop {
+        let Upstream { forwarder, stream } = upstream;
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(stream);
+
+        let client_done = AtomicBool::new(false);
+        let idle_closed = {
+            let is_idle = || {
+                !client_done.load(Ordering::Relaxed) && last_activity.lock().unwrap().elapsed() >= idle
+            };
+
+            // Only what the client sends is carried over to the new port-forward
+            let mut unsent_down = Vec::new();
+            let to_upstream =
+                copy_tracked(&mut client_read, &mut upstream_write, &up, &last_activity, &mut unsent, || true);
+            let to_client = copy_tracked(
+                &mut upstream_read,
+                &mut client_write,
+                &down,
+                &last_activity,
+                &mut unsent_down,
+                || !is_idle(),
+            );
+            pin!(to_upstream);
+            pin!(to_client);
+
+            loop {
+                tokio::select! {
+                    result = &mut to_upstream, if !client_done.load(Ordering::Relaxed) => {
+                        result?;
+                        client_done.store(true, Ordering::Relaxed);
+                    }
+                    result = &mut to_client => {
+                        if is_idle() {
+                            break true;
+                        }
+                        result?;
+                        if !client_done.load(Ordering::Relaxed) {
+                            to_upstream.await?;
+                        }
+                        break false;
+                    }
+                }
+            }
+        };
+
+        if !idle_closed {
+            forwarder.join().await.context("forwarder join error")?;
+            break;
+        }
+
+        forwarder.abort();
+        info!("pod side of idle connection closed, reconnecting on next use");
+
+        if unsent.is_empty() {
+            let mut buf = vec![0u8; 8192];
+            let n = client_read.read(&mut buf).await?;
+            if n == 0 {
+                client_write.shutdown().await?;
+                break;
+            }
+            unsent.extend_from_slice(&buf[..n]);
+        }
+
+        let (pod, choice) = resolved.find_pod(selection).await?;
+        let port = find_pod_port(&resolved.pod_port, &pod)?;
+        let pod_name = pod.metadata.name.unwrap_or_default();
+        choice.record(&Span::current(), &pod_name, port, false);
+
+        upstream = open_upstream(&resolved.pod_api, &pod_name, port)
+            .await?
+            .with_stall_guard(args.stall_timeout.map(Duration::from_secs), stalls);
+        upstream.stream.write_all(&unsent).await?;
+        up.fetch_add(unsent.len() as u64, Ordering::Relaxed);
+        unsent.clear();
+        *last_activity.lock().unwrap() = Instant::now();
+
+        info!(pod_name, pod_port = port, "reconnected");
+    }
+
+    Ok((up.into_inner(), down.into_inner()))
+}
+
+/// Copies until `reader` ends, counting the bytes into `total` and noting when any were last
+/// read. `writer` is shut down at the end when `shutdown` returns true.
+///
+/// Bytes are read into `unsent` and only taken out once written, so if the copy is dropped part
+/// way through, what was read but not written is left there.
+async fn copy_tracked(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    total: &AtomicU64,
+    last_activity: &Mutex<Instant>,
+    unsent: &mut Vec<u8>,
+    shutdown: impl Fn() -> bool,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; 8192];
+
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            // A dropped channel may surface as an error rather than the end of the stream
+            Err(_) if !shutdown() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        *last_activity.lock().unwrap() = Instant::now();
+        unsent.extend_from_slice(&buf[..n]);
+        writer.write_all(unsent).await?;
+        unsent.clear();
+        total.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    if shutdown() {
+        writer.shutdown().await?;
+    }
+
+    Ok(())
+}
+
+pub async fn find_pod(
+    api: &Api<Pod>,
+    selector: &ListParams,
+    selection: &PodSelection,
+) -> anyhow::Result<(Pod, PodChoice)> {
     let items = api.list(selector).await?.items;
-    let length = items.len();
 
-    let mut valid = items
+    pick_pod(api, items, selection).await
+}
+
+/// Picks a pod from those matching the selector, already listed, eg. by a
+/// [`PodCache`](crate::pod_cache::PodCache).
+pub async fn pick_pod(
+    api: &Api<Pod>,
+    mut items: Vec<Pod>,
+    selection: &PodSelection,
+) -> anyhow::Result<(Pod, PodChoice)> {
+    if selection.newest_revision {
+        items = newest_revision(api, items, selection).await;
+    }
+
+    Ok(select_pod(items, selection)?)
+}
+
+/// Narrows the pods to those of the newest ReplicaSet owning any of the eligible pods, for
+/// --newest-revision. Pods are left as they are when none of the eligible ones are owned by a
+/// ReplicaSet, or the ReplicaSets can't be looked up.
+async fn newest_revision(api: &Api<Pod>, items: Vec<Pod>, selection: &PodSelection) -> Vec<Pod> {
+    let owners: BTreeSet<&str> = items
+        .iter()
+        .filter(|p| selection.is_eligible(p))
+        .filter_map(replica_set_owner)
+        .collect();
+
+    let newest = match owners.len() {
+        0 => {
+            debug!("no eligible pods are owned by a ReplicaSet, ignoring --newest-revision");
+            return items;
+        }
+        1 => owners.first().map(|o| o.to_string()),
+        _ => {
+            let namespace = items[0].metadata.namespace.clone().unwrap_or_default();
+            let rs_api: Api<ReplicaSet> = Api::namespaced(api.clone().into_client(), &namespace);
+
+            let mut replica_sets = vec![];
+            for owner in &owners {
+                match rs_api.get(owner).await {
+                    Ok(rs) => replica_sets.push(rs),
+                    Err(e) => {
+                        warn!(
+                            error = &e as &dyn std::error::Error,
+                            replica_set = owner,
+                            "failed to look up ReplicaSet, ignoring --newest-revision"
+                        );
+                        return items;
+                    }
+                }
+            }
+
+            newest_replica_set(&replica_sets).map(str::to_owned)
+        }
+    };
+
+    let Some(newest) = newest else {
+        return items;
+    };
+    debug!(replica_set = newest, "using pods of the newest revision");
+
+    items
         .into_iter()
-        .filter(|p| {
-            ignore_readiness ||
-            p.status.as_ref().map_or(false, |s| {
-                s.conditions.as_ref().map_or(false, |cs| {
-                    cs.iter().any(|c| c.type_ == "Ready" && c.status == "True")
-                })
-            })
+        .filter(|p| replica_set_owner(p) == Some(newest.as_str()))
+        .collect()
+}
+
+/// The name of the ReplicaSet controlling the pod, if any.
+fn replica_set_owner(pod: &Pod) -> Option<&str> {
+    pod.metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .find(|o| o.kind == "ReplicaSet" && o.controller == Some(true))
+        .map(|o| o.name.as_str())
+}
+
+/// Picks the newest ReplicaSet, by the deployment revision it was created for, or when that's
+/// missing by when it was created.
+fn newest_replica_set(replica_sets: &[ReplicaSet]) -> Option<&str> {
+    replica_sets
+        .iter()
+        .max_by_key(|rs| {
+            let revision = rs
+                .metadata
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get("deployment.kubernetes.io/revision"))
+                .and_then(|r| r.parse::<u64>().ok());
+            (revision, rs.metadata.creation_timestamp.clone())
+        })
+        .and_then(|rs| rs.metadata.name.as_deref())
+}
+
+/// Picks the pod to forward to from the pods matching the selector.
+///
+/// Distinguishes between nothing matching the selector at all, pods matching but not the
+/// --select-where conditions, and pods matching but none of them being ready yet (eg. while
+/// they are still starting).
+fn select_pod(items: Vec<Pod>, selection: &PodSelection) -> Result<(Pod, PodChoice), MyError> {
+    let candidates = items.len();
+
+    if items.is_empty() {
+        return Err(MyError::NoPodsMatchSelector());
+    }
+
+    if let Some(owner) = &selection.owner {
+        if !items.iter().any(|p| owner.owns(p)) {
+            return Err(MyError::NoPodsWithOwner(owner.to_string()));
+        }
+    }
+
+    if !items.iter().any(|p| selection.meets_conditions(p)) {
+        return Err(MyError::NoPodsMatchSelectWhere());
+    }
+
+    let mut valid: Vec<Pod> = items
+        .into_iter()
+        .filter(|p| selection.is_eligible(p))
+        .filter(|p| !p.metadata.name.as_ref().is_some_and(|n| selection.excluded.contains(n)))
+        .collect();
+
+    if valid.is_empty() {
+        return Err(match &selection.owner {
+            Some(owner) => MyError::NoReadyPodsWithOwner(owner.to_string()),
+            None => MyError::MatchingReadyPodNotFound(),
         });
+    }
 
-    let count = match randomise {
-        true => rand::thread_rng().gen_range(0..length),
-        false => 0,
+    let (strategy, index) = match (selection.sticky, selection.randomise, selection.policy) {
+        (Some(peer), _, _) => ("sticky", selection.balance.pinned(&valid, peer)),
+        (None, true, _) => ("random", random_index(valid.len())),
+        (None, false, PodPolicy::First) => ("first", 0),
+        (None, false, PodPolicy::RoundRobin) => ("round-robin", selection.balance.next(&valid, selection.policy)),
+        (None, false, PodPolicy::LeastConn) => ("least-conn", selection.balance.next(&valid, selection.policy)),
+    };
+    let choice = PodChoice {
+        strategy,
+        candidates,
+        eligible: valid.len(),
+        index,
     };
 
-    valid
-        .nth(count)
-        .ok_or_else(|| MyError::MatchingReadyPodNotFound().into())
+    Ok((valid.swap_remove(index), choice))
+}
+
+/// The generator used by --randomise once seeded with --seed, shared by every forward
+static SEEDED_RNG: OnceLock<Mutex<StdRng>> = OnceLock::new();
+
+/// Makes --randomise pick pods from a generator seeded with `seed`, for reproducible runs.
+pub fn seed(seed: u64) {
+    let _ = SEEDED_RNG.set(Mutex::new(StdRng::seed_from_u64(seed)));
+}
+
+fn random_index(len: usize) -> usize {
+    match SEEDED_RNG.get() {
+        Some(rng) => rng.lock().unwrap().gen_range(0..len),
+        None => rand::thread_rng().gen_range(0..len),
+    }
+}
+
+/// Counts the pods matching the selector, returning `(ready, total)`.
+pub async fn count_pods(
+    api: &Api<Pod>,
+    selector: &ListParams,
+    selection: &PodSelection,
+) -> anyhow::Result<(usize, usize)> {
+    let items = api.list(selector).await?.items;
+    let ready = items.iter().filter(|p| selection.is_ready(p)).count();
+
+    Ok((ready, items.len()))
+}
+
+/// Returns true when the pod is reporting the `Ready` condition as `True`.
+pub fn is_pod_ready(pod: &Pod) -> bool {
+    pod.status.as_ref().is_some_and(|s| {
+        s.conditions.as_ref().is_some_and(|cs| {
+            cs.iter().any(|c| c.type_ == "Ready" && c.status == "True")
+        })
+    })
+}
+
+/// Stricter than [`is_pod_ready`], also requiring the pod to be `Running`, have been assigned
+/// an IP and not be terminating.
+pub fn is_pod_serveable(pod: &Pod) -> bool {
+    is_pod_ready(pod)
+        && pod.metadata.deletion_timestamp.is_none()
+        && pod.status.as_ref().is_some_and(|s| {
+            s.phase.as_deref() == Some("Running") && s.pod_ip.as_ref().is_some_and(|ip| !ip.is_empty())
+        })
 }
 
 const EMPTY_CONTAINER_LIST: &Vec<ContainerPort> = &vec![];
 
-fn find_pod_port(pod_port: &IntOrString, pod: &Pod) -> Result<u16, MyError> {
+pub fn find_pod_port(pod_port: &IntOrString, pod: &Pod) -> Result<u16, MyError> {
     match pod_port {
+        IntOrString::Int(i) if *i <= 0 => Err(MyError::InvalidTargetPort(*i)),
         IntOrString::Int(i) => match u16::try_from(*i) {
             Ok(t) => Ok(t),
             Err(_) => Err(MyError::CouldNotFindPort(pod_port.clone())),
@@ -183,9 +1072,25 @@ fn find_pod_port(pod_port: &IntOrString, pod: &Pod) -> Result<u16, MyError> {
     }
 }
 
+/// Fires --on-event as the pods seen by a forward's pod watch change, when the last ready pod
+/// goes away or a first one becomes ready again.
+pub fn availability_hook(selection: PodSelection, target: String) -> OnChange {
+    let mut availability = Availability::default();
+
+    Box::new(move |pods| {
+        let ready = pods.iter().filter(|p| selection.is_ready(p)).count();
+        if let Some(event) = availability.update(ready) {
+            info!(ready_pods = ready, event = event.as_str(), "pod availability changed");
+            hook::fire(event, &target, Some(ready));
+        }
+    })
+}
+
 async fn wait_for_unready(
     api: Api<Pod>,
     name: &str,
+    selection: &PodSelection,
+    grace_period: Option<Duration>,
     abort_handle: AbortHandle,
 ) -> anyhow::Result<()> {
     //let mut stream  = watch_object(api, name.as_str());
@@ -195,21 +1100,546 @@ async fn wait_for_unready(
     )
     .applied_objects();
 
-    pin!(stream);
+    until_unready(stream, selection, grace_period, abort_handle).await
+}
+
+/// Waits for the pod to be seen unready in `updates`. With --unready-grace-period, it must then
+/// stay unready for `grace_period`, and becoming ready again in that time starts the wait over,
+/// so pods only briefly failing a probe keep their connections.
+async fn until_unready<E>(
+    updates: impl Stream<Item = Result<Pod, E>>,
+    selection: &PodSelection,
+    grace_period: Option<Duration>,
+    abort_handle: AbortHandle,
+) -> anyhow::Result<()>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    pin!(updates);
+    // Until when the pod is given to become ready again
+    let mut deadline: Option<tokio::time::Instant> = None;
 
-    while let Some(pod) = stream.try_next().await? {
+    loop {
+        let update = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, updates.try_next()).await {
+                Ok(update) => update?,
+                Err(_) => {
+                    info!("pod still unready after the grace period");
+                    break;
+                }
+            },
+            None => updates.try_next().await?,
+        };
+        let Some(pod) = update else {
+            break;
+        };
         if abort_handle.is_aborted() {
             break;
         }
-        if let Some(status) = pod.status {
-            let is_ready = status.conditions.as_ref().map_or(false, |cs| {
-                cs.iter().any(|c| c.type_ == "Ready" && c.status == "True")
-            });
-            if !is_ready {
-                break;
+
+        match (pod.status.is_some() && !selection.is_ready(&pod), grace_period, deadline) {
+            (true, None, _) => break,
+            (true, Some(grace_period), None) => {
+                info!(
+                    grace_period_secs = grace_period.as_secs(),
+                    "pod transitioned to unready, waiting for it to become ready again"
+                );
+                deadline = Some(tokio::time::Instant::now() + grace_period);
+            }
+            (false, _, Some(_)) => {
+                info!("pod ready again within the grace period");
+                deadline = None;
             }
+            _ => {}
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::{
+        api::core::v1::{PodCondition, PodStatus},
+        apimachinery::pkg::apis::meta::v1::{OwnerReference, Time},
+        chrono,
+    };
+    use kube::api::ObjectMeta;
+
+    #[test]
+    fn forbidden_port_forwards() {
+        let upgrade = |status| {
+            kube::Error::UpgradeConnection(kube::client::UpgradeConnectionError::ProtocolSwitch(status))
+        };
+
+        assert!(is_forbidden(&upgrade(http::StatusCode::FORBIDDEN)));
+        assert!(!is_forbidden(&upgrade(http::StatusCode::NOT_FOUND)));
+        assert!(is_forbidden(&kube::Error::Api(kube::core::ErrorResponse {
+            status: "Failure".to_owned(),
+            message: "pods \"web-0\" is forbidden".to_owned(),
+            reason: "Forbidden".to_owned(),
+            code: 403,
+        })));
+    }
+
+    fn pod(name: &str, ready: Option<bool>) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_owned()),
+                ..Default::default()
+            },
+            status: ready.map(|r| PodStatus {
+                conditions: Some(vec![PodCondition {
+                    type_: "Ready".to_owned(),
+                    status: if r { "True" } else { "False" }.to_owned(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn selection(ignore_readiness: bool, randomise: bool) -> PodSelection {
+        PodSelection {
+            ignore_readiness,
+            randomise,
+            ..Default::default()
+        }
+    }
+
+    fn owned_pod(name: &str, replica_set: &str) -> Pod {
+        let mut pod = pod(name, Some(true));
+        pod.metadata.owner_references = Some(vec![OwnerReference {
+            kind: "ReplicaSet".to_owned(),
+            name: replica_set.to_owned(),
+            controller: Some(true),
+            ..Default::default()
+        }]);
+        pod
+    }
+
+    fn replica_set(name: &str, revision: Option<&str>, created: i64) -> ReplicaSet {
+        ReplicaSet {
+            metadata: ObjectMeta {
+                name: Some(name.to_owned()),
+                annotations: revision.map(|r| [("deployment.kubernetes.io/revision".to_owned(), r.to_owned())].into()),
+                creation_timestamp: Some(Time(chrono::DateTime::from_timestamp(created, 0).unwrap())),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replica_set_owners() {
+        assert_eq!(replica_set_owner(&owned_pod("a", "web-5d4f9")), Some("web-5d4f9"));
+        assert_eq!(replica_set_owner(&pod("b", Some(true))), None);
+    }
+
+    #[test]
+    fn newest_replica_set_by_revision_then_age() {
+        // revision 10 is newer than 9, whatever the timestamps say
+        let by_revision = [replica_set("old", Some("9"), 200), replica_set("new", Some("10"), 100)];
+        assert_eq!(newest_replica_set(&by_revision), Some("new"));
+
+        let by_age = [replica_set("new", None, 200), replica_set("old", None, 100)];
+        assert_eq!(newest_replica_set(&by_age), Some("new"));
+        assert_eq!(newest_replica_set(&[]), None);
+    }
+
+    #[test]
+    fn select_first_ready() {
+        let pods = vec![pod("a", Some(false)), pod("b", Some(true)), pod("c", Some(true))];
+
+        let (selected, _) = select_pod(pods, &selection(false, false)).unwrap();
+
+        assert_eq!(selected.metadata.name.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn select_random_ready() {
+        for _ in 0..20 {
+            let pods = vec![pod("a", Some(false)), pod("b", Some(true)), pod("c", None)];
+
+            let (selected, _) = select_pod(pods, &selection(false, true)).unwrap();
+
+            assert_eq!(selected.metadata.name.as_deref(), Some("b"));
+        }
+    }
+
+    #[test]
+    fn select_round_robin() {
+        let selection = PodSelection {
+            policy: PodPolicy::RoundRobin,
+            ..Default::default()
+        };
+        let pick = |pods: Vec<Pod>| {
+            let (selected, choice) = select_pod(pods, &selection).unwrap();
+            assert_eq!(choice.strategy, "round-robin");
+            selected.metadata.name.unwrap()
+        };
+        let pods = vec![pod("c", Some(true)), pod("a", Some(true)), pod("b", Some(false)), pod("b2", Some(true))];
+
+        let picked: Vec<_> = (0..4).map(|_| pick(pods.clone())).collect();
+        assert_eq!(picked, ["a", "b2", "c", "a"]);
+
+        // Carries on after the last pick even once it has gone
+        let pods = vec![pod("a", Some(true)), pod("c", Some(true))];
+        assert_eq!(pick(pods.clone()), "c");
+        assert_eq!(pick(vec![pod("b", Some(true)), pod("d", Some(true))]), "d");
+    }
+
+    #[test]
+    fn select_least_connections() {
+        let selection = PodSelection {
+            policy: PodPolicy::LeastConn,
+            ..Default::default()
+        };
+        let pods = vec![pod("a", Some(true)), pod("b", Some(true)), pod("c", Some(true))];
+        let pick = || {
+            let (selected, choice) = select_pod(pods.clone(), &selection).unwrap();
+            assert_eq!(choice.strategy, "least-conn");
+            selected.metadata.name.unwrap()
+        };
+
+        let a = [selection.balance.connected("a"), selection.balance.connected("a")];
+        let b = selection.balance.connected("b");
+        assert_eq!(pick(), "c");
+        let c = selection.balance.connected("c");
+        // Tied on one connection each, b and c are picked in turn
+        assert_eq!(pick(), "b");
+        assert_eq!(pick(), "c");
+
+        drop(a);
+        assert_eq!(pick(), "a");
+        drop((b, c));
+        assert!(selection.balance.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn select_sticky() {
+        let client = |peer: &str| PodSelection {
+            sticky: Some(peer.parse().unwrap()),
+            ..Default::default()
+        };
+        let pick = |pods: &[Pod], selection: &PodSelection| {
+            let (selected, choice) = select_pod(pods.to_vec(), selection).unwrap();
+            assert_eq!(choice.strategy, "sticky");
+            selected.metadata.name.unwrap()
+        };
+        let pods: Vec<_> = ["a", "b", "c", "d"].into_iter().map(|n| pod(n, Some(true))).collect();
+
+        let first = client("192.0.2.1");
+        let pinned = pick(&pods, &first);
+        assert_eq!(pick(&pods, &first), pinned);
+        let others: BTreeSet<_> = (2..50).map(|i| pick(&pods, &client(&format!("192.0.2.{i}")))).collect();
+        assert!(others.len() > 1, "clients should be spread over the pods");
+
+        // Kept while the pod stays eligible, even once another pod would hash higher
+        let mut more = pods.clone();
+        more.extend(["e", "f", "g", "h"].map(|n| pod(n, Some(true))));
+        assert_eq!(pick(&more, &first), pinned);
+
+        // Moved only once it isn't
+        let pinned_unready: Vec<_> = more
+            .iter()
+            .map(|p| p.metadata.name.as_deref().unwrap())
+            .map(|name| pod(name, Some(name != pinned)))
+            .collect();
+        let moved = pick(&pinned_unready, &first);
+        assert_ne!(moved, pinned);
+        assert_eq!(pick(&more, &first), moved);
+    }
+
+    #[tokio::test]
+    async fn unready_grace_period() {
+        let (updates, rx) = tokio::sync::mpsc::unbounded_channel::<Result<Pod, std::io::Error>>();
+        let (_, registration) = AbortHandle::new_pair();
+        let grace_period = Duration::from_millis(100);
+        let selection = PodSelection::default();
+        let unready = until_unready(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(rx),
+            &selection,
+            Some(grace_period),
+            registration.handle(),
+        );
+        pin!(unready);
+
+        // Ready again within the grace period
+        updates.send(Ok(pod("a", Some(false)))).unwrap();
+        updates.send(Ok(pod("a", Some(true)))).unwrap();
+        assert!(tokio::time::timeout(grace_period * 2, &mut unready).await.is_err());
+
+        let started = Instant::now();
+        updates.send(Ok(pod("a", Some(false)))).unwrap();
+        unready.await.unwrap();
+        assert!(started.elapsed() >= grace_period);
+    }
+
+    #[test]
+    fn select_explains_choice() {
+        let pods = vec![pod("a", Some(false)), pod("b", Some(true)), pod("c", Some(true))];
+
+        let (_, choice) = select_pod(pods, &selection(false, false)).unwrap();
+
+        assert_eq!(
+            choice,
+            PodChoice {
+                strategy: "first",
+                candidates: 3,
+                eligible: 2,
+                index: 0,
+            }
+        );
+        assert_eq!(choice.to_string(), "first index 0 of 2 eligible (3 matching)");
+
+        let pods = vec![pod("a", Some(true)), pod("b", Some(true))];
+        let (selected, choice) = select_pod(pods, &selection(false, true)).unwrap();
+        let expected = ["a", "b"][choice.index];
+
+        assert_eq!(choice.strategy, "random");
+        assert_eq!(selected.metadata.name.as_deref(), Some(expected));
+    }
+
+    #[test]
+    fn select_no_pods() {
+        let err = select_pod(vec![], &selection(false, false)).unwrap_err();
+
+        assert!(matches!(err, MyError::NoPodsMatchSelector()));
+    }
+
+    #[test]
+    fn select_no_ready_pods() {
+        let pods = vec![pod("a", Some(false)), pod("b", None)];
+
+        let err = select_pod(pods, &selection(false, true)).unwrap_err();
+
+        assert!(matches!(err, MyError::MatchingReadyPodNotFound()));
+    }
+
+    #[test]
+    fn select_ignoring_readiness() {
+        let pods = vec![pod("a", None)];
+
+        let (selected, _) = select_pod(pods, &selection(true, false)).unwrap();
+
+        assert_eq!(selected.metadata.name.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn select_where_excludes_all() {
+        let pods = vec![pod("a", Some(true))];
+        let selection = PodSelection {
+            select_where: vec![PodPredicate::parse("label.track == canary").unwrap()],
+            ..Default::default()
+        };
+
+        let err = select_pod(pods, &selection).unwrap_err();
+
+        assert!(matches!(err, MyError::NoPodsMatchSelectWhere()));
+    }
+
+    #[test]
+    fn owner_filters() {
+        let mut unready = owned_pod("c", "web-7c8b2");
+        unready.status = pod("c", Some(false)).status;
+        let pods = vec![owned_pod("a", "web-5d4f9"), owned_pod("b", "web-7c8b2"), unready];
+        let owned_by = |owner: &str| PodSelection {
+            owner: Some(PodOwner::parse(owner).unwrap()),
+            ..Default::default()
+        };
+
+        let (selected, choice) = select_pod(pods.clone(), &owned_by("ReplicaSet/web-7c8b2")).unwrap();
+        assert_eq!(selected.metadata.name.as_deref(), Some("b"));
+        assert_eq!((choice.candidates, choice.eligible), (3, 1));
+
+        assert!(matches!(
+            select_pod(pods.clone(), &owned_by("ReplicaSet/web-0")),
+            Err(MyError::NoPodsWithOwner(o)) if o == "ReplicaSet/web-0"
+        ));
+        assert!(matches!(
+            select_pod(vec![pods[2].clone()], &owned_by("ReplicaSet/web-7c8b2")),
+            Err(MyError::NoReadyPodsWithOwner(_))
+        ));
+    }
+
+    #[test]
+    fn excluded_pods_are_not_picked() {
+        let pods = vec![pod("a", Some(true)), pod("b", Some(true))];
+        let mut selection = PodSelection {
+            excluded: ["a".to_owned()].into(),
+            ..Default::default()
+        };
+
+        let (selected, _) = select_pod(pods.clone(), &selection).unwrap();
+        assert_eq!(selected.metadata.name.as_deref(), Some("b"));
+
+        selection.excluded.insert("b".to_owned());
+        assert!(matches!(select_pod(pods, &selection), Err(MyError::MatchingReadyPodNotFound())));
+    }
+
+    #[test]
+    fn select_where_filters() {
+        let pods = vec![pod("a", Some(true)), pod("b", Some(true))];
+        let selection = PodSelection {
+            select_where: vec![PodPredicate::parse("metadata.name != a").unwrap()],
+            ..Default::default()
+        };
+
+        let (selected, _) = select_pod(pods, &selection).unwrap();
+
+        assert_eq!(selected.metadata.name.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn target_port_zero() {
+        let err = find_pod_port(&IntOrString::Int(0), &pod("a", None)).unwrap_err();
+
+        assert!(matches!(err, MyError::InvalidTargetPort(0)));
+    }
+
+    #[test]
+    fn target_port_negative() {
+        let err = find_pod_port(&IntOrString::Int(-80), &pod("a", None)).unwrap_err();
+
+        assert!(matches!(err, MyError::InvalidTargetPort(-80)));
+    }
+
+    #[test]
+    fn target_port_number() {
+        assert_eq!(find_pod_port(&IntOrString::Int(8080), &pod("a", None)).unwrap(), 8080);
+    }
+
+    fn serveable_pod() -> Pod {
+        let mut pod = pod("serveable", Some(true));
+        let status = pod.status.as_mut().unwrap();
+        status.phase = Some("Running".to_owned());
+        status.pod_ip = Some("10.0.0.1".to_owned());
+        pod
+    }
+
+    #[test]
+    fn serveable() {
+        assert!(is_pod_serveable(&serveable_pod()));
+    }
+
+    #[test]
+    fn not_serveable_when_unready() {
+        let mut pod = serveable_pod();
+        pod.status.as_mut().unwrap().conditions.as_mut().unwrap()[0].status = "False".to_owned();
+
+        assert!(!is_pod_serveable(&pod));
+    }
+
+    #[test]
+    fn not_serveable_when_not_running() {
+        let mut pod = serveable_pod();
+        pod.status.as_mut().unwrap().phase = Some("Pending".to_owned());
+
+        assert!(!is_pod_serveable(&pod));
+    }
+
+    #[test]
+    fn not_serveable_without_ip() {
+        let mut pod = serveable_pod();
+        pod.status.as_mut().unwrap().pod_ip = Some(String::new());
+
+        assert!(!is_pod_serveable(&pod));
+
+        pod.status.as_mut().unwrap().pod_ip = None;
+
+        assert!(!is_pod_serveable(&pod));
+    }
+
+    #[test]
+    fn not_serveable_when_terminating() {
+        let mut pod = serveable_pod();
+        pod.metadata.deletion_timestamp = Some(k8s_openapi::apimachinery::pkg::apis::meta::v1::Time(
+            k8s_openapi::chrono::Utc::now(),
+        ));
+
+        assert!(!is_pod_serveable(&pod));
+    }
+
+    #[test]
+    fn strict_selection() {
+        let selection = PodSelection {
+            strict_ready: true,
+            ..Default::default()
+        };
+
+        let err = select_pod(vec![pod("a", Some(true))], &selection).unwrap_err();
+        assert!(matches!(err, MyError::MatchingReadyPodNotFound()));
+
+        let (selected, _) = select_pod(vec![pod("a", Some(true)), serveable_pod()], &selection).unwrap();
+        assert_eq!(selected.metadata.name.as_deref(), Some("serveable"));
+    }
+
+    #[test]
+    fn ready_labels_combine_with_ready_condition() {
+        let selection = PodSelection {
+            ready_labels: vec![PodPredicate::parse_label("serving=true").unwrap()],
+            ..Default::default()
+        };
+        let mut serving = pod("a", Some(true));
+        serving.metadata.labels = Some([("serving".to_owned(), "true".to_owned())].into());
+
+        assert!(selection.is_ready(&serving));
+        assert!(!selection.is_ready(&pod("b", Some(true))));
+
+        serving.status = pod("a", Some(false)).status;
+        assert!(!selection.is_ready(&serving));
+
+        let labels_only = PodSelection {
+            ignore_readiness: true,
+            ..selection
+        };
+        assert!(labels_only.is_ready(&serving));
+        assert!(!labels_only.is_ready(&pod("b", None)));
+    }
+
+    #[tokio::test]
+    async fn copy_tracked_counts_and_leaves_open() {
+        let (mut reader, mut source) = tokio::io::duplex(64);
+        let (mut writer, mut sink) = tokio::io::duplex(64);
+        let total = AtomicU64::new(0);
+        let started = Instant::now();
+        let last_activity = Mutex::new(started);
+
+        source.write_all(b"hello").await.unwrap();
+        drop(source);
+
+        copy_tracked(&mut reader, &mut writer, &total, &last_activity, &mut Vec::new(), || false)
+            .await
+            .unwrap();
+
+        assert_eq!(total.load(Ordering::Relaxed), 5);
+        assert!(*last_activity.lock().unwrap() > started);
+
+        // not shut down, so the sink is still open for more
+        writer.write_all(b"!").await.unwrap();
+        let mut buf = [0u8; 6];
+        sink.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello!");
+    }
+
+    #[tokio::test]
+    async fn copy_tracked_keeps_unwritten_bytes() {
+        let (mut reader, mut source) = tokio::io::duplex(64);
+        // Too small for what is read, and never read from, so the write can't finish
+        let (mut writer, _sink) = tokio::io::duplex(4);
+        let total = AtomicU64::new(0);
+        let last_activity = Mutex::new(Instant::now());
+        let mut unsent = Vec::new();
+
+        source.write_all(b"hello world").await.unwrap();
+
+        let copy = copy_tracked(&mut reader, &mut writer, &total, &last_activity, &mut unsent, || true);
+        assert!(tokio::time::timeout(Duration::from_millis(50), copy).await.is_err());
+
+        assert_eq!(unsent, b"hello world");
+        assert_eq!(total.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use futures::{FutureExt, StreamExt};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::ListParams,
+    runtime::{reflector, reflector::Store, watcher, watcher::Config, WatchStreamExt},
+    Api,
+};
+use tokio::task::AbortHandle;
+use tracing::{debug, Instrument};
+
+/// Told of the pods matching the selector each time the watch sees them change, once they have
+/// been listed for the first time.
+pub type OnChange = Box<dyn FnMut(&[Arc<Pod>]) + Send>;
+
+/// The pods matching a target's selector, kept up to date by a watch so connections pick from
+/// them rather than listing the pods for each connection.
+#[derive(Clone)]
+pub struct PodCache {
+    api: Api<Pod>,
+    selector: ListParams,
+    store: Store<Pod>,
+}
+
+/// The watch config selecting the same pods as `selector`.
+pub fn watch_config(selector: &ListParams) -> Config {
+    let config = Config::default().labels(selector.label_selector.as_deref().unwrap_or_default());
+    match &selector.field_selector {
+        Some(fields) => config.fields(fields),
+        None => config,
+    }
+}
+
+impl PodCache {
+    /// Starts watching the pods matching `selector`, returning the cache along with the watch,
+    /// which runs until aborted.
+    pub fn watch(api: Api<Pod>, selector: ListParams, mut on_change: Option<OnChange>) -> (Self, AbortHandle) {
+        let (store, writer) = reflector::store();
+        let stream = reflector(writer, watcher(api.clone(), watch_config(&selector)).default_backoff());
+        let reader = store.clone();
+        let watch = stream.for_each(move |event| {
+            match event {
+                // Wait for the initial listing to finish before telling of the pods
+                Ok(watcher::Event::Init | watcher::Event::InitApply(_)) => {}
+                Ok(_) => {
+                    if let Some(on_change) = &mut on_change {
+                        on_change(&reader.state());
+                    }
+                }
+                Err(e) => debug!(error = &e as &dyn std::error::Error, "pod watch failed, retrying"),
+            }
+            async {}
+        });
+
+        let cache = Self { api, selector, store };
+        (cache, tokio::spawn(watch.in_current_span()).abort_handle())
+    }
+
+    /// The pods as last seen by the watch. Until the watch has listed them for the first time,
+    /// they are listed instead, so the first connections don't wait on it.
+    ///
+    /// The store holds the pods in no particular order, so they are sorted by namespace and name,
+    /// as listing them would, for --policy first and --seed to pick the same pods each time.
+    pub async fn pods(&self) -> kube::Result<Vec<Pod>> {
+        let mut pods = match self.store.wait_until_ready().now_or_never() {
+            Some(Ok(())) => self.store.state().iter().map(|p| Pod::clone(p)).collect(),
+            _ => self.api.list(&self.selector).await?.items,
+        };
+        pods.sort_by(|a, b| {
+            (&a.metadata.namespace, &a.metadata.name).cmp(&(&b.metadata.namespace, &b.metadata.name))
+        });
+
+        Ok(pods)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pod::{pick_pod, PodSelection};
+    use kube::{api::ObjectMeta, Client};
+
+    #[test]
+    fn watches_the_selected_pods() {
+        let config = watch_config(&ListParams::default().labels("app=web").fields("status.phase=Running"));
+        assert_eq!(config.label_selector.as_deref(), Some("app=web"));
+        assert_eq!(config.field_selector.as_deref(), Some("status.phase=Running"));
+
+        let config = watch_config(&ListParams::default().fields("metadata.name=web-0"));
+        assert_eq!(config.field_selector.as_deref(), Some("metadata.name=web-0"));
+    }
+
+    #[tokio::test]
+    async fn pods_are_picked_whatever_the_store_order() {
+        let client = Client::try_from(kube::Config::new("http://127.0.0.1:9".parse().unwrap())).unwrap();
+        let api: Api<Pod> = Api::default_namespaced(client);
+        let pod = |namespace: &str, name: &str| Pod {
+            metadata: ObjectMeta {
+                namespace: Some(namespace.to_owned()),
+                name: Some(name.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let names = |pods: &[Pod]| -> Vec<String> {
+            pods.iter()
+                .map(|p| format!("{}/{}", p.metadata.namespace.as_deref().unwrap(), p.metadata.name.as_deref().unwrap()))
+                .collect()
+        };
+        let selection = PodSelection {
+            ignore_readiness: true,
+            ..Default::default()
+        };
+
+        let all = [pod("b", "web-0"), pod("a", "web-1"), pod("a", "web-0"), pod("b", "api-0")];
+        for rotate in 0..all.len() {
+            let (store, mut writer) = reflector::store();
+            writer.apply_watcher_event(&watcher::Event::Init);
+            for p in all.iter().cycle().skip(rotate).take(all.len()) {
+                writer.apply_watcher_event(&watcher::Event::InitApply(p.clone()));
+            }
+            writer.apply_watcher_event(&watcher::Event::InitDone);
+
+            let cache = PodCache { api: api.clone(), selector: ListParams::default(), store };
+            let pods = cache.pods().await.unwrap();
+            assert_eq!(names(&pods), ["a/web-0", "a/web-1", "b/api-0", "b/web-0"]);
+
+            let (picked, _) = pick_pod(&api, pods, &selection).await.unwrap();
+            assert_eq!(picked.metadata.name.as_deref(), Some("web-0"));
+            assert_eq!(picked.metadata.namespace.as_deref(), Some("a"));
+        }
+    }
+}
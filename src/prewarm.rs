@@ -0,0 +1,191 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::{api::core::v1::Pod, apimachinery::pkg::util::intstr::IntOrString};
+use kube::Api;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::{
+    pod::{self, PodChoice, PodSelection, Upstream},
+    pod_cache::PodCache,
+    status,
+};
+
+/// How often the pool is checked for expired or unready streams when no connections are taken
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A port-forward stream established ahead of time and waiting for a client.
+pub struct PrewarmedStream {
+    pub pod_name: String,
+    pub port: u16,
+    pub upstream: Upstream,
+    /// How the pod was picked when the stream was opened
+    pub choice: PodChoice,
+    created: Instant,
+}
+
+/// Holds up to `size` idle port-forward streams to ready pods so accepted connections can be
+/// spliced onto an already established stream instead of waiting for the port-forward handshake.
+pub struct PrewarmPool {
+    pod_api: Api<Pod>,
+    pods: PodCache,
+    pod_port: IntOrString,
+    size: usize,
+    ttl: Duration,
+    selection: PodSelection,
+
+    streams: Mutex<VecDeque<PrewarmedStream>>,
+    taken: Notify,
+    /// How many streams are waiting, for --status-addr
+    ready: Arc<AtomicUsize>,
+    /// Whether opening the last stream failed, so failures are only warned of once
+    failing: AtomicBool,
+    _status: status::Prewarming,
+}
+
+impl PrewarmPool {
+    pub fn new(
+        target: &str,
+        pod_api: Api<Pod>,
+        pods: PodCache,
+        pod_port: IntOrString,
+        size: usize,
+        ttl: Duration,
+        selection: PodSelection,
+    ) -> Self {
+        let ready = Arc::new(AtomicUsize::new(0));
+
+        Self {
+            pod_api,
+            pods,
+            pod_port,
+            size,
+            ttl,
+            selection,
+            streams: Mutex::new(VecDeque::with_capacity(size)),
+            taken: Notify::new(),
+            _status: status::prewarming(target, size, ready.clone()),
+            ready,
+            failing: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes the oldest stream from the pool that has not yet expired.
+    pub fn take(&self) -> Option<PrewarmedStream> {
+        let mut streams = self.streams.lock().unwrap();
+        let mut result = None;
+
+        while let Some(stream) = streams.pop_front() {
+            if stream.created.elapsed() < self.ttl {
+                result = Some(stream);
+                break;
+            }
+            stream.upstream.abort();
+        }
+
+        self.ready.store(streams.len(), Ordering::Relaxed);
+        drop(streams);
+        self.taken.notify_one();
+
+        result
+    }
+
+    /// Keeps the pool topped up, discarding streams which have expired or whose pod is no longer ready.
+    pub async fn maintain(&self) {
+        loop {
+            if let Err(e) = self.prune().await {
+                warn!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    "failed to check prewarmed streams"
+                );
+            }
+            self.fill().await;
+
+            tokio::select! {
+                _ = self.taken.notified() => {},
+                _ = tokio::time::sleep(MAINTAIN_INTERVAL) => {},
+            }
+        }
+    }
+
+    async fn prune(&self) -> anyhow::Result<()> {
+        if self.streams.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+
+        let eligible: HashSet<String> = self
+            .pods
+            .pods()
+            .await?
+            .into_iter()
+            .filter(|p| self.selection.is_eligible(p))
+            .filter_map(|p| p.metadata.name)
+            .collect();
+
+        let mut streams = self.streams.lock().unwrap();
+        let (keep, discard): (VecDeque<_>, VecDeque<_>) = streams
+            .drain(..)
+            .partition(|s| s.created.elapsed() < self.ttl && eligible.contains(&s.pod_name));
+        *streams = keep;
+        self.ready.store(streams.len(), Ordering::Relaxed);
+        drop(streams);
+
+        for stream in discard {
+            debug!(pod_name = stream.pod_name, "discarding prewarmed stream");
+            stream.upstream.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn fill(&self) {
+        while self.streams.lock().unwrap().len() < self.size {
+            match self.open().await {
+                Ok(stream) => {
+                    debug!(pod_name = stream.pod_name, "prewarmed stream");
+                    if self.failing.swap(false, Ordering::Relaxed) {
+                        info!("prewarming streams again");
+                    }
+
+                    let mut streams = self.streams.lock().unwrap();
+                    streams.push_back(stream);
+                    self.ready.store(streams.len(), Ordering::Relaxed);
+                }
+                Err(e) if self.failing.swap(true, Ordering::Relaxed) => {
+                    debug!(error = e.as_ref() as &dyn std::error::Error, "failed to prewarm stream");
+                    break;
+                }
+                Err(e) => {
+                    warn!(
+                        error = e.as_ref() as &dyn std::error::Error,
+                        "failed to prewarm stream, retrying until one can be opened"
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn open(&self) -> anyhow::Result<PrewarmedStream> {
+        let (pod, choice) = pod::pick_pod(&self.pod_api, self.pods.pods().await?, &self.selection).await?;
+        let port = pod::find_pod_port(&self.pod_port, &pod)?;
+        let pod_name = pod.metadata.name.unwrap_or_default();
+
+        let upstream = pod::open_upstream(&self.pod_api, &pod_name, port).await?;
+
+        Ok(PrewarmedStream {
+            pod_name,
+            port,
+            upstream,
+            choice,
+            created: Instant::now(),
+        })
+    }
+}
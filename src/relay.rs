@@ -0,0 +1,81 @@
+use anyhow::Context;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::AttachParams, Api};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::info;
+
+/// Relays a connection to `host:port` from inside a pod, by running the relay command in the pod
+/// and bridging its stdin and stdout with the client.
+///
+/// The pod must have the relay command available (`nc` by default) and be able to route to
+/// `host:port` itself. Returns the bytes sent `(up, down)`.
+pub async fn relay_connection(
+    pod_api: &Api<Pod>,
+    pod_name: &str,
+    relay_command: &str,
+    host: &str,
+    port: u16,
+    client: impl AsyncRead + AsyncWrite + Unpin,
+) -> anyhow::Result<(u64, u64)> {
+    info!(relay_host = host, relay_port = port, "relaying started");
+
+    let command = build_command(relay_command, host, port);
+    let ap = AttachParams::default().stdin(true).stdout(true).stderr(false);
+
+    let mut process = pod_api.exec(pod_name, command, &ap).await?;
+    let mut stdin = process.stdin().context("relay process has no stdin")?;
+    let mut stdout = process.stdout().context("relay process has no stdout")?;
+
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+
+    let up = async {
+        let n = tokio::io::copy(&mut client_read, &mut stdin).await?;
+        stdin.shutdown().await?;
+        Ok::<_, std::io::Error>(n)
+    };
+    let down = async {
+        let n = tokio::io::copy(&mut stdout, &mut client_write).await?;
+        client_write.shutdown().await?;
+        Ok::<_, std::io::Error>(n)
+    };
+
+    let (up, down) = futures::try_join!(up, down)?;
+
+    drop(stdin);
+    drop(stdout);
+    process.join().await.context("relay process join error")?;
+
+    Ok((up, down))
+}
+
+/// Splits the relay command template into arguments, substituting `{host}` and `{port}`.
+fn build_command(template: &str, host: &str, port: u16) -> Vec<String> {
+    template
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{host}", host)
+                .replace("{port}", &port.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_command() {
+        assert_eq!(
+            build_command("nc {host} {port}", "10.0.0.1", 80),
+            vec!["nc", "10.0.0.1", "80"]
+        );
+    }
+
+    #[test]
+    fn socat_command() {
+        assert_eq!(
+            build_command("socat - TCP:{host}:{port}", "10.0.0.1", 80),
+            vec!["socat", "-", "TCP:10.0.0.1:80"]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::future::join_all;
+use kube::Client;
+use tokio::{pin, task::JoinHandle};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    cli::{self, CliArgs, ClientKey, ControlArgs, Forward},
+    connectivity::Connectivity,
+    control::{self, Reply, Request, Requests},
+    hook::{self, Event},
+    shutdown, target,
+};
+
+/// How often the --config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type Clients = HashMap<ClientKey, (Client, Arc<Connectivity>)>;
+
+/// Everything a forward is started from, so a change to any of it restarts the forward.
+#[derive(Debug, PartialEq, Clone)]
+struct Spec {
+    forward: Forward,
+    control: ControlArgs,
+    client: ClientKey,
+}
+
+impl Spec {
+    fn new(args: &CliArgs, forward: Forward) -> Spec {
+        Spec {
+            control: args.control.with_options(&forward.options),
+            client: args.client_key(&forward),
+            forward,
+        }
+    }
+
+    fn all(args: &CliArgs) -> Vec<Spec> {
+        args.forwards.iter().map(|forward| Spec::new(args, forward.clone())).collect()
+    }
+}
+
+struct Running {
+    spec: Spec,
+    handle: JoinHandle<anyhow::Result<()>>,
+    /// Added through --control-socket, so left running when --config changes
+    added: bool,
+}
+
+/// Owns the forwards, starting, stopping and restarting them to match the --config file as it
+/// changes and the requests made through --control-socket.
+pub struct Supervisor {
+    config: Option<PathBuf>,
+    requests: Option<Requests>,
+    args: CliArgs,
+    clients: Clients,
+    running: Vec<Running>,
+}
+
+async fn start_forward(clients: &Clients, spec: &Spec) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let (client, connectivity) = clients
+        .get(&spec.client)
+        .ok_or_else(|| anyhow::anyhow!("no client for the forward"))?;
+
+    crate::create_forward(
+        client.clone(),
+        connectivity.clone(),
+        &spec.forward,
+        spec.control.clone(),
+        crate::Lifecycle::global(),
+    )
+    .await
+}
+
+/// Which of the running forwards to stop, by index, and which forwards to start, leaving
+/// those that haven't changed alone.
+fn plan<'a>(running: &[&Spec], desired: &'a [Spec]) -> (Vec<usize>, Vec<&'a Spec>) {
+    let stop = (0..running.len()).filter(|i| !desired.contains(running[*i])).collect();
+    let start = desired.iter().filter(|s| !running.contains(s)).collect();
+
+    (stop, start)
+}
+
+/// Whether arguments that aren't reloaded have changed, to warn about them.
+fn fixed_changed(old: &CliArgs, new: &CliArgs) -> bool {
+    let reloaded = CliArgs {
+        forwards: old.forwards.clone(),
+        context: old.context.clone(),
+        control: old.control.clone(),
+        ..new.clone()
+    };
+
+    reloaded != *old
+}
+
+impl Supervisor {
+    /// Takes over the forwards already started from `args`, in order, skipping those whose
+    /// client couldn't be created, as they were.
+    pub fn new(
+        config: Option<PathBuf>,
+        requests: Option<Requests>,
+        args: CliArgs,
+        clients: Clients,
+        handles: Vec<JoinHandle<anyhow::Result<()>>>,
+    ) -> Self {
+        let running = Spec::all(&args)
+            .into_iter()
+            .filter(|s| clients.contains_key(&s.client))
+            .zip(handles)
+            .map(|(spec, handle)| Running {
+                spec,
+                handle,
+                added: false,
+            })
+            .collect();
+
+        Self {
+            config,
+            requests,
+            args,
+            clients,
+            running,
+        }
+    }
+
+    /// Keeps the forwards in line with the file and requests until shutting down, then waits
+    /// for them to finish. Failing to start a forward is only logged or replied with here,
+    /// unlike when kubempf starts.
+    pub async fn run(mut self) {
+        self.watch().await;
+        join_all(self.running.into_iter().map(|r| r.handle)).await;
+    }
+
+    async fn watch(&mut self) {
+        // Without either, the forwards are left to run as they were started
+        if self.config.is_none() && self.requests.is_none() {
+            return;
+        }
+        let mut requests = self.requests.take();
+        let mut contents = self.config.as_ref().and_then(|path| std::fs::read(path).ok());
+        let shutdown = shutdown::requested();
+        pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = tokio::time::sleep(POLL_INTERVAL), if self.config.is_some() => {}
+                Some((request, reply)) = next_request(&mut requests) => {
+                    let _ = reply.send(self.handle(request).instrument(info_span!("control")).await);
+                    continue;
+                }
+            }
+
+            // Editors often replace the file rather than writing to it, so it can briefly be missing
+            let latest = self.config.as_ref().and_then(|path| std::fs::read(path).ok());
+            if latest.is_none() || latest == contents {
+                continue;
+            }
+            contents = latest;
+
+            self.reload().instrument(info_span!("reload")).await;
+        }
+    }
+
+    /// Carries out a request made through --control-socket.
+    async fn handle(&mut self, request: Request) -> Reply {
+        match request {
+            Request::List => Ok(self
+                .running
+                .iter()
+                .map(|r| match r.handle.is_finished() {
+                    true => format!("{} (stopped)", control::describe(&r.spec.forward)),
+                    false => control::describe(&r.spec.forward),
+                })
+                .collect()),
+            Request::Add(forward) => {
+                let spec = Spec::new(&self.args, forward);
+                if self.running.iter().any(|r| r.spec == spec && !r.handle.is_finished()) {
+                    return Err("the forward is already running".to_owned());
+                }
+
+                let args = self.args.clone();
+                self.ensure_client(&args, &spec.client).await.map_err(|e| format!("{e:#}"))?;
+                let handle = start_forward(&self.clients, &spec).await.map_err(|e| format!("{e:#}"))?;
+                info!(service = spec.forward.service_name, "started forward added through --control-socket");
+
+                // One that had stopped is replaced
+                self.running.retain(|r| r.spec != spec);
+                self.running.push(Running {
+                    spec,
+                    handle,
+                    added: true,
+                });
+                Ok(vec![])
+            }
+            Request::Remove(forward) => {
+                let i = self
+                    .running
+                    .iter()
+                    .position(|r| r.spec.forward == forward)
+                    .ok_or_else(|| "no such forward is running".to_owned())?;
+
+                let running = self.running.remove(i);
+                running.handle.abort();
+                let _ = running.handle.await;
+                info!(service = running.spec.forward.service_name, "stopped forward removed through --control-socket");
+                Ok(vec![])
+            }
+        }
+    }
+
+    async fn reload(&mut self) {
+        let args = match cli::try_parse_args() {
+            Ok(args) => args,
+            Err(e) => {
+                let message = e.to_string();
+                warn!(
+                    error = message.lines().next().unwrap_or_default().trim_start_matches("error: "),
+                    "keeping the current forwards, as --config is now invalid"
+                );
+                return;
+            }
+        };
+        if fixed_changed(&self.args, &args) {
+            warn!("only forwards, --context and forward options are reloaded from --config, restart to apply the other changes");
+        }
+
+        let desired = Spec::all(&args);
+        let (stop, start) = plan(&self.running.iter().map(|r| &r.spec).collect::<Vec<_>>(), &desired);
+        let stop: Vec<usize> = stop.into_iter().filter(|i| !self.running[*i].added).collect();
+        let (stopped, mut started) = (stop.len(), 0);
+        // Those started again with the same local address and target were changed, so restarted
+        let mut changed = HashSet::new();
+
+        // Stopped first, so a changed forward's listener is gone before it is bound again
+        for i in stop.into_iter().rev() {
+            let running = self.running.remove(i);
+            running.handle.abort();
+            let _ = running.handle.await;
+            info!(service = running.spec.forward.service_name, "stopped forward, as it was removed or changed in --config");
+            changed.insert(control::describe(&running.spec.forward));
+        }
+
+        for spec in start {
+            if let Err(e) = self.ensure_client(&args, &spec.client).await {
+                error!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    service = spec.forward.service_name,
+                    "failed to create client, skipping the forward"
+                );
+                continue;
+            }
+
+            match start_forward(&self.clients, spec).await {
+                Ok(handle) => {
+                    started += 1;
+                    if changed.contains(&control::describe(&spec.forward)) {
+                        let (client, _) = &self.clients[&spec.client];
+                        hook::fire(Event::ForwardRestarted, &target::name(&spec.forward, client), None);
+                    }
+                    self.running.push(Running {
+                        spec: spec.clone(),
+                        handle,
+                        added: false,
+                    });
+                }
+                Err(e) => error!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    service = spec.forward.service_name,
+                    "failed to start forward added to --config"
+                ),
+            }
+        }
+
+        self.args = args;
+        info!(started, stopped, running = self.running.len(), "reloaded --config");
+    }
+
+    async fn ensure_client(&mut self, args: &CliArgs, key: &ClientKey) -> anyhow::Result<()> {
+        if !self.clients.contains_key(key) {
+            let client = crate::create_client(args, key).await?;
+            let connectivity = Connectivity::new(client.clone(), key.context.clone());
+            self.clients.insert(key.clone(), (client, connectivity));
+        }
+
+        Ok(())
+    }
+}
+
+/// The next request, or never when there's no --control-socket.
+async fn next_request(requests: &mut Option<Requests>) -> Option<(Request, tokio::sync::oneshot::Sender<Reply>)> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn specs(args: &[&str]) -> Vec<Spec> {
+        Spec::all(&CliArgs::parse_from([&["kubempf"], args].concat()))
+    }
+
+    #[test]
+    fn only_changed_forwards_restart() {
+        let running = specs(&["web:80", "api:8080", "db:5432"]);
+        let desired = specs(&["web:80", "api:8080?ignore-readiness", "cache:6379"]);
+
+        let (stop, start) = plan(&running.iter().collect::<Vec<_>>(), &desired);
+
+        assert_eq!(stop, [1, 2]);
+        assert_eq!(start, [&desired[1], &desired[2]]);
+    }
+
+    #[test]
+    fn global_options_restart_every_forward() {
+        let running = specs(&["web:80", "api:8080"]);
+        let desired = specs(&["web:80", "api:8080", "--context", "staging"]);
+
+        let (stop, start) = plan(&running.iter().collect::<Vec<_>>(), &desired);
+
+        assert_eq!(stop, [0, 1]);
+        assert_eq!(start.len(), 2);
+    }
+
+    #[test]
+    fn unchanged_forwards_are_left_alone() {
+        let running = specs(&["web:80", "api:8080"]);
+        let desired = specs(&["api:8080", "web:80"]);
+
+        let (stop, start) = plan(&running.iter().collect::<Vec<_>>(), &desired);
+
+        assert!(stop.is_empty());
+        assert!(start.is_empty());
+    }
+}
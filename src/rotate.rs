@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+use tracing::info;
+
+/// A forward's active connections, so a share of them can be closed every --rotate-interval to
+/// have their clients reconnect and be spread over the pods again.
+#[derive(Default)]
+pub struct Rotation {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+}
+
+impl Rotation {
+    /// Tracks a connection until the returned handle is dropped.
+    pub fn register(self: &Arc<Self>) -> Rotatable {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.active.lock().unwrap().insert(id, tx);
+
+        Rotatable {
+            id,
+            rotated: rx,
+            rotation: self.clone(),
+        }
+    }
+
+    /// Closes `percent` of the active connections, rounded up, chosen at random. Returns how many
+    /// were closed and how many were active.
+    pub fn rotate(&self, percent: u8) -> (usize, usize) {
+        let mut active = self.active.lock().unwrap();
+        let count = active.len();
+        let closing = (count * usize::from(percent)).div_ceil(100).min(count);
+
+        let ids: Vec<u64> = active.keys().copied().collect();
+        for id in rand::seq::index::sample(&mut rand::thread_rng(), count, closing) {
+            if let Some(tx) = active.remove(&ids[id]) {
+                let _ = tx.send(());
+            }
+        }
+
+        (closing, count)
+    }
+
+    /// Rotates connections every `interval` until aborted, which happens when the forward stops
+    /// accepting connections so shutdown isn't mixed up with rotation.
+    pub async fn run(&self, interval: Duration, percent: u8) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let (closed, active) = self.rotate(percent);
+            if active > 0 {
+                info!(closed, active, "rotated connections");
+            }
+        }
+    }
+}
+
+/// An active connection, removed from its [`Rotation`] when dropped.
+pub struct Rotatable {
+    id: u64,
+    rotated: oneshot::Receiver<()>,
+    rotation: Arc<Rotation>,
+}
+
+impl Rotatable {
+    /// Resolves once the connection has been picked for closing.
+    pub async fn rotated(&mut self) {
+        if (&mut self.rotated).await.is_err() {
+            // The rotation is gone, so this connection will never be picked
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Rotatable {
+    fn drop(&mut self) {
+        self.rotation.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rotates_share_of_connections_rounding_up() {
+        let rotation = Arc::new(Rotation::default());
+        let mut connections: Vec<_> = (0..3).map(|_| rotation.register()).collect();
+
+        assert_eq!(rotation.rotate(10), (1, 3));
+
+        let mut rotated = 0;
+        for connection in &mut connections {
+            if tokio::time::timeout(Duration::from_millis(10), connection.rotated()).await.is_ok() {
+                rotated += 1;
+            }
+        }
+        assert_eq!(rotated, 1);
+        assert_eq!(rotation.rotate(100), (2, 2));
+    }
+
+    #[test]
+    fn dropped_connections_are_forgotten() {
+        let rotation = Arc::new(Rotation::default());
+        drop(rotation.register());
+
+        assert_eq!(rotation.rotate(100), (0, 0));
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{cli::Forward, errors::MyError};
+
+/// The most a connection is read looking for the hostname before giving up on it
+const MAX_SNIFF_BYTES: usize = 16 * 1024;
+
+/// Hostname matching connections without a hostname, or one no other route matches
+pub const FALLBACK_HOST: &str = "*";
+
+/// A `--route HOST=TARGET`, sending connections to the shared route port for `HOST` to `TARGET`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Route {
+    /// Lowercased hostname, or [`FALLBACK_HOST`]
+    pub host: String,
+    pub forward: Forward,
+}
+
+impl Route {
+    pub fn parse(arg: &str) -> anyhow::Result<Route> {
+        let invalid = |reason: &str| MyError::InvalidRoute(arg.to_owned(), reason.to_owned());
+
+        let (host, target) = arg
+            .split_once('=')
+            .ok_or_else(|| invalid("expected HOST=[NAMESPACE/]SERVICE[:PORT]"))?;
+        let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() {
+            return Err(invalid("missing hostname").into());
+        }
+
+        let forward = Forward::parse_remote(target)
+            .map_err(|_| invalid("expected [NAMESPACE/]SERVICE[:PORT][?OPTIONS] after the hostname"))?;
+
+        Ok(Route { host, forward })
+    }
+}
+
+/// Parses `--route-bind [ADDRESS:]PORT`, where a bare port binds `127.0.0.1`.
+pub fn parse_bind(arg: &str) -> Result<SocketAddr, MyError> {
+    match arg.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+        Err(_) => arg
+            .parse()
+            .map_err(|_| MyError::ArgumentParseError(arg.to_owned())),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    /// More of the connection is needed to tell
+    Incomplete,
+    Host(String),
+    /// The connection isn't TLS or HTTP, or doesn't name a host
+    NoHost,
+}
+
+/// Reads the start of a connection until the hostname in its TLS ClientHello (SNI) or HTTP
+/// `Host` header is found. Returns the hostname, if any, along with the bytes read, which must
+/// still be sent on.
+pub async fn read_host<T>(stream: &mut T) -> std::io::Result<(Option<String>, Vec<u8>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        match sniff(&buf) {
+            Sniffed::Incomplete if buf.len() < MAX_SNIFF_BYTES => {}
+            Sniffed::Host(host) => return Ok((Some(host), buf)),
+            Sniffed::Incomplete | Sniffed::NoHost => return Ok((None, buf)),
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok((None, buf));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn sniff(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(0x16) => tls_server_name(buf),
+        Some(b) if b.is_ascii_uppercase() => http_host(buf),
+        Some(_) => Sniffed::NoHost,
+    }
+}
+
+/// Finds the server_name extension in a TLS ClientHello.
+fn tls_server_name(buf: &[u8]) -> Sniffed {
+    // record header: type, version (2), length (2)
+    let Some(record_len) = buf.get(3..5).map(|l| u16::from_be_bytes([l[0], l[1]]) as usize) else {
+        return Sniffed::Incomplete;
+    };
+    let Some(record) = buf.get(5..5 + record_len) else {
+        return Sniffed::Incomplete;
+    };
+
+    match client_hello_server_name(record) {
+        Some(host) => Sniffed::Host(host),
+        None => Sniffed::NoHost,
+    }
+}
+
+fn client_hello_server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+
+    // handshake type 1 is ClientHello
+    if r.u8()? != 1 {
+        return None;
+    }
+    r.skip(3)?; // handshake length
+    r.skip(2 + 32)?; // client version, random
+    let session_id = r.u8()? as usize;
+    r.skip(session_id)?;
+    let cipher_suites = r.u16()? as usize;
+    r.skip(cipher_suites)?;
+    let compression_methods = r.u8()? as usize;
+    r.skip(compression_methods)?;
+
+    let extensions_len = r.u16()? as usize;
+    let mut extensions = Reader(r.take(extensions_len)?);
+
+    while let Some(ext_type) = extensions.u16() {
+        let ext_len = extensions.u16()? as usize;
+        let ext = extensions.take(ext_len)?;
+
+        // server_name
+        if ext_type != 0 {
+            continue;
+        }
+
+        let mut names = Reader(ext);
+        let list_len = names.u16()? as usize;
+        let mut names = Reader(names.take(list_len)?);
+        while let Some(name_type) = names.u8() {
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+
+            // host_name
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(normalise_host);
+            }
+        }
+    }
+
+    None
+}
+
+/// Finds the `Host` header of an HTTP/1 request.
+fn http_host(buf: &[u8]) -> Sniffed {
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Sniffed::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Sniffed::NoHost;
+    };
+
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty())
+        .map_or(Sniffed::NoHost, |value| Sniffed::Host(normalise_host(strip_port(value))))
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or(host, |(ip, _)| ip),
+        None => host.split_once(':').map_or(host, |(name, _)| name),
+    }
+}
+
+fn normalise_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let taken = self.0.get(..n)?;
+        self.0 = &self.0[n..];
+        Some(taken)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/// Replays the bytes already read from a stream before reading any more from it.
+pub struct Prefixed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    prefix: Vec<u8>,
+    read: usize,
+    stream: T,
+}
+
+impl<T> Prefixed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(prefix: Vec<u8>, stream: T) -> Self {
+        Self {
+            prefix,
+            read: 0,
+            stream,
+        }
+    }
+}
+
+impl<T> AsyncRead for Prefixed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut_self = self.get_mut();
+
+        if mut_self.read < mut_self.prefix.len() {
+            let remaining = &mut_self.prefix[mut_self.read..];
+            let n = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..n]);
+            mut_self.read += n;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut mut_self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for Prefixed<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// A minimal ClientHello with the given SNI hostname
+    fn client_hello(host: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        // an unrelated extension first, supported_groups
+        extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+        if let Some(host) = host {
+            let name_len = host.len() as u16;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(name_len + 5).to_be_bytes());
+            extensions.extend_from_slice(&(name_len + 3).to_be_bytes());
+            extensions.push(0);
+            extensions.extend_from_slice(&name_len.to_be_bytes());
+            extensions.extend_from_slice(host.as_bytes());
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn tls_sni() {
+        let hello = client_hello(Some("Grafana.Example.Test"));
+
+        assert_eq!(sniff(&hello), Sniffed::Host("grafana.example.test".to_owned()));
+        assert_eq!(sniff(&hello[..hello.len() - 1]), Sniffed::Incomplete);
+        assert_eq!(sniff(&hello[..3]), Sniffed::Incomplete);
+        assert_eq!(sniff(&client_hello(None)), Sniffed::NoHost);
+    }
+
+    #[test]
+    fn http_host_header() {
+        let request = b"GET / HTTP/1.1\r\nAccept: */*\r\nhost: Grafana.example.test:8080\r\n\r\n";
+
+        assert_eq!(sniff(request), Sniffed::Host("grafana.example.test".to_owned()));
+        assert_eq!(sniff(&request[..20]), Sniffed::Incomplete);
+        assert_eq!(sniff(b"GET / HTTP/1.0\r\n\r\n"), Sniffed::NoHost);
+        assert_eq!(
+            sniff(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+            Sniffed::Host("::1".to_owned())
+        );
+    }
+
+    #[test]
+    fn other_protocols() {
+        assert_eq!(sniff(&[0x00, 0x01]), Sniffed::NoHost);
+        assert_eq!(sniff(b""), Sniffed::Incomplete);
+    }
+
+    #[test]
+    fn parse_route() {
+        let route = Route::parse("Grafana.Example.Test=monitoring/grafana:80").unwrap();
+
+        assert_eq!(route.host, "grafana.example.test");
+        assert_eq!(route.forward.namespace.as_deref(), Some("monitoring"));
+        assert_eq!(route.forward.service_name, "grafana");
+        assert_eq!(route.forward.service_port.as_deref(), Some("80"));
+
+        assert_eq!(Route::parse("*=web").unwrap().host, FALLBACK_HOST);
+        assert_eq!(
+            Route::parse("web=web:http").unwrap().forward.service_port.as_deref(),
+            Some("http")
+        );
+    }
+
+    #[test]
+    fn parse_route_errors() {
+        for arg in ["grafana", "=grafana:80", "host=8080:grafana:80", "host=127.0.0.1:8080:grafana:80"] {
+            let err = Route::parse(arg).unwrap_err();
+            assert!(matches!(err.downcast_ref(), Some(MyError::InvalidRoute(_, _))), "{arg}");
+        }
+    }
+
+    #[test]
+    fn parse_route_bind() {
+        assert_eq!(parse_bind("8443").unwrap(), "127.0.0.1:8443".parse().unwrap());
+        assert_eq!(parse_bind("[::]:443").unwrap(), "[::]:443".parse().unwrap());
+        assert!(parse_bind("localhost:443").is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_sniffed_bytes() {
+        let (mut client, mut peer) = tokio::io::duplex(1024);
+        let request = b"GET / HTTP/1.1\r\nHost: web\r\n\r\n";
+
+        peer.write_all(request).await.unwrap();
+        peer.write_all(b"body").await.unwrap();
+        drop(peer);
+
+        let (host, read) = read_host(&mut client).await.unwrap();
+        assert_eq!(host.as_deref(), Some("web"));
+
+        let mut all = vec![];
+        Prefixed::new(read, client).read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, [&request[..], b"body"].concat());
+    }
+}
@@ -0,0 +1,349 @@
+use std::cmp::Ordering;
+
+use k8s_openapi::api::core::v1::Pod;
+use serde_json::Value;
+
+use crate::errors::MyError;
+
+/// A `--select-where` condition a pod must meet to be forwarded to, eg.
+/// `status.containerStatuses[*].restartCount == 0` or `label.track != canary`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PodPredicate {
+    path: PodPath,
+    op: Op,
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum PodPath {
+    Label(String),
+    Annotation(String),
+    Field(Vec<Segment>),
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Segment {
+    name: String,
+    index: Option<Index>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Index {
+    All,
+    At(usize),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Operators in the order they are tried, so `<=` is not taken for `<`
+const OPS: [(&str, Op); 6] = [
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+impl PodPredicate {
+    pub fn parse(expr: &str) -> Result<Self, MyError> {
+        let invalid = |reason: &str| MyError::InvalidSelectWhere(expr.to_owned(), reason.to_owned());
+
+        let (start, token, op) = OPS
+            .iter()
+            .filter_map(|(token, op)| expr.find(token).map(|i| (i, *token, *op)))
+            .min_by_key(|(i, token, _)| (*i, std::cmp::Reverse(token.len())))
+            .ok_or_else(|| invalid("expected one of == != < <= > >="))?;
+
+        let path = expr[..start].trim();
+        let value = expr[start + token.len()..].trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let path = if let Some(key) = path.strip_prefix("label.") {
+            PodPath::Label(key.to_owned())
+        } else if let Some(key) = path.strip_prefix("annotation.") {
+            PodPath::Annotation(key.to_owned())
+        } else {
+            PodPath::Field(
+                path.split('.')
+                    .map(Segment::parse)
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| invalid("invalid field path"))?,
+            )
+        };
+
+        if matches!(&path, PodPath::Label(k) | PodPath::Annotation(k) if k.is_empty()) {
+            return Err(invalid("missing label or annotation key"));
+        }
+
+        Ok(Self {
+            path,
+            op,
+            value: value.to_owned(),
+        })
+    }
+
+    /// Parses a `--ready-label KEY=VALUE`, a condition that the pod's label `KEY` is `VALUE`.
+    pub fn parse_label(label: &str) -> Result<Self, MyError> {
+        match label.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => Ok(Self {
+                path: PodPath::Label(key.trim().to_owned()),
+                op: Op::Eq,
+                value: value.trim().to_owned(),
+            }),
+            _ => Err(MyError::InvalidReadyLabel(label.to_owned())),
+        }
+    }
+
+    /// Whether the pod meets the condition. Every value a `[*]` path selects must match, and a
+    /// path which selects nothing doesn't match, unless the condition is `!=`: a missing value
+    /// isn't equal to anything.
+    pub fn matches(&self, pod: &Pod) -> bool {
+        let metadata_value = |map: Option<&std::collections::BTreeMap<String, String>>, key: &str| {
+            map.and_then(|m| m.get(key)).map(|v| Value::String(v.clone()))
+        };
+
+        let values: Vec<Value> = match &self.path {
+            PodPath::Label(key) => metadata_value(pod.metadata.labels.as_ref(), key).into_iter().collect(),
+            PodPath::Annotation(key) => {
+                metadata_value(pod.metadata.annotations.as_ref(), key).into_iter().collect()
+            }
+            PodPath::Field(segments) => match serde_json::to_value(pod) {
+                Ok(pod) => select(vec![pod], segments),
+                Err(_) => vec![],
+            },
+        };
+
+        match values.is_empty() {
+            true => self.op == Op::Ne,
+            false => values.iter().all(|v| self.compare(v)),
+        }
+    }
+
+    fn compare(&self, actual: &Value) -> bool {
+        let actual = match actual {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            _ => return false,
+        };
+
+        let ordering = match (actual.parse::<f64>(), self.value.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b),
+            _ => Some(actual.as_str().cmp(self.value.as_str())),
+        };
+
+        match (self.op, ordering) {
+            (Op::Eq, Some(o)) => o == Ordering::Equal,
+            (Op::Ne, Some(o)) => o != Ordering::Equal,
+            (Op::Lt, Some(o)) => o == Ordering::Less,
+            (Op::Le, Some(o)) => o != Ordering::Greater,
+            (Op::Gt, Some(o)) => o == Ordering::Greater,
+            (Op::Ge, Some(o)) => o != Ordering::Less,
+            (_, None) => false,
+        }
+    }
+}
+
+impl Segment {
+    /// Parses `name`, `name[N]` or `name[*]`
+    fn parse(segment: &str) -> Option<Self> {
+        let (name, index) = match segment.split_once('[') {
+            Some((name, rest)) => {
+                let index = match rest.strip_suffix(']')? {
+                    "*" => Index::All,
+                    i => Index::At(i.parse().ok()?),
+                };
+                (name, Some(index))
+            }
+            None => (segment, None),
+        };
+
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return None;
+        }
+
+        Some(Self {
+            name: name.to_owned(),
+            index,
+        })
+    }
+}
+
+/// An `--owner KIND/NAME`, keeping only pods owned by that object, eg. `replicaset/web-5d4f9`
+/// or `job/migrate-42`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PodOwner {
+    kind: String,
+    name: String,
+}
+
+impl PodOwner {
+    pub fn parse(owner: &str) -> Result<Self, MyError> {
+        match owner.split_once('/') {
+            Some((kind, name)) if !kind.trim().is_empty() && !name.trim().is_empty() => Ok(Self {
+                kind: kind.trim().to_owned(),
+                name: name.trim().to_owned(),
+            }),
+            _ => Err(MyError::InvalidOwner(owner.to_owned())),
+        }
+    }
+
+    /// Whether any of the pod's owner references, controller or not, is this owner. The kind is
+    /// compared ignoring case, so `replicaset` matches `ReplicaSet`.
+    pub fn owns(&self, pod: &Pod) -> bool {
+        pod.metadata
+            .owner_references
+            .iter()
+            .flatten()
+            .any(|o| o.kind.eq_ignore_ascii_case(&self.kind) && o.name == self.name)
+    }
+}
+
+impl std::fmt::Display for PodOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.kind, self.name)
+    }
+}
+
+fn select(mut values: Vec<Value>, segments: &[Segment]) -> Vec<Value> {
+    for segment in segments {
+        values = values
+            .into_iter()
+            .filter_map(|mut v| v.get_mut(&segment.name).map(Value::take))
+            .flat_map(|v| match (&segment.index, v) {
+                (None, v) => vec![v],
+                (Some(Index::All), Value::Array(items)) => items,
+                (Some(Index::At(i)), Value::Array(mut items)) if *i < items.len() => {
+                    vec![items.swap_remove(*i)]
+                }
+                _ => vec![],
+            })
+            .collect();
+    }
+
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod() -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "web-0",
+                "labels": { "app.kubernetes.io/name": "web", "track": "stable" },
+                "annotations": { "example.com/weight": "10" },
+            },
+            "spec": {
+                "nodeName": "node-a",
+                "containers": [],
+            },
+            "status": {
+                "phase": "Running",
+                "containerStatuses": [
+                    { "name": "web", "restartCount": 0, "ready": true, "image": "", "imageID": "" },
+                    { "name": "sidecar", "restartCount": 3, "ready": true, "image": "", "imageID": "" },
+                ],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn matches(expr: &str) -> bool {
+        PodPredicate::parse(expr).unwrap().matches(&pod())
+    }
+
+    #[test]
+    fn labels_and_annotations() {
+        assert!(matches("label.app.kubernetes.io/name == web"));
+        assert!(matches("label.track != canary"));
+        assert!(!matches("label.missing == web"));
+        assert!(matches("annotation.example.com/weight >= 5"));
+    }
+
+    #[test]
+    fn missing_values_are_not_equal() {
+        let mut unlabelled = pod();
+        unlabelled.metadata.labels = None;
+        let matches = |expr: &str| PodPredicate::parse(expr).unwrap().matches(&unlabelled);
+
+        assert!(matches("label.track != canary"));
+        assert!(!matches("label.track == canary"));
+        assert!(!matches("label.track < canary"));
+        assert!(matches("status.podIP != 10.0.0.1"));
+    }
+
+    #[test]
+    fn fields() {
+        assert!(matches("spec.nodeName == node-a"));
+        assert!(matches("status.phase == \"Running\""));
+        assert!(matches("status.containerStatuses[*].ready == true"));
+        assert!(!matches("status.containerStatuses[*].restartCount == 0"));
+        assert!(matches("status.containerStatuses[0].restartCount == 0"));
+        assert!(matches("status.containerStatuses[*].restartCount < 5"));
+        assert!(!matches("status.containerStatuses[5].restartCount == 0"));
+        assert!(!matches("status.podIP == 10.0.0.1"));
+    }
+
+    #[test]
+    fn numeric_comparison() {
+        assert!(matches("status.containerStatuses[1].restartCount > 2"));
+        assert!(matches("status.containerStatuses[1].restartCount <= 3"));
+        assert!(!matches("status.containerStatuses[1].restartCount > 10"));
+    }
+
+    #[test]
+    fn ready_label() {
+        let label = PodPredicate::parse_label("track=stable").unwrap();
+
+        assert_eq!(label, PodPredicate::parse("label.track == stable").unwrap());
+        assert!(label.matches(&pod()));
+        assert!(!PodPredicate::parse_label("track=canary").unwrap().matches(&pod()));
+        assert!(matches!(PodPredicate::parse_label("=x"), Err(MyError::InvalidReadyLabel(_))));
+        assert!(matches!(PodPredicate::parse_label("track"), Err(MyError::InvalidReadyLabel(_))));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for expr in ["spec.nodeName", "== web", "label. == web", "spec..nodeName == a", "status.x[a] == 1"] {
+            assert!(
+                matches!(PodPredicate::parse(expr), Err(MyError::InvalidSelectWhere(_, _))),
+                "{expr}"
+            );
+        }
+    }
+
+    #[test]
+    fn owners() {
+        let mut pod = pod();
+        pod.metadata.owner_references = Some(
+            serde_json::from_value(serde_json::json!([
+                { "apiVersion": "apps/v1", "kind": "ReplicaSet", "name": "web-5d4f9", "uid": "1", "controller": true },
+                { "apiVersion": "batch/v1", "kind": "Job", "name": "migrate", "uid": "2" },
+            ]))
+            .unwrap(),
+        );
+
+        assert!(PodOwner::parse("replicaset/web-5d4f9").unwrap().owns(&pod));
+        assert!(PodOwner::parse("Job/migrate").unwrap().owns(&pod));
+        assert!(!PodOwner::parse("ReplicaSet/web-7c8b2").unwrap().owns(&pod));
+        assert!(!PodOwner::parse("StatefulSet/web-5d4f9").unwrap().owns(&pod));
+
+        for invalid in ["web", "/web", "ReplicaSet/", ""] {
+            assert!(PodOwner::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
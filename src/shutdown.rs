@@ -0,0 +1,145 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    runtime::{watcher, watcher::Config, WatchStreamExt},
+    Api,
+};
+use tokio::{pin, sync::watch};
+use tracing::{info, warn};
+
+use crate::errors::MyError;
+
+/// How often --until-file-removed checks for the file
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static SHUTDOWN: OnceLock<Shutdown> = OnceLock::new();
+
+/// Stops the forwards sharing it accepting connections. kubempf's own share the [`global`] one,
+/// while each [`ForwardHandle`](crate::ForwardHandle) has one of its own.
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    /// Resolves once [`Shutdown::trigger`] has been called.
+    pub async fn requested(&self) {
+        let _ = self.0.subscribe().wait_for(|t| *t).await;
+    }
+}
+
+/// The shutdown of kubempf itself, triggered on Ctrl-C.
+pub fn global() -> &'static Shutdown {
+    SHUTDOWN.get_or_init(Shutdown::default)
+}
+
+/// Stops accepting connections and exits, as Ctrl-C does.
+pub fn trigger(reason: &str) {
+    info!(reason, "shutting down");
+    global().trigger();
+}
+
+/// Resolves once shutting down has been asked for by [`trigger`], eg. on Ctrl-C.
+pub async fn requested() {
+    global().requested().await;
+}
+
+/// Shuts down on Ctrl-C. Only kubempf itself listens for it, so forwards started from another
+/// tool leave its handling of the signal alone.
+pub async fn on_ctrl_c() {
+    if tokio::signal::ctrl_c().await.is_ok() {
+        trigger("received Ctrl-C");
+    }
+}
+
+/// Checks the --until-file-removed file exists, then shuts down once it has been removed.
+pub fn until_file_removed(path: PathBuf) -> Result<impl std::future::Future<Output = ()>, MyError> {
+    if !path.exists() {
+        return Err(MyError::UntilFileMissing(path.display().to_string()));
+    }
+
+    Ok(async move {
+        wait_for_removal(&path, FILE_POLL_INTERVAL).await;
+        trigger("--until-file-removed file was removed");
+    })
+}
+
+async fn wait_for_removal(path: &Path, interval: Duration) {
+    // Polling a single path is cheap enough not to need filesystem notifications
+    while path.exists() {
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Checks the --until-pod-gone pod exists, then shuts down once it has been deleted.
+pub async fn until_pod_gone(api: Api<Pod>, name: String) -> anyhow::Result<impl std::future::Future<Output = ()>> {
+    api.get(&name).await?;
+
+    Ok(async move {
+        wait_for_deletion(api, &name).await;
+        trigger("--until-pod-gone pod is gone");
+    })
+}
+
+async fn wait_for_deletion(api: Api<Pod>, name: &str) {
+    let stream = watcher(api, Config::default().fields(&format!("metadata.name={name}"))).default_backoff();
+    pin!(stream);
+
+    let mut listed = false;
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(error = &e as &dyn std::error::Error, pod_name = name, "failed to watch --until-pod-gone pod, retrying");
+                continue;
+            }
+        };
+        match event {
+            watcher::Event::Delete(_) => return,
+            watcher::Event::Init => listed = false,
+            watcher::Event::InitApply(_) => listed = true,
+            // A relist without the pod means it was deleted while the watch was down
+            watcher::Event::InitDone if !listed => return,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_file_removal() {
+        let path = std::env::temp_dir().join(format!("kubempf-{}-until", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+
+        let wait = wait_for_removal(&path, Duration::from_millis(10));
+        pin!(wait);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut wait).await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), wait).await.unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let path = std::env::temp_dir().join(format!("kubempf-{}-missing", std::process::id()));
+
+        assert!(matches!(until_file_removed(path), Err(MyError::UntilFileMissing(_))));
+    }
+}
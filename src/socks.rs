@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    sync::{Arc, Mutex},
+};
+
+use kube::Client;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    capture::Capture,
+    cli::{ControlArgs, Forward, ForwardOptions, TargetKind},
+    connectivity::Connectivity,
+    target::Target,
+};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// The REP field of a reply to a request
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reply {
+    Succeeded = 0,
+    HostUnreachable = 4,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+/// A CONNECT request, to `host` on `port`.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Request {
+    /// Lowercased hostname, without any trailing `.`
+    pub host: String,
+    pub port: u16,
+}
+
+/// Reads the client's greeting and its CONNECT request, agreeing to no authentication, which
+/// is all that is offered. Requests for anything other than a hostname are refused, as only
+/// service hostnames can be forwarded to.
+pub async fn read_request<T>(stream: &mut T) -> std::io::Result<Request>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, methods] = read_array(stream).await?;
+    if version != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("unsupported SOCKS version {version}")));
+    }
+    let mut offered = vec![0; methods.into()];
+    stream.read_exact(&mut offered).await?;
+    if !offered.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(Error::new(ErrorKind::InvalidData, "client requires authentication"));
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let [_, command, _, address_type] = read_array(stream).await?;
+    let host = match address_type {
+        ATYP_DOMAIN => {
+            let [len] = read_array(stream).await?;
+            let mut host = vec![0; len.into()];
+            stream.read_exact(&mut host).await?;
+            String::from_utf8_lossy(&host).trim_end_matches('.').to_ascii_lowercase()
+        }
+        ATYP_IPV4 | ATYP_IPV6 => {
+            // Read off, so the refusal isn't mistaken for a reset
+            let mut address = vec![0; if address_type == ATYP_IPV4 { 4 } else { 16 }];
+            stream.read_exact(&mut address).await?;
+            String::new()
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("unknown address type {address_type}"))),
+    };
+    let port = u16::from_be_bytes(read_array(stream).await?);
+
+    if command != CONNECT {
+        reply(stream, Reply::CommandNotSupported).await?;
+        return Err(Error::new(ErrorKind::Unsupported, format!("unsupported SOCKS command {command}")));
+    }
+    if host.is_empty() {
+        reply(stream, Reply::AddressTypeNotSupported).await?;
+        return Err(Error::new(ErrorKind::Unsupported, "requested an IP address rather than a service hostname"));
+    }
+
+    Ok(Request { host, port })
+}
+
+/// Answers the request. There's no one local address a forwarded connection is bound to, so
+/// the reply always gives `0.0.0.0:0`.
+pub async fn reply<T>(stream: &mut T, reply: Reply) -> std::io::Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    stream.write_all(&[VERSION, reply as u8, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
+
+async fn read_array<const N: usize, T: AsyncRead + Unpin>(stream: &mut T) -> std::io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// The `(namespace, service)` a cluster DNS name for a service refers to, from `SERVICE`,
+/// `SERVICE.NAMESPACE`, `SERVICE.NAMESPACE.svc` or `SERVICE.NAMESPACE.svc.CLUSTER_DOMAIN`. A
+/// bare `SERVICE` is in the default namespace.
+pub fn service_for_host(host: &str) -> Option<(Option<String>, String)> {
+    let labels: Vec<&str> = host.split('.').collect();
+    let valid = |label: &&str| {
+        !label.is_empty() && label.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    };
+
+    match labels.as_slice() {
+        [service] if valid(service) => Some((None, service.to_string())),
+        [service, namespace] | [service, namespace, "svc", ..] if valid(service) && valid(namespace) => {
+            Some((Some(namespace.to_string()), service.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// The namespace, service and port of a target
+type TargetKey = (Option<String>, String, u16);
+
+/// A target, along with where its traffic is captured to
+type CapturedTarget = (Arc<Target>, Option<Arc<Capture>>);
+
+/// The forwards made through the SOCKS5 proxy, one for each service and port requested,
+/// created as they are first asked for.
+pub struct Targets {
+    client: Client,
+    connectivity: Arc<Connectivity>,
+    args: ControlArgs,
+    targets: Mutex<HashMap<TargetKey, CapturedTarget>>,
+}
+
+impl Targets {
+    pub fn new(client: Client, connectivity: Arc<Connectivity>, args: ControlArgs) -> Self {
+        Self {
+            client,
+            connectivity,
+            args,
+            targets: Mutex::default(),
+        }
+    }
+
+    /// The target for `port` on the service, along with where its traffic is captured to.
+    pub fn get(
+        &self,
+        namespace: Option<String>,
+        service: String,
+        port: u16,
+    ) -> anyhow::Result<CapturedTarget> {
+        let mut targets = self.targets.lock().unwrap();
+        let key = (namespace, service, port);
+        if let Some(target) = targets.get(&key) {
+            return Ok(target.clone());
+        }
+
+        let forward = Forward {
+            service_name: key.1.clone(),
+            service_port: Some(port.to_string()),
+            namespace: key.0.clone(),
+            local_address: None,
+            local_port: None,
+            local_path: None,
+            options: ForwardOptions::default(),
+            kind: TargetKind::Service,
+        };
+        let target = Arc::new(Target::new(
+            self.client.clone(),
+            self.connectivity.clone(),
+            forward,
+            self.args.clone(),
+        ));
+        let capture = self
+            .args
+            .capture
+            .as_ref()
+            .map(|dir| Capture::new(dir.clone(), &target.name, self.args.capture_max_bytes))
+            .transpose()?
+            .map(Arc::new);
+
+        targets.insert(key, (target.clone(), capture.clone()));
+        Ok((target, capture))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(address: &[u8], port: u16) -> Vec<u8> {
+        let mut bytes = vec![VERSION, 2, 2, NO_AUTHENTICATION, VERSION, CONNECT, 0];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(&port.to_be_bytes());
+        bytes
+    }
+
+    async fn handshake(client_sends: Vec<u8>) -> (std::io::Result<Request>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&client_sends).await.unwrap();
+
+        let request = read_request(&mut server).await;
+        drop(server);
+        let mut replies = vec![];
+        client.read_to_end(&mut replies).await.unwrap();
+
+        (request, replies)
+    }
+
+    #[tokio::test]
+    async fn connect_to_hostname() {
+        let host = b"Postgres.Data.svc.cluster.local.";
+        let mut address = vec![ATYP_DOMAIN, host.len() as u8];
+        address.extend_from_slice(host);
+
+        let (request, replies) = handshake(request(&address, 5432)).await;
+
+        assert_eq!(
+            request.unwrap(),
+            Request {
+                host: "postgres.data.svc.cluster.local".to_owned(),
+                port: 5432
+            }
+        );
+        assert_eq!(replies, [VERSION, NO_AUTHENTICATION]);
+    }
+
+    #[tokio::test]
+    async fn ip_addresses_are_refused() {
+        let (request, replies) = handshake(request(&[ATYP_IPV4, 10, 0, 0, 1], 80)).await;
+
+        assert!(request.is_err());
+        assert_eq!(replies[2..4], [VERSION, Reply::AddressTypeNotSupported as u8]);
+    }
+
+    #[tokio::test]
+    async fn authentication_is_refused() {
+        let (request, replies) = handshake(vec![VERSION, 1, 2]).await;
+
+        assert!(request.is_err());
+        assert_eq!(replies, [VERSION, NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[test]
+    fn service_hostnames() {
+        let service = |host| service_for_host(host).map(|(ns, svc)| (ns.unwrap_or_default(), svc));
+
+        assert_eq!(service("postgres"), Some(("".to_owned(), "postgres".to_owned())));
+        assert_eq!(service("postgres.data"), Some(("data".to_owned(), "postgres".to_owned())));
+        assert_eq!(service("postgres.data.svc"), Some(("data".to_owned(), "postgres".to_owned())));
+        assert_eq!(
+            service("postgres.data.svc.cluster.local"),
+            Some(("data".to_owned(), "postgres".to_owned()))
+        );
+        assert_eq!(service("example.com.au"), None);
+        assert_eq!(service("pod-0.postgres.data.svc.cluster.local"), None);
+        assert_eq!(service("bad_name.data"), None);
+    }
+}
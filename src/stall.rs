@@ -0,0 +1,257 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+use tracing::warn;
+
+/// How long a write has to be blocked before the connection counts as stalled, rather than just
+/// slowed by ordinary backpressure.
+pub const STALLED_AFTER: Duration = Duration::from_secs(1);
+
+/// Since when reads from and writes to one end of a connection have been blocked, if they are.
+#[derive(Debug, Default)]
+pub struct Blocked {
+    read: Mutex<Option<Instant>>,
+    write: Mutex<Option<Instant>>,
+}
+
+impl Blocked {
+    fn track<R>(since: &Mutex<Option<Instant>>, result: &Poll<R>) {
+        let mut since = since.lock().unwrap();
+        match result {
+            Poll::Ready(_) => *since = None,
+            Poll::Pending => {
+                since.get_or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    /// Waiting for the peer to send something, which is all an idle connection does.
+    pub fn is_read_blocked(&self) -> bool {
+        self.read.lock().unwrap().is_some()
+    }
+
+    /// Waiting for the peer to accept what was written to it.
+    pub fn is_write_blocked(&self) -> bool {
+        self.write.lock().unwrap().is_some()
+    }
+
+    /// Whether a write has been blocked for at least [`STALLED_AFTER`].
+    pub fn is_stalled(&self) -> bool {
+        self.write.lock().unwrap().is_some_and(|since| since.elapsed() >= STALLED_AFTER)
+    }
+}
+
+/// What both ends of a connection are blocked on, shared by its [`StallGuard`]s with the status
+/// API.
+#[derive(Debug, Default)]
+pub struct Stalls {
+    pub client: Blocked,
+    pub pod: Blocked,
+}
+
+impl Stalls {
+    pub fn is_stalled(&self) -> bool {
+        self.client.is_stalled() || self.pod.is_stalled()
+    }
+}
+
+/// Which end of a connection a [`StallGuard`] wraps.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Peer {
+    Client,
+    Pod,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Peer::Client => "client",
+            Peer::Pod => "pod",
+        })
+    }
+}
+
+/// Tracks when a stream is blocked reading or writing, in the connection's [`Stalls`], and
+/// fails writes to a stream which has been unable to accept any data for longer than `timeout`,
+/// so a wedged peer can't hold a connection open with data pending forever.
+///
+/// This is distinct from idleness, a stream with nothing to write never stalls.
+pub struct StallGuard<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    stream: T,
+    peer: Peer,
+    timeout: Option<Duration>,
+    stalls: Arc<Stalls>,
+    stalled: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> StallGuard<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// `peer` is the other end of the stream.
+    pub fn new(stream: T, peer: Peer, timeout: Option<Duration>, stalls: Arc<Stalls>) -> Self {
+        Self {
+            stream,
+            peer,
+            timeout,
+            stalls,
+            stalled: None,
+        }
+    }
+
+    fn blocked(&self) -> &Blocked {
+        match self.peer {
+            Peer::Client => &self.stalls.client,
+            Peer::Pod => &self.stalls.pod,
+        }
+    }
+
+    fn check<R>(&mut self, cx: &mut Context<'_>, result: Poll<std::io::Result<R>>) -> Poll<std::io::Result<R>> {
+        Blocked::track(&self.blocked().write, &result);
+
+        let Some(timeout) = self.timeout else {
+            return result;
+        };
+
+        if result.is_ready() {
+            self.stalled = None;
+            return result;
+        }
+
+        let stalled = self
+            .stalled
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+
+        match stalled.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                warn!(peer = %self.peer, "closing connection stalled writing to {}", self.peer);
+                Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("{} stopped accepting data for {}s", self.peer, timeout.as_secs_f32()),
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T> AsyncRead for StallGuard<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut_self = self.get_mut();
+        let result = Pin::new(&mut mut_self.stream).poll_read(cx, buf);
+        Blocked::track(&mut_self.blocked().read, &result);
+
+        result
+    }
+}
+
+impl<T> AsyncWrite for StallGuard<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let mut_self = self.get_mut();
+        let result = Pin::new(&mut mut_self.stream).poll_write(cx, buf);
+
+        mut_self.check(cx, result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let mut_self = self.get_mut();
+        let result = Pin::new(&mut mut_self.stream).poll_flush(cx);
+
+        mut_self.check(cx, result)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn stalled_write_times_out() {
+        let (stream, _peer) = tokio::io::duplex(8);
+        let stalls = Arc::new(Stalls::default());
+        let mut guarded = StallGuard::new(stream, Peer::Pod, Some(Duration::from_millis(50)), stalls.clone());
+
+        let err = guarded.write_all(&[0u8; 16]).await.unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(stalls.pod.is_write_blocked() && !stalls.client.is_write_blocked());
+        assert!(!stalls.is_stalled(), "stalled only after a second");
+    }
+
+    #[tokio::test]
+    async fn tracks_blocked_reads_and_writes() {
+        let (stream, mut peer) = tokio::io::duplex(8);
+        let stalls = Arc::new(Stalls::default());
+        let mut guarded = StallGuard::new(stream, Peer::Client, None, stalls.clone());
+
+        let mut buf = [0u8; 8];
+        assert!(tokio::time::timeout(Duration::from_millis(10), guarded.read(&mut buf)).await.is_err());
+        assert!(stalls.client.is_read_blocked() && !stalls.client.is_write_blocked());
+
+        peer.write_all(b"ping").await.unwrap();
+        assert_eq!(guarded.read(&mut buf).await.unwrap(), 4);
+        assert!(!stalls.client.is_read_blocked());
+
+        // Without a timeout nothing fails, the write just stays blocked
+        let write = tokio::time::timeout(Duration::from_millis(10), guarded.write_all(&[0u8; 16]));
+        assert!(write.await.is_err());
+        assert!(stalls.client.is_write_blocked() && !stalls.is_stalled());
+
+        *stalls.client.write.lock().unwrap() = Some(Instant::now() - STALLED_AFTER);
+        assert!(stalls.is_stalled());
+    }
+
+    #[tokio::test]
+    async fn draining_write_does_not_time_out() {
+        let (stream, mut peer) = tokio::io::duplex(8);
+        let stalls = Arc::new(Stalls::default());
+        let mut guarded = StallGuard::new(stream, Peer::Pod, Some(Duration::from_millis(50)), stalls.clone());
+
+        let reader = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            peer.read_to_end(&mut buf).await.unwrap();
+            buf.len()
+        });
+
+        guarded.write_all(&[0u8; 64]).await.unwrap();
+        guarded.shutdown().await.unwrap();
+        drop(guarded);
+
+        assert_eq!(reader.await.unwrap(), 64);
+        assert!(!stalls.pod.is_write_blocked());
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tracing::{debug, trace, Instrument};
+
+use crate::{
+    accept, shutdown,
+    stall::{Blocked, Stalls},
+};
+
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest request head read, far more than any GET needs
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// What is known of the running forwards and connections, only tracked once --status-addr is
+/// given.
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+struct State {
+    started: Instant,
+    next_id: u64,
+    /// By id, so a target bound more than once is listed for each
+    listeners: BTreeMap<u64, Listener>,
+    /// The --prewarm pools, by id
+    pools: BTreeMap<u64, Pool>,
+    /// By connection id
+    connections: BTreeMap<u64, ConnectionState>,
+    /// The connections and bytes of each target's closed connections
+    closed: HashMap<String, Totals>,
+}
+
+struct Listener {
+    target: String,
+    local_addrs: Vec<String>,
+    /// The privileged port that couldn't be bound, when --fallback-port was bound instead
+    fallback_from: Option<u16>,
+}
+
+struct Pool {
+    target: String,
+    size: usize,
+    /// Streams currently waiting in the pool
+    ready: Arc<AtomicUsize>,
+}
+
+struct ConnectionState {
+    target: String,
+    peer_addr: String,
+    opened: SystemTime,
+    pod: Option<(String, u16)>,
+    up: Arc<AtomicU64>,
+    down: Arc<AtomicU64>,
+    stalls: Arc<Stalls>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    connections: u64,
+    up: u64,
+    down: u64,
+}
+
+/// Starts tracking forwards and connections, with the uptime counted from now.
+pub fn enable() {
+    *STATE.lock().unwrap() = Some(State {
+        started: Instant::now(),
+        next_id: 0,
+        listeners: BTreeMap::new(),
+        pools: BTreeMap::new(),
+        connections: BTreeMap::new(),
+        closed: HashMap::new(),
+    });
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> Option<R> {
+    STATE.lock().unwrap().as_mut().map(f)
+}
+
+/// A forward's listeners, listed until dropped.
+pub struct Listening(Option<u64>);
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            with_state(|state| state.listeners.remove(&id));
+        }
+    }
+}
+
+/// Lists `target` as listening on `local_addrs` until the returned handle is dropped, along with
+/// the privileged port it bound --fallback-port in place of, if any.
+pub fn listening(target: &str, local_addrs: Vec<String>, fallback_from: Option<u16>) -> Listening {
+    Listening(with_state(|state| {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.listeners.insert(
+            id,
+            Listener {
+                target: target.to_owned(),
+                local_addrs,
+                fallback_from,
+            },
+        );
+        id
+    }))
+}
+
+/// A forward's --prewarm pool, listed until dropped.
+pub struct Prewarming(Option<u64>);
+
+impl Drop for Prewarming {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            with_state(|state| state.pools.remove(&id));
+        }
+    }
+}
+
+/// Lists `target` as keeping up to `size` streams prewarmed, `ready` of which are waiting in the
+/// pool, until the returned handle is dropped.
+pub fn prewarming(target: &str, size: usize, ready: Arc<AtomicUsize>) -> Prewarming {
+    Prewarming(with_state(|state| {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pools.insert(
+            id,
+            Pool {
+                target: target.to_owned(),
+                size,
+                ready,
+            },
+        );
+        id
+    }))
+}
+
+pub fn connection_opened(
+    id: u64,
+    target: &str,
+    peer_addr: String,
+    up: Arc<AtomicU64>,
+    down: Arc<AtomicU64>,
+    stalls: Arc<Stalls>,
+) {
+    with_state(|state| {
+        state.connections.insert(
+            id,
+            ConnectionState {
+                target: target.to_owned(),
+                peer_addr,
+                opened: SystemTime::now(),
+                pod: None,
+                up,
+                down,
+                stalls,
+            },
+        )
+    });
+}
+
+pub fn pod_selected(id: u64, pod_name: &str, pod_port: u16) {
+    with_state(|state| {
+        if let Some(connection) = state.connections.get_mut(&id) {
+            connection.pod = Some((pod_name.to_owned(), pod_port));
+        }
+    });
+}
+
+/// Moves the connection's bytes into its target's totals.
+pub fn connection_closed(id: u64) {
+    with_state(|state| {
+        if let Some(connection) = state.connections.remove(&id) {
+            let totals = state.closed.entry(connection.target).or_default();
+            totals.connections += 1;
+            totals.up += connection.up.load(Ordering::Relaxed);
+            totals.down += connection.down.load(Ordering::Relaxed);
+        }
+    });
+}
+
+fn blocked(blocked: &Blocked) -> Value {
+    json!({ "read": blocked.is_read_blocked(), "write": blocked.is_write_blocked() })
+}
+
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+impl State {
+    fn forwards(&self) -> Value {
+        let forwards: Vec<Value> = self
+            .listeners
+            .values()
+            .map(|Listener { target, local_addrs, fallback_from }| {
+                let active: Vec<&ConnectionState> =
+                    self.connections.values().filter(|c| c.target == *target).collect();
+                let closed = self.closed.get(target).copied().unwrap_or_default();
+                // Until first used, or after being released while idle, the target has no pool
+                let pool = self.pools.values().find(|p| p.target == *target);
+
+                json!({
+                    "target": target,
+                    "local_addrs": local_addrs,
+                    "fallback_from": fallback_from,
+                    "prewarm_size": pool.map(|p| p.size),
+                    "prewarmed": pool.map(|p| p.ready.load(Ordering::Relaxed)),
+                    "active_connections": active.len(),
+                    "stalled_connections": active.iter().filter(|c| c.stalls.is_stalled()).count(),
+                    "connections": closed.connections + active.len() as u64,
+                    "up": closed.up + active.iter().map(|c| c.up.load(Ordering::Relaxed)).sum::<u64>(),
+                    "down": closed.down + active.iter().map(|c| c.down.load(Ordering::Relaxed)).sum::<u64>(),
+                })
+            })
+            .collect();
+
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "forwards": forwards,
+        })
+    }
+
+    fn connections(&self) -> Value {
+        let connections: Vec<Value> = self
+            .connections
+            .iter()
+            .map(|(id, c)| {
+                json!({
+                    "connection_id": id,
+                    "target": c.target,
+                    "peer_addr": c.peer_addr,
+                    "pod_name": c.pod.as_ref().map(|(name, _)| name),
+                    "pod_port": c.pod.as_ref().map(|(_, port)| port),
+                    "opened": timestamp(c.opened),
+                    "up": c.up.load(Ordering::Relaxed),
+                    "down": c.down.load(Ordering::Relaxed),
+                    "blocked": {
+                        "client": blocked(&c.stalls.client),
+                        "pod": blocked(&c.stalls.pod),
+                    },
+                    "stalled": c.stalls.is_stalled(),
+                })
+            })
+            .collect();
+
+        json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "connections": connections,
+        })
+    }
+}
+
+/// The status line and body answering a request for `path`.
+fn respond(method: &str, path: &str) -> (&'static str, Value) {
+    if method != "GET" {
+        return ("405 Method Not Allowed", json!({ "error": "only GET is supported" }));
+    }
+
+    // Query strings are ignored, so cache-busting clients still get an answer
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let body = match path.trim_end_matches('/') {
+        "/forwards" => with_state(|state| state.forwards()),
+        "/connections" => with_state(|state| state.connections()),
+        _ => None,
+    };
+
+    match body {
+        Some(body) => ("200 OK", body),
+        None => ("404 Not Found", json!({ "error": "not found, try /forwards or /connections" })),
+    }
+}
+
+/// Reads the request line, then the rest of the head up to the blank line ending it.
+async fn read_request_line<T: AsyncRead + Unpin>(stream: &mut T) -> std::io::Result<String> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && !head.windows(2).any(|w| w == b"\n\n") {
+        if head.len() >= MAX_REQUEST_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "request too long"));
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().unwrap_or_default().to_owned())
+}
+
+async fn handle<T: AsyncRead + AsyncWrite + Unpin>(mut stream: T) -> std::io::Result<()> {
+    let request_line = match tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut stream)).await {
+        Ok(line) => line?,
+        Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    trace!(method, path, "status request");
+
+    let (status, body) = respond(method, path);
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answers status requests on `socket` until shutting down.
+pub async fn serve(socket: TcpListener) -> anyhow::Result<()> {
+    TcpListenerStream::new(socket)
+        .take_until(shutdown::requested())
+        .filter_map(|accepted| async { accept::recover(accepted).await.transpose() })
+        .try_for_each(|conn| async {
+            tokio::spawn(
+                async move {
+                    if let Err(e) = handle(conn).await {
+                        debug!(error = &e as &dyn std::error::Error, "failed to answer status request");
+                    }
+                }
+                .in_current_span(),
+            );
+
+            Ok(())
+        })
+        .await?;
+
+    trace!("closed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_get_requests() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(b"GET /nowhere HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        handle(server).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap()["error"], "not found, try /forwards or /connections");
+
+        assert_eq!(respond("POST", "/forwards").0, "405 Method Not Allowed");
+    }
+
+    #[test]
+    fn forwards_and_connections() {
+        let mut state = State {
+            started: Instant::now(),
+            next_id: 2,
+            listeners: BTreeMap::from([(
+                0,
+                Listener {
+                    target: "default/web:80".to_owned(),
+                    local_addrs: vec!["127.0.0.1:8080".to_owned()],
+                    fallback_from: Some(80),
+                },
+            )]),
+            pools: BTreeMap::from([(
+                1,
+                Pool {
+                    target: "default/web:80".to_owned(),
+                    size: 2,
+                    ready: Arc::new(AtomicUsize::new(1)),
+                },
+            )]),
+            connections: BTreeMap::new(),
+            closed: HashMap::from([(
+                "default/web:80".to_owned(),
+                Totals {
+                    connections: 2,
+                    up: 100,
+                    down: 1000,
+                },
+            )]),
+        };
+        state.connections.insert(
+            7,
+            ConnectionState {
+                target: "default/web:80".to_owned(),
+                peer_addr: "127.0.0.1:50000".to_owned(),
+                opened: UNIX_EPOCH + Duration::from_millis(1500),
+                pod: Some(("web-0".to_owned(), 8080)),
+                up: Arc::new(AtomicU64::new(10)),
+                down: Arc::new(AtomicU64::new(20)),
+                stalls: Arc::default(),
+            },
+        );
+
+        let forwards = state.forwards();
+        let connections = state.connections();
+
+        assert_eq!(
+            forwards["forwards"],
+            json!([{
+                "target": "default/web:80",
+                "local_addrs": ["127.0.0.1:8080"],
+                "fallback_from": 80,
+                "prewarm_size": 2,
+                "prewarmed": 1,
+                "active_connections": 1,
+                "stalled_connections": 0,
+                "connections": 3,
+                "up": 110,
+                "down": 1020,
+            }])
+        );
+        assert_eq!(
+            connections["connections"],
+            json!([{
+                "connection_id": 7,
+                "target": "default/web:80",
+                "peer_addr": "127.0.0.1:50000",
+                "pod_name": "web-0",
+                "pod_port": 8080,
+                "opened": 1500,
+                "up": 10,
+                "down": 20,
+                "blocked": {
+                    "client": { "read": false, "write": false },
+                    "pod": { "read": false, "write": false },
+                },
+                "stalled": false,
+            }])
+        );
+    }
+}
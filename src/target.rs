@@ -0,0 +1,969 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::{
+    api::{
+        apps::v1::{Deployment, StatefulSet},
+        authorization::v1::{ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec},
+        core::v1::{Pod, PodTemplateSpec, Service, ServicePort},
+    },
+    apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+};
+use kube::{
+    api::{Api, ListParams, PostParams},
+    Client,
+};
+use tokio::task::AbortHandle;
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{
+    cli::{ControlArgs, Forward, ForwardOptions, TargetKind},
+    connectivity::Connectivity,
+    endpoints::Endpoints,
+    errors::MyError,
+    hook,
+    pod::{self, PodBalance, PodChoice, PodSelection},
+    pod_cache::PodCache,
+    prewarm::PrewarmPool,
+    rotate::Rotation,
+    shutdown::Shutdown,
+};
+
+/// The longest --wait-for-service waits between looking the service up
+const MAX_WAIT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The pods a forward sends its connections to, as resolved from the forward's service.
+pub struct Resolved {
+    /// The service port number the forward resolved to
+    pub port: i32,
+    pub pod_api: Api<Pod>,
+    pub selector: ListParams,
+    pub pod_port: IntOrString,
+    pub prewarm: Option<Arc<PrewarmPool>>,
+    /// When set, connections are relayed through a pod to this address instead of to the pod itself
+    pub cluster_ip: Option<String>,
+    /// The bastion pod to relay through instead of the service's pods
+    pub via_pod: Option<(Api<Pod>, String)>,
+    /// With --headless-endpoints, the endpoints connections are forwarded to instead of a pod
+    /// chosen by the selector
+    pub endpoints: Option<Endpoints>,
+    /// What --policy picks pods by for connections to the target
+    pub balance: Arc<PodBalance>,
+    /// The pods matching the selector, when connections are forwarded to one of them
+    pods: Option<PodCache>,
+
+    maintain: Option<AbortHandle>,
+    pod_watch: Option<AbortHandle>,
+}
+
+impl Drop for Resolved {
+    fn drop(&mut self) {
+        if let Some(m) = &self.maintain {
+            m.abort();
+        }
+        if let Some(w) = &self.pod_watch {
+            w.abort();
+        }
+    }
+}
+
+/// A forward's target, resolved against the cluster on first use and cached until released.
+pub struct Target {
+    /// `namespace/service[:port]`, for display
+    pub name: String,
+    /// The forward's namespace, or the client's default when it doesn't give one
+    pub namespace: String,
+    pub connectivity: Arc<Connectivity>,
+    /// The target's active connections, for --rotate-interval
+    pub rotation: Arc<Rotation>,
+    client: Client,
+    forward: Forward,
+    args: ControlArgs,
+
+    resolved: tokio::sync::Mutex<Option<Arc<Resolved>>>,
+    last_used: Mutex<Instant>,
+}
+
+impl Target {
+    pub fn new(
+        client: Client,
+        connectivity: Arc<Connectivity>,
+        forward: Forward,
+        args: ControlArgs,
+    ) -> Self {
+        let namespace = forward
+            .namespace
+            .clone()
+            .unwrap_or_else(|| client.default_namespace().to_owned());
+        let name = name(&forward, &client);
+
+        Self {
+            name,
+            namespace,
+            connectivity,
+            rotation: Arc::default(),
+            client,
+            forward,
+            args,
+            resolved: tokio::sync::Mutex::new(None),
+            last_used: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns the resolved target, looking up the service if it has not been resolved yet.
+    pub async fn resolve(&self) -> anyhow::Result<Arc<Resolved>> {
+        *self.last_used.lock().unwrap() = Instant::now();
+
+        let mut resolved = self.resolved.lock().await;
+        if let Some(r) = resolved.as_ref() {
+            return Ok(r.clone());
+        }
+
+        let r = Arc::new(resolve_service(self.client.clone(), &self.forward, &self.args, &self.name).await?);
+        *resolved = Some(r.clone());
+
+        Ok(r)
+    }
+
+    /// Drops the cached resolution, and with it any background work against the API server,
+    /// once the target has not been used for `idle`.
+    pub async fn release_if_idle(&self, idle: Duration) {
+        if self.last_used.lock().unwrap().elapsed() < idle {
+            return;
+        }
+
+        if self.resolved.lock().await.take().is_some() {
+            info!("released idle forward");
+        }
+    }
+}
+
+async fn resolve_service(
+    client: Client,
+    forward: &Forward,
+    args: &ControlArgs,
+    name: &str,
+) -> anyhow::Result<Resolved> {
+    if !forward.kind.is_service() {
+        return resolve_pods(client, forward, args, name).await;
+    }
+
+    let service = get_service(client.clone(), forward).await?;
+    let service_name = service.metadata.name.clone().unwrap_or_default();
+
+    let service_spec = service
+        .spec
+        .ok_or_else(|| MyError::ServiceNotFound(service_name.clone()))?;
+    // Headless services with manually managed endpoints have no selector, and don't need one
+    let selector = match service_spec.selector {
+        Some(s) => selector_into_list_params(&s),
+        None if args.headless_endpoints => ListParams::default(),
+        None => return Err(MyError::ServiceMissingSelectors(service_name.clone()).into()),
+    };
+
+    let ports = service_spec.ports.unwrap_or_default();
+    let (port, pod_port) = resolve_service_port(
+        &service_name,
+        ports.clone(),
+        forward.service_port.as_deref(),
+        forward.options.port_is_name,
+    )?;
+
+    info!(
+        service_port = port,
+        pod_port = match &pod_port {
+            IntOrString::Int(p) => p.to_string(),
+            IntOrString::String(name) => name.clone(),
+        },
+        "resolved service port"
+    );
+
+    let cluster_ip = match args.via_cluster_ip || args.via_pod.is_some() {
+        true => Some(
+            service_spec
+                .cluster_ip
+                .clone()
+                .filter(|ip| !ip.is_empty() && ip != "None")
+                .ok_or_else(|| MyError::ServiceMissingClusterIp(service_name.clone()))?,
+        ),
+        false => None,
+    };
+
+    let via_pod = match &args.via_pod {
+        Some(via_pod) => {
+            let (namespace, name) = match via_pod.split_once('/') {
+                Some((ns, name)) => (Some(ns.to_owned()), name),
+                None => (None, via_pod.as_str()),
+            };
+            let api = get_pod_api(namespace.as_ref(), client.clone());
+
+            // Fail up front rather than on every connection when the bastion doesn't exist
+            api.get(name).await?;
+            info!(via_pod, "relaying through bastion pod");
+
+            Some((api, name.to_owned()))
+        }
+        None => None,
+    };
+
+    let endpoints = match args.headless_endpoints {
+        true if service_spec.cluster_ip.as_deref() == Some("None") => {
+            let api = match &forward.namespace {
+                Some(ns) => Api::namespaced(client.clone(), ns),
+                None => Api::default_namespaced(client.clone()),
+            };
+            let port_name = ports.iter().find(|p| p.port == port).and_then(|p| p.name.as_deref());
+            Some(Endpoints::new(api, &service_name, port_name))
+        }
+        true => return Err(MyError::ServiceNotHeadless(service_name).into()),
+        false => None,
+    };
+
+    // Relaying execs into a pod instead, which needs a different permission
+    if cluster_ip.is_none() {
+        let namespace = forward
+            .namespace
+            .clone()
+            .unwrap_or_else(|| client.default_namespace().to_owned());
+        check_portforward_permission(client.clone(), namespace).await?;
+    }
+
+    let mut resolved = Resolved {
+        port,
+        pod_api: get_pod_api(forward.namespace.as_ref(), client),
+        selector,
+        pod_port,
+        prewarm: None,
+        cluster_ip,
+        via_pod,
+        endpoints,
+        balance: Arc::default(),
+        pods: None,
+        maintain: None,
+        pod_watch: None,
+    };
+    resolved.start(args, name).await;
+
+    Ok(resolved)
+}
+
+/// Looks up the service of a service or `service-labels:` forward.
+async fn get_service(client: Client, forward: &Forward) -> anyhow::Result<Service> {
+    let service_api = get_service_api(forward.namespace.as_ref(), client);
+
+    match forward.kind {
+        TargetKind::ServiceLabels => {
+            let services = service_api
+                .list(&ListParams::default().labels(&forward.service_name))
+                .await?;
+            let service = select_service(services.items, &forward.service_name)?;
+            info!(service_name = service.metadata.name, "matched service");
+            Ok(service)
+        }
+        _ => Ok(service_api.get(forward.service_name.as_str()).await?),
+    }
+}
+
+/// Splits a `SERVICE:*` forward into a forward for each of the service's ports, as they are
+/// when it starts.
+pub async fn forwards_for_ports(client: Client, forward: &Forward) -> anyhow::Result<Vec<Forward>> {
+    let service = get_service(client, forward).await?;
+    let service_name = service.metadata.name.unwrap_or_default();
+    let ports = service.spec.and_then(|s| s.ports).unwrap_or_default();
+
+    Ok(port_forwards(forward, &service_name, &ports)?)
+}
+
+/// Whether looking a target up failed as it doesn't exist, at least not yet.
+fn is_missing(error: &anyhow::Error) -> bool {
+    match error.downcast_ref() {
+        Some(kube::Error::Api(response)) => response.code == 404,
+        _ => matches!(error.downcast_ref(), Some(MyError::NoServicesMatchLabels(_))),
+    }
+}
+
+/// Runs `lookup` until the target it looks up exists, for --wait-for-service, waiting twice as
+/// long between each try, up to [`MAX_WAIT_INTERVAL`]. Other failures are returned straight
+/// away, as is the last one when shutting down.
+pub async fn wait_for<T, F>(shutdown: &Shutdown, mut lookup: impl FnMut() -> F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let mut interval = Duration::from_secs(1);
+    loop {
+        match lookup().await {
+            Err(e) if is_missing(&e) => {
+                info!(
+                    error = e.as_ref() as &dyn std::error::Error,
+                    retry_secs = interval.as_secs(),
+                    "waiting for the service to be created"
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = shutdown.requested() => return Err(e),
+                }
+                interval = (interval * 2).min(MAX_WAIT_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn port_forwards(forward: &Forward, service_name: &str, ports: &[ServicePort]) -> Result<Vec<Forward>, MyError> {
+    if ports.is_empty() {
+        return Err(MyError::ServiceHasNoPorts(service_name.to_owned()));
+    }
+
+    Ok(ports
+        .iter()
+        .map(|p| Forward {
+            service_port: Some(p.port.to_string()),
+            options: ForwardOptions {
+                port_is_name: false,
+                ..forward.options.clone()
+            },
+            ..forward.clone()
+        })
+        .collect())
+}
+
+/// Resolves a forward to a pod, or to a workload's or label selector's pods, without a
+/// service. A `pod/NAME` forward only ever matches that pod, by name, so it is never swapped
+/// for another, while a workload's pods are matched by its selector. The port is a number or
+/// the name of a container port, on the pod, the workload's pod template or any matching pod.
+async fn resolve_pods(
+    client: Client,
+    forward: &Forward,
+    args: &ControlArgs,
+    name: &str,
+) -> anyhow::Result<Resolved> {
+    let target = format!("{}{}", forward.kind.prefix(), forward.service_name);
+    if args.via_cluster_ip || args.via_pod.is_some() || args.headless_endpoints {
+        return Err(MyError::TargetNeedsService(target).into());
+    }
+
+    let pod_api = get_pod_api(forward.namespace.as_ref(), client.clone());
+    let pod_port = match forward.service_port.as_deref().map(str::parse::<i32>) {
+        Some(Ok(p)) if !forward.options.port_is_name => IntOrString::Int(p),
+        _ => IntOrString::String(forward.service_port.clone().unwrap_or_default()),
+    };
+
+    // Fail up front rather than on every connection when the pod or workload doesn't exist
+    let (pod, selector) = match forward.kind {
+        TargetKind::Deployment => {
+            let api: Api<Deployment> = get_workload_api(forward.namespace.as_ref(), client.clone());
+            let spec = api.get(&forward.service_name).await?.spec.unwrap_or_default();
+            (template_pod(spec.template), label_selector_into_list_params(&target, &spec.selector)?)
+        }
+        TargetKind::StatefulSet => {
+            let api: Api<StatefulSet> = get_workload_api(forward.namespace.as_ref(), client.clone());
+            let spec = api.get(&forward.service_name).await?.spec.unwrap_or_default();
+            (template_pod(spec.template), label_selector_into_list_params(&target, &spec.selector)?)
+        }
+        TargetKind::PodLabels => {
+            let selector = ListParams::default().labels(&forward.service_name);
+            // Any of the pods will do to look a named port up on, and none are needed otherwise
+            let pod = match (pod_api.list(&selector).await?.items.into_iter().next(), &pod_port) {
+                (Some(pod), _) => pod,
+                (None, IntOrString::Int(_)) => Pod::default(),
+                (None, IntOrString::String(_)) => return Err(MyError::NoPodsMatchSelector().into()),
+            };
+            (pod, selector)
+        }
+        _ => (
+            pod_api.get(&forward.service_name).await?,
+            ListParams::default().fields(&format!("metadata.name={}", forward.service_name)),
+        ),
+    };
+
+    let port = pod::find_pod_port(&pod_port, &pod)?;
+    info!(pod_port = port, "resolved pod port");
+
+    let namespace = forward
+        .namespace
+        .clone()
+        .unwrap_or_else(|| client.default_namespace().to_owned());
+    check_portforward_permission(client, namespace).await?;
+
+    let mut resolved = Resolved {
+        port: port.into(),
+        pod_api,
+        selector,
+        pod_port,
+        prewarm: None,
+        cluster_ip: None,
+        via_pod: None,
+        endpoints: None,
+        balance: Arc::default(),
+        pods: None,
+        maintain: None,
+        pod_watch: None,
+    };
+    resolved.start(args, name).await;
+
+    Ok(resolved)
+}
+
+/// How `forward` is named in logs, events and hooks, `namespace/service:port`, with `client`'s
+/// default namespace when it doesn't give one.
+pub fn name(forward: &Forward, client: &Client) -> String {
+    format!(
+        "{namespace}/{kind}{service_name}{service_port}",
+        namespace = forward.namespace.as_deref().unwrap_or(client.default_namespace()),
+        kind = forward.kind.prefix(),
+        service_name = forward.service_name,
+        service_port = forward.service_port.as_ref().map(|p| format!(":{p}")).unwrap_or_default()
+    )
+}
+
+/// A pod from a workload's template, to look named ports up on.
+fn template_pod(template: PodTemplateSpec) -> Pod {
+    Pod {
+        metadata: template.metadata.unwrap_or_default(),
+        spec: template.spec,
+        ..Default::default()
+    }
+}
+
+impl Resolved {
+    /// Picks a pod for a connection from those matching the selector, as last seen by the
+    /// target's pod watch.
+    pub async fn find_pod(&self, selection: &PodSelection) -> anyhow::Result<(Pod, PodChoice)> {
+        match &self.pods {
+            Some(pods) => pod::pick_pod(&self.pod_api, pods.pods().await?, selection).await,
+            None => pod::find_pod(&self.pod_api, &self.selector, selection).await,
+        }
+    }
+
+    /// Counts what matched, and starts any work kept up in the background against it. The
+    /// counts are only logged, so failing to count is only warned about.
+    async fn start(&mut self, args: &ControlArgs, name: &str) {
+        match &self.endpoints {
+            Some(e) => match e.count().await {
+                Ok((ready, total)) => info!(ready_endpoints = ready, total_endpoints = total, "matched endpoints"),
+                Err(e) => warn!(error = e.as_ref() as &dyn std::error::Error, "failed to count matched endpoints"),
+            },
+            None => match pod::count_pods(&self.pod_api, &self.selector, &PodSelection::from_args(args)).await {
+                Ok((ready, total)) => info!(ready_pods = ready, total_pods = total, "matched pods"),
+                Err(e) => warn!(error = e.as_ref() as &dyn std::error::Error, "failed to count matched pods"),
+            },
+        }
+
+        // Connections to the endpoints or through the bastion don't pick from the pods, so they
+        // are only watched for --on-event. Endpoints are listed rather than watched, and may not
+        // have a selector to watch pods by.
+        let on_change = hook::enabled().then(|| pod::availability_hook(PodSelection::from_args(args), name.to_owned()));
+        if self.endpoints.is_none() && (self.via_pod.is_none() || on_change.is_some()) {
+            let (pods, watch) = PodCache::watch(self.pod_api.clone(), self.selector.clone(), on_change);
+            self.pods = self.via_pod.is_none().then_some(pods);
+            self.pod_watch = Some(watch);
+        }
+
+        self.prewarm = match (args.prewarm, &self.pods) {
+            (0, _) | (_, None) => None,
+            (size, Some(pods)) => Some(Arc::new(PrewarmPool::new(
+                name,
+                self.pod_api.clone(),
+                pods.clone(),
+                self.pod_port.clone(),
+                size,
+                Duration::from_secs(args.prewarm_ttl),
+                PodSelection::for_target(args, self),
+            ))),
+        };
+
+        self.maintain = self.prewarm.clone().map(|pool| {
+            tokio::spawn(async move { pool.maintain().await }.in_current_span()).abort_handle()
+        });
+    }
+}
+
+/// Asks the API server whether we may port-forward to pods in `namespace`, so a missing
+/// permission fails the forward up front rather than every connection. Failing to ask is only
+/// logged, as the port-forwards themselves still report it.
+async fn check_portforward_permission(client: Client, namespace: String) -> anyhow::Result<()> {
+    let review = SelfSubjectAccessReview {
+        spec: SelfSubjectAccessReviewSpec {
+            resource_attributes: Some(ResourceAttributes {
+                namespace: Some(namespace.clone()),
+                verb: Some("create".to_owned()),
+                resource: Some("pods".to_owned()),
+                subresource: Some("portforward".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let allowed = match Api::<SelfSubjectAccessReview>::all(client)
+        .create(&PostParams::default(), &review)
+        .await
+    {
+        Ok(r) => r.status.is_some_and(|s| s.allowed),
+        Err(e) => {
+            debug!(error = &e as &dyn std::error::Error, "unable to check for the pods/portforward permission");
+            return Ok(());
+        }
+    };
+
+    match allowed {
+        true => Ok(()),
+        false => Err(MyError::PortForwardNotPermitted(namespace).into()),
+    }
+}
+
+/// Picks the only service matching a `service-labels:` forward's labels.
+fn select_service(mut services: Vec<Service>, labels: &str) -> Result<Service, MyError> {
+    match services.len() {
+        0 => Err(MyError::NoServicesMatchLabels(labels.to_owned())),
+        1 => Ok(services.remove(0)),
+        _ => Err(MyError::AmbiguousServiceLabels(
+            labels.to_owned(),
+            services
+                .iter()
+                .filter_map(|s| s.metadata.name.as_deref())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )),
+    }
+}
+
+/// Resolves the requested port on the service to `(service port number, port on the pod)`.
+///
+/// When no port is requested the service must have exactly one port, which is used. A numeric
+/// port is taken as the port number unless `port_is_name` is set, and is mapped to the
+/// `targetPort` of the service port with that number. A number the service doesn't expose is
+/// used on the pod as is.
+fn resolve_service_port(
+    service_name: &str,
+    ports: Vec<ServicePort>,
+    service_port: Option<&str>,
+    port_is_name: bool,
+) -> Result<(i32, IntOrString), MyError> {
+    let service_port = match service_port {
+        Some(p) => p,
+        None => {
+            return match ports.as_slice() {
+                [] => Err(MyError::ServiceHasNoPorts(service_name.to_string())),
+                [p] => Ok((p.port, p.target_port.clone().unwrap_or(IntOrString::Int(p.port)))),
+                _ => Err(MyError::AmbiguousServicePort(
+                    service_name.to_string(),
+                    ports
+                        .iter()
+                        .map(|p| p.name.clone().unwrap_or_else(|| p.port.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )),
+            };
+        }
+    };
+
+    match service_port.parse::<i32>() {
+        Ok(p) if !port_is_name => Ok(ports
+            .into_iter()
+            .find(|sp| sp.port == p)
+            .map(|sp| (p, sp.target_port.unwrap_or(IntOrString::Int(p))))
+            .unwrap_or((p, IntOrString::Int(p)))),
+        _ => ports
+            .into_iter()
+            .find(|p| p.name.as_deref() == Some(service_port))
+            .map(|p| (p.port, p.target_port.unwrap_or(IntOrString::Int(p.port))))
+            .ok_or_else(|| {
+                MyError::MissingNamedPort(
+                    service_port.to_string(),
+                    service_name.to_string(),
+                )
+            }),
+    }
+}
+
+/// A `--span-target-format` template for the `target` field of a forward's span.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TargetFormat(String);
+
+const TARGET_FORMAT_PLACEHOLDERS: [&str; 5] = ["namespace", "service", "service_port", "local_port", "name"];
+
+impl TargetFormat {
+    pub fn parse(template: &str) -> Result<Self, MyError> {
+        let invalid = |reason: String| MyError::InvalidSpanTargetFormat(template.to_owned(), reason);
+
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let (placeholder, after) = rest[start + 1..]
+                .split_once('}')
+                .ok_or_else(|| invalid("unclosed {".to_owned()))?;
+
+            if !TARGET_FORMAT_PLACEHOLDERS.contains(&placeholder) {
+                return Err(invalid(format!("unknown placeholder {{{placeholder}}}")));
+            }
+            rest = after;
+        }
+
+        Ok(Self(template.to_owned()))
+    }
+
+    pub fn needs_local_port(&self) -> bool {
+        self.0.contains("{local_port}")
+    }
+
+    /// Renders the template for a forward. `{service_port}` and `{local_port}` are empty when
+    /// not known.
+    pub fn render(&self, target: &Target, local_port: Option<u16>) -> String {
+        self.0
+            .replace("{namespace}", &target.namespace)
+            .replace("{service}", &target.forward.service_name)
+            .replace("{service_port}", target.forward.service_port.as_deref().unwrap_or_default())
+            .replace("{local_port}", &local_port.map(|p| p.to_string()).unwrap_or_default())
+            .replace("{name}", &target.name)
+    }
+}
+
+/// Chooses the local port for a forward without an explicit one, offsetting the service port.
+pub fn local_port_for(port: i32, offset: u16) -> Result<u16, MyError> {
+    port.checked_add(i32::from(offset))
+        .and_then(|p| u16::try_from(p).ok())
+        .filter(|p| *p != 0)
+        .ok_or(MyError::InvalidLocalPort(port, offset))
+}
+
+fn get_service_api(namespace: Option<&String>, client: Client) -> Api<Service> {
+    match namespace {
+        Some(ns) => Api::namespaced(client, ns.as_str()),
+        None => Api::default_namespaced(client),
+    }
+}
+
+fn get_pod_api(namespace: Option<&String>, client: Client) -> Api<Pod> {
+    match namespace {
+        Some(ns) => Api::namespaced(client, ns.as_str()),
+        None => Api::default_namespaced(client)
+    }
+}
+
+fn get_workload_api<K>(namespace: Option<&String>, client: Client) -> Api<K>
+where
+    K: kube::Resource<Scope = k8s_openapi::NamespaceResourceScope>,
+    K::DynamicType: Default,
+{
+    match namespace {
+        Some(ns) => Api::namespaced(client, ns.as_str()),
+        None => Api::default_namespaced(client),
+    }
+}
+
+fn selector_into_list_params(selectors: &BTreeMap<String, String>) -> ListParams {
+    let labels = selectors
+        .iter()
+        .fold(String::new(), |mut res, (key, value)| {
+            if !res.is_empty() {
+                res.push(',');
+            }
+            res.push_str(key);
+            res.push('=');
+            res.push_str(value);
+            res
+        });
+
+    ListParams::default().labels(&labels)
+}
+
+/// Converts a workload's selector, which may have expressions as well as labels, to the
+/// equivalent label selector for listing its pods.
+fn label_selector_into_list_params(target: &str, selector: &LabelSelector) -> Result<ListParams, MyError> {
+    let invalid = |reason: String| MyError::InvalidWorkloadSelector(target.to_owned(), reason);
+
+    let mut requirements: Vec<String> = selector
+        .match_labels
+        .iter()
+        .flatten()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+
+    for expression in selector.match_expressions.iter().flatten() {
+        let key = &expression.key;
+        let values = expression.values.as_deref().unwrap_or_default().join(",");
+        requirements.push(match expression.operator.as_str() {
+            "In" => format!("{key} in ({values})"),
+            "NotIn" => format!("{key} notin ({values})"),
+            "Exists" => key.clone(),
+            "DoesNotExist" => format!("!{key}"),
+            op => return Err(invalid(format!("unknown operator {op}"))),
+        });
+    }
+
+    // An empty selector matches every pod in the namespace
+    if requirements.is_empty() {
+        return Err(invalid("the selector is empty".to_owned()));
+    }
+
+    Ok(ListParams::default().labels(&requirements.join(",")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service_port(name: Option<&str>, port: i32, target_port: Option<IntOrString>) -> ServicePort {
+        ServicePort {
+            name: name.map(|n| n.to_owned()),
+            port,
+            target_port,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn single_port_used_when_unspecified() {
+        let ports = vec![service_port(Some("http"), 80, Some(IntOrString::Int(8080)))];
+
+        let (port, pod_port) = resolve_service_port("test", ports, None, false).unwrap();
+
+        assert_eq!(port, 80);
+        assert_eq!(pod_port, IntOrString::Int(8080));
+    }
+
+    #[test]
+    fn single_port_without_target_port() {
+        let ports = vec![service_port(None, 5432, None)];
+
+        let (port, pod_port) = resolve_service_port("test", ports, None, false).unwrap();
+
+        assert_eq!(port, 5432);
+        assert_eq!(pod_port, IntOrString::Int(5432));
+    }
+
+    #[test]
+    fn multiple_ports_require_a_choice() {
+        let ports = vec![
+            service_port(Some("http"), 80, None),
+            service_port(None, 443, None),
+        ];
+
+        let err = resolve_service_port("test", ports, None, false).unwrap_err();
+
+        assert!(matches!(err, MyError::AmbiguousServicePort(_, ref p) if p == "http, 443"));
+    }
+
+    #[test]
+    fn workload_selectors() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement;
+
+        let requirement = |key: &str, operator: &str, values: &[&str]| LabelSelectorRequirement {
+            key: key.to_owned(),
+            operator: operator.to_owned(),
+            values: Some(values.iter().map(|v| v.to_string()).collect()),
+        };
+        let selector = LabelSelector {
+            match_labels: Some(BTreeMap::from([("app".to_owned(), "web".to_owned())])),
+            match_expressions: Some(vec![
+                requirement("tier", "In", &["frontend", "edge"]),
+                requirement("track", "NotIn", &["canary"]),
+                requirement("release", "Exists", &[]),
+                requirement("legacy", "DoesNotExist", &[]),
+            ]),
+        };
+
+        let params = label_selector_into_list_params("deployment/web", &selector).unwrap();
+
+        assert_eq!(
+            params.label_selector.as_deref(),
+            Some("app=web,tier in (frontend,edge),track notin (canary),release,!legacy")
+        );
+
+        let err = label_selector_into_list_params("deployment/web", &LabelSelector::default()).unwrap_err();
+        assert!(matches!(err, MyError::InvalidWorkloadSelector(..)));
+    }
+
+    #[test]
+    fn no_ports() {
+        let err = resolve_service_port("test", vec![], None, false).unwrap_err();
+
+        assert!(matches!(err, MyError::ServiceHasNoPorts(_)));
+    }
+
+    #[test]
+    fn forward_per_port() {
+        let forward = Forward::parse("web/frontend:*?port-is-name&ignore-readiness").unwrap();
+        let ports = [service_port(Some("http"), 80, None), service_port(Some("https"), 443, None)];
+
+        let forwards = port_forwards(&forward, "frontend", &ports).unwrap();
+
+        assert_eq!(
+            forwards.iter().map(|f| f.service_port.as_deref()).collect::<Vec<_>>(),
+            [Some("80"), Some("443")]
+        );
+        assert!(forwards.iter().all(|f| f.namespace.as_deref() == Some("web") && f.local_port.is_none()));
+        assert!(forwards.iter().all(|f| !f.options.port_is_name && f.options.ignore_readiness == Some(true)));
+
+        let err = port_forwards(&forward, "frontend", &[]).unwrap_err();
+        assert!(matches!(err, MyError::ServiceHasNoPorts(_)));
+    }
+
+    #[test]
+    fn named_port() {
+        let ports = vec![
+            service_port(Some("http"), 80, Some(IntOrString::String("web".to_owned()))),
+            service_port(Some("https"), 443, None),
+        ];
+
+        let (port, pod_port) = resolve_service_port("test", ports, Some("http"), false).unwrap();
+
+        assert_eq!(port, 80);
+        assert_eq!(pod_port, IntOrString::String("web".to_owned()));
+    }
+
+    #[test]
+    fn numeric_port_uses_target_port() {
+        let ports = vec![
+            service_port(Some("http"), 80, Some(IntOrString::Int(8080))),
+            service_port(Some("https"), 443, Some(IntOrString::Int(8443))),
+        ];
+
+        let (port, pod_port) = resolve_service_port("test", ports, Some("443"), false).unwrap();
+
+        assert_eq!(port, 443);
+        assert_eq!(pod_port, IntOrString::Int(8443));
+    }
+
+    #[test]
+    fn numeric_port_without_target_port() {
+        let ports = vec![service_port(Some("https"), 443, None)];
+
+        let (port, pod_port) = resolve_service_port("test", ports, Some("443"), false).unwrap();
+
+        assert_eq!(port, 443);
+        assert_eq!(pod_port, IntOrString::Int(443));
+    }
+
+    #[test]
+    fn numeric_port_not_on_service() {
+        let ports = vec![service_port(Some("https"), 443, Some(IntOrString::Int(8443)))];
+
+        let (port, pod_port) = resolve_service_port("test", ports, Some("9090"), false).unwrap();
+
+        assert_eq!(port, 9090);
+        assert_eq!(pod_port, IntOrString::Int(9090));
+    }
+
+    #[test]
+    fn numeric_port_name() {
+        let ports = vec![
+            service_port(Some("8080"), 80, Some(IntOrString::Int(3000))),
+            service_port(Some("https"), 443, None),
+        ];
+
+        let (port, pod_port) = resolve_service_port("test", ports.clone(), Some("8080"), true).unwrap();
+
+        assert_eq!(port, 80);
+        assert_eq!(pod_port, IntOrString::Int(3000));
+
+        let (port, _) = resolve_service_port("test", ports, Some("8080"), false).unwrap();
+
+        assert_eq!(port, 8080);
+    }
+
+    fn service(name: &str) -> Service {
+        Service {
+            metadata: kube::api::ObjectMeta {
+                name: Some(name.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn single_service_matches_labels() {
+        let selected = select_service(vec![service("grafana-7d9f")], "app=grafana").unwrap();
+
+        assert_eq!(selected.metadata.name.as_deref(), Some("grafana-7d9f"));
+    }
+
+    #[test]
+    fn no_services_match_labels() {
+        let err = select_service(vec![], "app=grafana").unwrap_err();
+
+        assert!(matches!(err, MyError::NoServicesMatchLabels(ref l) if l == "app=grafana"));
+    }
+
+    #[tokio::test]
+    async fn waits_only_for_missing_targets() {
+        let api_error = |code| {
+            anyhow::Error::from(kube::Error::Api(kube::core::ErrorResponse {
+                status: "Failure".to_owned(),
+                message: String::new(),
+                reason: String::new(),
+                code,
+            }))
+        };
+        assert!(is_missing(&api_error(404)));
+        assert!(is_missing(&MyError::NoServicesMatchLabels("app=grafana".to_owned()).into()));
+        assert!(!is_missing(&api_error(403)));
+        assert!(!is_missing(&MyError::ServiceMissingSelectors("grafana".to_owned()).into()));
+
+        let mut lookups = 0;
+        let forbidden = wait_for(&Shutdown::default(), || {
+            lookups += 1;
+            async { Err::<(), _>(api_error(403)) }
+        })
+        .await;
+        assert!(forbidden.is_err());
+        assert_eq!(lookups, 1);
+    }
+
+    #[test]
+    fn multiple_services_match_labels() {
+        let err = select_service(vec![service("a"), service("b")], "app=grafana").unwrap_err();
+
+        assert!(matches!(err, MyError::AmbiguousServiceLabels(_, ref s) if s == "a, b"));
+    }
+
+    #[test]
+    fn local_port_without_offset() {
+        assert_eq!(local_port_for(8080, 0).unwrap(), 8080);
+    }
+
+    #[test]
+    fn local_port_with_offset() {
+        assert_eq!(local_port_for(80, 10000).unwrap(), 10080);
+    }
+
+    #[test]
+    fn local_port_offset_overflow() {
+        let err = local_port_for(60000, 10000).unwrap_err();
+
+        assert!(matches!(err, MyError::InvalidLocalPort(60000, 10000)));
+    }
+
+    #[test]
+    fn missing_named_port() {
+        let ports = vec![service_port(Some("http"), 80, None)];
+
+        let err = resolve_service_port("test", ports, Some("grpc"), false).unwrap_err();
+
+        assert!(matches!(err, MyError::MissingNamedPort(_, _)));
+    }
+
+    #[test]
+    fn target_format_placeholders() {
+        let format = TargetFormat::parse("{service}.{namespace}:{local_port}").unwrap();
+
+        assert!(format.needs_local_port());
+        assert!(!TargetFormat::parse("{name}").unwrap().needs_local_port());
+        assert!(TargetFormat::parse("static").is_ok());
+    }
+
+    #[test]
+    fn target_format_invalid() {
+        let err = TargetFormat::parse("{service}.{cluster}").unwrap_err();
+        assert!(matches!(err, MyError::InvalidSpanTargetFormat(_, ref r) if r == "unknown placeholder {cluster}"));
+
+        let err = TargetFormat::parse("{service").unwrap_err();
+        assert!(matches!(err, MyError::InvalidSpanTargetFormat(_, _)));
+    }
+}